@@ -0,0 +1,947 @@
+mod expression;
+pub mod gdb;
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use expression::Expression;
+
+use crate::{
+    hardware::{
+        bus::Bus,
+        io::joypad::Button,
+        machine::Machine,
+        movie::{Movie, Recorder},
+        opcode::Opcode,
+        rewind::Rewind,
+        state::slot_path,
+        watchpoint::{Access, Watchpoint},
+    },
+    symbols::Symbols,
+};
+
+const HELP: &str = "\
+break [BANK:]ADDR [if EXPR]
+                    b   Stop when PC reaches the address, and EXPR holds
+condition N [EXPR]      Set or clear a breakpoint's condition
+ignore N COUNT          Let a breakpoint pass COUNT more times
+action N stop|log|dump|save SLOT
+                        Stop, print the PC or the registers and carry on,
+                        or save a state in SLOT and carry on
+delete N                Remove a breakpoint
+breakpoints         bl  List the breakpoints
+watch r|w|x ADDR[-END] [VALUE]
+                    w   Stop when memory is read, written or executed
+unwatch N               Remove a watchpoint
+watchpoints         wl  List the watchpoints
+step [N]            s   Run N instructions
+next                n   Run an instruction, stepping over CALL and RST
+finish              f   Run until the current function returns
+rewind [N]              Go back N frames, 60 by default
+press BUTTON            Hold down right, left, up, down, a, b, select or start
+release BUTTON          Let go of a button
+continue            c   Run until a breakpoint, or Ctrl-C
+until [BANK:]ADDR   u   Run until PC reaches the address, or Ctrl-C
+registers           r   Show the registers
+memory ADDR [LEN]   x   Dump memory
+list [ADDR] [N]     l   Disassemble N instructions, around PC by default
+print EXPR          p   Evaluate an expression
+quit                q   Exit
+An empty line repeats the last command.
+
+Addresses are hex, or labels from the symbol file. Write hex with $ if there's
+a label that looks like a number.
+
+Expressions use A-L, AF, BC, DE, HL, SP and PC, the flags F.Z, F.N, F.H and
+F.C, bytes and words in memory as [ADDR] and [ADDR].w, labels, and C's
+operators. Numbers are decimal, or hex with 0x or $.";
+
+// Instructions listed before PC, when they can be found
+const LIST_CONTEXT: u16 = 3;
+
+/// What a breakpoint does once it triggers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Stop,
+    Log,
+    Dump,
+    Save(u8),
+}
+
+// An expression, along with how it was written
+#[derive(Debug, Clone)]
+struct Condition {
+    text: String,
+    expression: Expression,
+}
+
+/// A PC breakpoint. Addresses in switchable ROM can be limited to one bank.
+/// It only triggers while its condition holds, and after it's been let
+/// through as many times as it's set to ignore.
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub bank: Option<usize>,
+    pub address: u16,
+    condition: Option<Condition>,
+    pub action: Action,
+    pub hits: u64,
+    pub ignore: u64,
+}
+
+impl Breakpoint {
+    fn at(bank: Option<usize>, address: u16) -> Breakpoint {
+        Breakpoint {
+            bank,
+            address,
+            condition: None,
+            action: Action::Stop,
+            hits: 0,
+            ignore: 0,
+        }
+    }
+
+    fn hit(&self, bus: &Bus, pc: u16) -> bool {
+        pc == self.address && self.bank.is_none_or(|bank| bank == bus.rom_bank(pc))
+    }
+
+    // Called when PC reaches the breakpoint. Conditions are evaluated then,
+    // and only hits where they hold are counted.
+    fn trigger(&mut self, machine: &Machine) -> bool {
+        if let Some(condition) = &self.condition {
+            if !condition.expression.is_true(machine) {
+                return false;
+            }
+        }
+
+        self.hits += 1;
+        self.hits > self.ignore
+    }
+}
+
+/// An interactive debugger, which reads commands from stdin
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    last_command: String,
+
+    // Where the save action puts its slots
+    rom_path: String,
+
+    symbols: Rc<Symbols>,
+
+    // Set by Ctrl-C, which stops the machine rather than the debugger
+    interrupted: Arc<AtomicBool>,
+
+    // Captured as the machine runs
+    rewind: Rewind,
+
+    // Buttons and frames go through it while a movie is being recorded
+    recorder: Option<Recorder>,
+}
+
+impl Debugger {
+    pub fn new(rom_path: &str) -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            last_command: String::new(),
+            rom_path: rom_path.to_owned(),
+            symbols: Rc::default(),
+            interrupted: Arc::default(),
+            rewind: Rewind::default(),
+            recorder: None,
+        }
+    }
+
+    /// Shows labels from the symbols, and takes them as addresses
+    pub fn with_symbols(self, symbols: Rc<Symbols>) -> Debugger {
+        Debugger { symbols, ..self }
+    }
+
+    /// Carries on from history captured before the debugger took over
+    pub fn with_rewind(self, rewind: Rewind) -> Debugger {
+        Debugger { rewind, ..self }
+    }
+
+    /// Records the session as a movie, with the buttons pressed from the
+    /// debugger as its input
+    pub fn with_recorder(self, recorder: Recorder) -> Debugger {
+        Debugger {
+            recorder: Some(recorder),
+            ..self
+        }
+    }
+
+    /// Returns the movie recorded so far, if there's one
+    pub fn finish_recording(self) -> Option<Movie> {
+        self.recorder.map(Recorder::finish)
+    }
+
+    pub fn run(&mut self, machine: &mut Machine) -> Result<()> {
+        let stdin = io::stdin();
+        let mut out = io::stdout();
+
+        let interrupted = Arc::clone(&self.interrupted);
+        ctrlc::set_handler(move || interrupted.store(true, Ordering::Relaxed))
+            .context("Can't catch Ctrl-C")?;
+
+        print_instruction(machine, &self.symbols, &mut out)?;
+
+        loop {
+            write!(out, "(gb) ")?;
+            out.flush()?;
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            // Only a Ctrl-C while running counts
+            self.interrupted.store(false, Ordering::Relaxed);
+
+            if !self.execute(machine, &line, &mut out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs a command, returning false once the debugger should exit
+    pub fn execute(
+        &mut self,
+        machine: &mut Machine,
+        line: &str,
+        out: &mut impl Write,
+    ) -> io::Result<bool> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_owned(),
+        };
+        self.last_command = line.clone();
+
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(true);
+        };
+        let args: Vec<&str> = words.collect();
+
+        match self.command(machine, command, &args, out) {
+            Ok(keep_going) => Ok(keep_going),
+            Err(error) => {
+                writeln!(out, "{}", error)?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn command(
+        &mut self,
+        machine: &mut Machine,
+        command: &str,
+        args: &[&str],
+        out: &mut impl Write,
+    ) -> Result<bool> {
+        let symbols = Rc::clone(&self.symbols);
+
+        match command {
+            "break" | "b" => {
+                let mut breakpoint = parse_breakpoint(args.first().copied(), &symbols)?;
+
+                match args.get(1) {
+                    Some(&"if") => {
+                        breakpoint.condition = Some(parse_condition(&args[2..], &symbols)?)
+                    }
+                    Some(word) => bail!("Expected if, not {}", word),
+                    None => {}
+                }
+
+                writeln!(
+                    out,
+                    "Breakpoint {} at {}",
+                    self.breakpoints.len(),
+                    format_breakpoint(&breakpoint, &symbols)
+                )?;
+                self.breakpoints.push(breakpoint);
+            }
+            "condition" => {
+                let breakpoint = self.breakpoint(args.first())?;
+                breakpoint.condition = match args.len() {
+                    1 => None,
+                    _ => Some(parse_condition(&args[1..], &symbols)?),
+                };
+            }
+            "ignore" => {
+                let breakpoint = self.breakpoint(args.first())?;
+                let count: u64 = args.get(1).ok_or(anyhow!("How many times?"))?.parse()?;
+                breakpoint.ignore = breakpoint.hits + count;
+            }
+            "action" => {
+                let breakpoint = self.breakpoint(args.first())?;
+                breakpoint.action = match args.get(1) {
+                    Some(&"stop") => Action::Stop,
+                    Some(&"log") => Action::Log,
+                    Some(&"dump") => Action::Dump,
+                    Some(&"save") => {
+                        Action::Save(args.get(2).ok_or(anyhow!("Which slot?"))?.parse()?)
+                    }
+                    _ => bail!("Breakpoints can stop, log, dump or save"),
+                };
+            }
+            "delete" => {
+                let index: usize = args.first().ok_or(anyhow!("Which breakpoint?"))?.parse()?;
+                if index >= self.breakpoints.len() {
+                    bail!("No breakpoint {}", index);
+                }
+                self.breakpoints.remove(index);
+            }
+            "breakpoints" | "bl" => {
+                for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+                    write!(
+                        out,
+                        "{}: {}",
+                        index,
+                        format_breakpoint(breakpoint, &symbols)
+                    )?;
+
+                    if let Some(condition) = &breakpoint.condition {
+                        write!(out, " if {}", condition.text)?;
+                    }
+
+                    write!(out, ", hit count {}", breakpoint.hits)?;
+                    if breakpoint.ignore > breakpoint.hits {
+                        write!(out, ", ignoring {}", breakpoint.ignore - breakpoint.hits)?;
+                    }
+
+                    match breakpoint.action {
+                        Action::Stop => writeln!(out)?,
+                        Action::Log => writeln!(out, ", logs")?,
+                        Action::Dump => writeln!(out, ", dumps")?,
+                        Action::Save(slot) => writeln!(out, ", saves slot {}", slot)?,
+                    }
+                }
+            }
+            "watch" | "w" => {
+                let watchpoint = parse_watchpoint(args, &symbols)?;
+                let index = machine.cpu.bus.add_watchpoint(watchpoint);
+                writeln!(out, "Watchpoint {} on {}", index, watchpoint)?;
+            }
+            "unwatch" => {
+                let index: usize = args.first().ok_or(anyhow!("Which watchpoint?"))?.parse()?;
+                if machine.cpu.bus.remove_watchpoint(index).is_none() {
+                    bail!("No watchpoint {}", index);
+                }
+            }
+            "watchpoints" | "wl" => {
+                for (index, watchpoint) in machine.cpu.bus.watchpoints().iter().enumerate() {
+                    writeln!(out, "{}: {}", index, watchpoint)?;
+                }
+            }
+            "step" | "s" => {
+                let count = args.first().map_or(Ok(1), |count| count.parse())?;
+                let mut steps = 0;
+                self.run_until(machine, out, |_| {
+                    steps += 1;
+                    steps >= count
+                })?;
+            }
+            "next" | "n" => {
+                let cpu = &machine.cpu;
+                let opcode = Opcode::from_byte(cpu.bus.peek(cpu.pc));
+
+                if matches!(opcode, Opcode::CALL(_) | Opcode::RST(_)) {
+                    let return_address = cpu.pc.wrapping_add(opcode.byte_count() as u16);
+                    let sp = cpu.sp;
+                    self.run_until(machine, out, |machine| {
+                        machine.cpu.pc == return_address && machine.cpu.sp >= sp
+                    })?;
+                } else {
+                    self.run_until(machine, out, |_| true)?;
+                }
+            }
+            "finish" | "f" => {
+                let sp = machine.cpu.sp;
+                let mut returning = false;
+                self.run_until(machine, out, |machine| {
+                    let returned = returning && machine.cpu.sp > sp;
+                    let cpu = &machine.cpu;
+                    returning = matches!(
+                        Opcode::from_byte(cpu.bus.peek(cpu.pc)),
+                        Opcode::RET(_) | Opcode::RETI
+                    );
+
+                    returned
+                })?;
+            }
+            "rewind" => {
+                // Inputs recorded since then would be replayed at the wrong time
+                if self.recorder.is_some() {
+                    bail!("Can't rewind while recording a movie");
+                }
+
+                let frames = args.first().map_or(Ok(60), |frames| frames.parse())?;
+                self.rewind.rewind(machine, frames)?;
+
+                writeln!(out, "Frame {}", machine.frames())?;
+                print_instruction(machine, &symbols, out)?;
+            }
+            "press" | "release" => {
+                let button: Button = args.first().ok_or(anyhow!("Which button?"))?.parse()?;
+                let pressed = command == "press";
+
+                match &mut self.recorder {
+                    Some(recorder) => recorder.set_button(machine, button, pressed),
+                    None => self.rewind.set_button(machine, button, pressed),
+                }
+            }
+            "continue" | "c" => self.run_until(machine, out, |_| false)?,
+            "until" | "u" => {
+                let target = parse_breakpoint(args.first().copied(), &symbols)?;
+                self.run_until(machine, out, |machine| {
+                    target.hit(&machine.cpu.bus, machine.cpu.pc)
+                })?;
+            }
+            "registers" | "r" => print_registers(machine, out)?,
+            "memory" | "x" => {
+                let start = args.first().ok_or(anyhow!("Which address?"))?;
+                let start = parse_address(start, &symbols)?;
+                let length = args.get(1).map_or(Ok(0x40), |length| parse_hex(length))?;
+
+                for row in (0..length as u32).step_by(16) {
+                    let address = start.wrapping_add(row as u16);
+                    let bytes: Vec<String> = (0..16.min(length as u32 - row))
+                        .map(|i| {
+                            format!(
+                                "{:02X}",
+                                machine.cpu.bus.peek(address.wrapping_add(i as u16))
+                            )
+                        })
+                        .collect();
+
+                    writeln!(out, "{:04X}: {}", address, bytes.join(" "))?;
+                }
+            }
+            "list" | "l" => {
+                let mut address = match args.first() {
+                    Some(address) => parse_address(address, &symbols)?,
+                    None => list_start(machine),
+                };
+                let count = args.get(1).map_or(Ok(8), |count| count.parse())?;
+
+                for _ in 0..count {
+                    let length = disassemble(machine, address, &symbols, out)?;
+                    address = address.wrapping_add(length as u16);
+                }
+            }
+            "print" | "p" => {
+                let value = parse_condition(args, &symbols)?
+                    .expression
+                    .evaluate(machine);
+                writeln!(out, "{} (0x{:X})", value, value)?;
+            }
+            "help" | "h" => writeln!(out, "{}", HELP)?,
+            "quit" | "q" => return Ok(false),
+            _ => bail!("Unknown command {}, try help", command),
+        }
+
+        Ok(true)
+    }
+
+    fn breakpoint(&mut self, index: Option<&&str>) -> Result<&mut Breakpoint> {
+        let index: usize = index.ok_or(anyhow!("Which breakpoint?"))?.parse()?;
+
+        self.breakpoints
+            .get_mut(index)
+            .ok_or(anyhow!("No breakpoint {}", index))
+    }
+
+    // Runs instructions until the condition holds after one of them, or a
+    // breakpoint, watchpoint or Ctrl-C stops it. At least one instruction is
+    // always run, so execution can carry on from a breakpoint.
+    fn run_until(
+        &mut self,
+        machine: &mut Machine,
+        out: &mut impl Write,
+        mut stop: impl FnMut(&Machine) -> bool,
+    ) -> Result<()> {
+        loop {
+            let result = match &mut self.recorder {
+                Some(recorder) => recorder.step(machine),
+                None => machine.step(),
+            };
+
+            if let Err(error) = result {
+                writeln!(out, "Stopped: {}", error)?;
+                break;
+            }
+
+            self.rewind.capture(machine);
+
+            let cpu = &machine.cpu;
+            let hits = cpu.bus.watch_hits();
+            if !hits.is_empty() {
+                for hit in hits.iter() {
+                    writeln!(out, "{}", hit)?;
+                }
+                break;
+            }
+
+            let mut stopped = false;
+            for (index, breakpoint) in self.breakpoints.iter_mut().enumerate() {
+                if !breakpoint.hit(&cpu.bus, cpu.pc) || !breakpoint.trigger(machine) {
+                    continue;
+                }
+
+                match breakpoint.action {
+                    Action::Stop => {
+                        writeln!(out, "Breakpoint {}", index)?;
+                        stopped = true;
+                    }
+                    Action::Log => {
+                        writeln!(out, "Breakpoint {}, hit {}", index, breakpoint.hits)?;
+                        print_instruction(machine, &self.symbols, out)?;
+                    }
+                    Action::Dump => {
+                        writeln!(out, "Breakpoint {}, hit {}", index, breakpoint.hits)?;
+                        print_registers(machine, out)?;
+                        print_instruction(machine, &self.symbols, out)?;
+                    }
+                    Action::Save(slot) => {
+                        let slot_path = slot_path(&self.rom_path, slot)?;
+                        fs::write(&slot_path, machine.save_state())
+                            .with_context(|| format!("Can't write {}", slot_path))?;
+
+                        writeln!(out, "Breakpoint {}, saved {}", index, slot_path)?;
+                    }
+                }
+            }
+
+            if stopped || stop(machine) {
+                break;
+            }
+
+            if self.interrupted.swap(false, Ordering::Relaxed) {
+                writeln!(out, "Interrupted")?;
+                break;
+            }
+        }
+
+        print_instruction(machine, &self.symbols, out)?;
+
+        Ok(())
+    }
+}
+
+fn print_registers(machine: &Machine, out: &mut impl Write) -> io::Result<()> {
+    let cpu = &machine.cpu;
+
+    writeln!(
+        out,
+        "{} SP:{:04X} PC:{:04X} [{}] IME:{} Bank:{:02X}",
+        cpu.registers,
+        cpu.sp,
+        cpu.pc,
+        cpu.registers.f,
+        cpu.ime as u8,
+        cpu.bus.rom_bank(0x4000)
+    )
+}
+
+// Where listing starts by default, a few instructions before PC. Going back
+// lands in the middle of instructions as often as not, so it's the furthest
+// address that decodes to PC in few enough instructions.
+fn list_start(machine: &Machine) -> u16 {
+    let bus = &machine.cpu.bus;
+    let pc = machine.cpu.pc;
+
+    for back in (1..=LIST_CONTEXT * 3).rev() {
+        let Some(start) = pc.checked_sub(back) else {
+            continue;
+        };
+
+        let mut address = start;
+        let mut count = 0;
+        while address < pc && count < LIST_CONTEXT {
+            address =
+                address.wrapping_add(Opcode::from_byte(bus.peek(address)).byte_count() as u16);
+            count += 1;
+        }
+
+        if address == pc {
+            return start;
+        }
+    }
+
+    pc
+}
+
+fn print_instruction(machine: &Machine, symbols: &Symbols, out: &mut impl Write) -> io::Result<()> {
+    disassemble(machine, machine.cpu.pc, symbols, out).map(|_| ())
+}
+
+// Prints the instruction at the address, returning its length. A label for
+// the address goes on the line before, and one for a jump or call target
+// after the instruction.
+fn disassemble(
+    machine: &Machine,
+    address: u16,
+    symbols: &Symbols,
+    out: &mut impl Write,
+) -> io::Result<u8> {
+    let bus = &machine.cpu.bus;
+    let opcode = Opcode::from_byte(bus.peek(address));
+    let length = opcode.byte_count();
+
+    let bytes: Vec<u8> = (0..length)
+        .map(|i| bus.peek(address.wrapping_add(i as u16)))
+        .collect();
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    let marker = if address == machine.cpu.pc { '>' } else { ' ' };
+
+    if let Some(label) = symbols.label(bus.rom_bank(address), address) {
+        writeln!(out, "{}:", label)?;
+    }
+
+    write!(
+        out,
+        "{} {:02X}:{:04X}  {:<9} {}",
+        marker,
+        bus.rom_bank(address),
+        address,
+        hex.join(" "),
+        opcode.debug_fmt(bus, address)
+    )?;
+
+    let target = opcode.target(&bytes[1..], address);
+    match target.and_then(|target| symbols.label(bus.rom_bank(target), target)) {
+        Some(label) => writeln!(out, " <{}>", label)?,
+        None => writeln!(out)?,
+    }
+
+    Ok(length)
+}
+
+// Hex, optionally prefixed with $ or 0x
+fn parse_hex(text: &str) -> Result<u16> {
+    let hex = text
+        .strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+
+    u16::from_str_radix(hex, 16).map_err(|_| anyhow!("Not an address: {}", text))
+}
+
+// Labels come first, so one that looks like hex still works
+fn parse_address(text: &str, symbols: &Symbols) -> Result<u16> {
+    match symbols.address(text) {
+        Some((_, address)) => Ok(address),
+        None => parse_hex(text),
+    }
+}
+
+// Labels in switchable ROM only break in their own bank
+fn parse_breakpoint(text: Option<&str>, symbols: &Symbols) -> Result<Breakpoint> {
+    let text = text.ok_or(anyhow!("Which address?"))?;
+
+    if let Some((bank, address)) = symbols.address(text) {
+        let bank = (0x4000..=0x7FFF).contains(&address).then_some(bank);
+        return Ok(Breakpoint::at(bank, address));
+    }
+
+    match text.split_once(':') {
+        Some((bank, address)) => Ok(Breakpoint::at(
+            Some(parse_hex(bank)? as usize),
+            parse_hex(address)?,
+        )),
+        None => Ok(Breakpoint::at(None, parse_hex(text)?)),
+    }
+}
+
+// Expressions can have spaces in them, so they take the rest of the words
+fn parse_condition(words: &[&str], symbols: &Symbols) -> Result<Condition> {
+    let text = words.join(" ");
+
+    Ok(Condition {
+        expression: Expression::parse(&text, symbols)?,
+        text,
+    })
+}
+
+// Takes the access, an address or range, and optionally a value
+fn parse_watchpoint(args: &[&str], symbols: &Symbols) -> Result<Watchpoint> {
+    let access = match args.first() {
+        Some(&"r" | &"read") => Access::Read,
+        Some(&"w" | &"write") => Access::Write,
+        Some(&"x" | &"execute") => Access::Execute,
+        _ => bail!("Watch reads (r), writes (w) or execution (x)?"),
+    };
+
+    let addresses = args.get(1).ok_or(anyhow!("Which address?"))?;
+    let watchpoint = match addresses.split_once('-') {
+        Some((start, end)) => {
+            let start = parse_address(start, symbols)?;
+            Watchpoint::range(access, start, parse_address(end, symbols)?)?
+        }
+        None => Watchpoint::new(access, parse_address(addresses, symbols)?),
+    };
+
+    match args.get(2) {
+        Some(value) => {
+            let value = u8::from_str_radix(value.trim_start_matches('$'), 16)
+                .map_err(|_| anyhow!("Not a byte: {}", value))?;
+
+            Ok(watchpoint.with_value(value))
+        }
+        None => Ok(watchpoint),
+    }
+}
+
+fn format_breakpoint(breakpoint: &Breakpoint, symbols: &Symbols) -> String {
+    let address = match breakpoint.bank {
+        Some(bank) => format!("{:02X}:{:04X}", bank, breakpoint.address),
+        None => format!("{:04X}", breakpoint.address),
+    };
+
+    let bank = breakpoint.bank.unwrap_or(0);
+    match symbols.label(bank, breakpoint.address) {
+        Some(label) => format!("{} <{}>", address, label),
+        None => address,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::{cartridge::Cartridge, model::Model, movie::replay};
+
+    // Calls a function at 0x0200 in a loop, which writes to 0xC000. Bank 2
+    // has a RET at 0x4000, and bank 1 a NOP.
+    fn machine() -> Machine {
+        let mut rom = vec![0; 0x10000];
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x01;
+        rom[0x0100..0x0106].copy_from_slice(&[0xCD, 0x00, 0x02, 0x00, 0x18, 0xFA]);
+        rom[0x0200..0x0206].copy_from_slice(&[0x3C, 0xEA, 0x00, 0xC0, 0x00, 0xC9]);
+        rom[0x8000] = 0xC9;
+
+        Machine::new(Bus::new(Cartridge::new(rom), Model::DMG))
+    }
+
+    fn run(debugger: &mut Debugger, machine: &mut Machine, line: &str) -> String {
+        let mut out = Vec::new();
+        debugger.execute(machine, line, &mut out).unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut machine = machine();
+        let mut debugger = Debugger::new("test.gb");
+
+        run(&mut debugger, &mut machine, "break 0204");
+        let output = run(&mut debugger, &mut machine, "continue");
+        assert!(output.starts_with("Breakpoint 0\n"));
+        assert_eq!(machine.cpu.pc, 0x0204);
+        assert_eq!(machine.cpu.bus.peek(0xC000), 0x02);
+
+        // Carries on past the breakpoint, back round the loop
+        run(&mut debugger, &mut machine, "c");
+        assert_eq!(machine.cpu.pc, 0x0204);
+        assert_eq!(machine.cpu.bus.peek(0xC000), 0x03);
+
+        // Breakpoints in another bank don't stop anything
+        run(&mut debugger, &mut machine, "delete 0");
+        run(&mut debugger, &mut machine, "b 02:0103");
+        let output = run(&mut debugger, &mut machine, "step 20");
+        assert!(!output.contains("Breakpoint"));
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut machine = machine();
+        let mut debugger = Debugger::new("test.gb");
+
+        // Steps over the call
+        let output = run(&mut debugger, &mut machine, "next");
+        assert_eq!(machine.cpu.pc, 0x0103);
+        assert_eq!(output, "> 00:0103  00        NOP\n");
+
+        // An empty line repeats it
+        run(&mut debugger, &mut machine, "");
+        run(&mut debugger, &mut machine, "s 2");
+        assert_eq!(machine.cpu.pc, 0x0200);
+
+        run(&mut debugger, &mut machine, "finish");
+        assert_eq!(machine.cpu.pc, 0x0103);
+
+        run(&mut debugger, &mut machine, "until 0205");
+        assert_eq!(machine.cpu.pc, 0x0205);
+
+        // Ctrl-C stops the machine, even without a breakpoint
+        debugger.interrupted.store(true, Ordering::Relaxed);
+        let output = run(&mut debugger, &mut machine, "continue");
+        assert!(output.starts_with("Interrupted\n"));
+        assert_eq!(machine.cpu.pc, 0x0103);
+
+        // History is captured while running, to go back through
+        run(&mut debugger, &mut machine, "step 100000");
+        let frames = machine.frames();
+        assert!(frames > 12);
+
+        let output = run(&mut debugger, &mut machine, "rewind 10");
+        assert!(output.starts_with(&format!("Frame {}\n", frames - 10)));
+        assert_eq!(machine.frames(), frames - 10);
+    }
+
+    #[test]
+    fn test_recording() {
+        let mut machine = machine();
+        let recorder = Recorder::start(&machine, 1);
+        let mut debugger = Debugger::new("test.gb").with_recorder(recorder);
+
+        run(&mut debugger, &mut machine, "step 10");
+        run(&mut debugger, &mut machine, "press start");
+        run(&mut debugger, &mut machine, "step 50000");
+        run(&mut debugger, &mut machine, "release START");
+        run(&mut debugger, &mut machine, "step 50000");
+
+        let output = run(&mut debugger, &mut machine, "press turbo");
+        assert!(output.starts_with("Unknown button"));
+        let output = run(&mut debugger, &mut machine, "rewind 1");
+        assert!(output.starts_with("Can't rewind"));
+
+        let movie = debugger.finish_recording().unwrap();
+        assert_eq!(movie.inputs().len(), 2);
+        assert_eq!(movie.end_frame(), machine.frames());
+
+        let mut replayed = self::machine();
+        assert_eq!(replay(movie, &mut replayed).unwrap(), None);
+        assert_eq!(replayed.frames(), machine.frames());
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut machine = machine();
+        let mut debugger = Debugger::new("test.gb");
+
+        run(
+            &mut debugger,
+            &mut machine,
+            "b 0204 if [$C000] == 5 && !F.Z",
+        );
+        run(&mut debugger, &mut machine, "c");
+        assert_eq!(machine.cpu.registers.a, 5);
+
+        // Hits only count while the condition holds
+        run(&mut debugger, &mut machine, "condition 0 A >= 0x07");
+        run(&mut debugger, &mut machine, "ignore 0 2");
+        run(&mut debugger, &mut machine, "c");
+        assert_eq!(machine.cpu.registers.a, 9);
+
+        run(&mut debugger, &mut machine, "action 0 log");
+        run(&mut debugger, &mut machine, "b 0200 if A == 11");
+        let output = run(&mut debugger, &mut machine, "c");
+        assert!(output.starts_with("Breakpoint 0, hit 5\n>"));
+        assert!(output.contains("Breakpoint 1\n"));
+
+        let output = run(&mut debugger, &mut machine, "bl");
+        assert_eq!(
+            output,
+            "0: 0204 if A >= 0x07, hit count 6, logs\n1: 0200 if A == 11, hit count 1\n"
+        );
+
+        let output = run(&mut debugger, &mut machine, "p [$C000] + 1");
+        assert_eq!(output, "12 (0xC)\n");
+
+        let output = run(&mut debugger, &mut machine, "b 0204 if A ==");
+        assert_eq!(output, "Expression ends too soon\n");
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut machine = machine();
+        let mut debugger = Debugger::new("test.gb");
+
+        run(&mut debugger, &mut machine, "watch w C000 03");
+        let output = run(&mut debugger, &mut machine, "c");
+        assert!(output.starts_with("Watchpoint 0: write C000 = 03 at 00:0201\n"));
+        assert_eq!(machine.cpu.pc, 0x0204);
+
+        run(&mut debugger, &mut machine, "unwatch 0");
+        run(&mut debugger, &mut machine, "w x 0200-0201");
+        let output = run(&mut debugger, &mut machine, "c");
+        assert!(output.starts_with("Watchpoint 0: execute 0200 = 3C at 00:0200\n"));
+
+        // Looking at memory doesn't count as reading it
+        run(&mut debugger, &mut machine, "w r C000");
+        run(&mut debugger, &mut machine, "x C000 1");
+        let output = run(&mut debugger, &mut machine, "wl");
+        assert_eq!(output, "0: execute 0200-0201\n1: read C000\n");
+
+        // Removing the last watchpoint leaves no hit behind to stop on
+        run(&mut debugger, &mut machine, "unwatch 1");
+        run(&mut debugger, &mut machine, "unwatch 0");
+        run(&mut debugger, &mut machine, "b 0103");
+        let output = run(&mut debugger, &mut machine, "c");
+        assert!(output.starts_with("Breakpoint 0\n"), "{}", output);
+        assert_eq!(machine.cpu.pc, 0x0103);
+    }
+
+    #[test]
+    fn test_inspecting() {
+        let mut machine = machine();
+        let mut debugger = Debugger::new("test.gb");
+
+        let output = run(&mut debugger, &mut machine, "x 0200 4");
+        assert_eq!(output, "0200: 3C EA 00 C0\n");
+
+        let output = run(&mut debugger, &mut machine, "list 0200 2");
+        assert_eq!(
+            output,
+            "  00:0200  3C        INC A\n  00:0201  EA 00 C0  LD [C000], A\n"
+        );
+
+        let output = run(&mut debugger, &mut machine, "r");
+        assert!(output.contains("PC:0100"));
+
+        // Listing starts a few instructions back, where they decode to PC
+        run(&mut debugger, &mut machine, "until 0204");
+        let output = run(&mut debugger, &mut machine, "list");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "  00:01FF  00        NOP");
+        assert_eq!(lines[3], "> 00:0204  00        NOP");
+        assert_eq!(lines.len(), 8);
+
+        let output = run(&mut debugger, &mut machine, "frobnicate");
+        assert!(output.starts_with("Unknown command"));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut machine = machine();
+        let symbols = Symbols::parse("00:0200 Tick\n00:c000 wTicks\n02:4000 Far").unwrap();
+        let mut debugger = Debugger::new("test.gb").with_symbols(Rc::new(symbols));
+
+        let output = run(&mut debugger, &mut machine, "b Tick");
+        assert_eq!(output, "Breakpoint 0 at 0200 <Tick>\n");
+        let output = run(&mut debugger, &mut machine, "b Far");
+        assert_eq!(output, "Breakpoint 1 at 02:4000 <Far>\n");
+
+        let output = run(&mut debugger, &mut machine, "c");
+        assert_eq!(output, "Breakpoint 0\nTick:\n> 00:0200  3C        INC A\n");
+
+        let output = run(&mut debugger, &mut machine, "list 0100 1");
+        assert_eq!(output, "  00:0100  CD 00 02  CALL 0200 <Tick>\n");
+
+        run(&mut debugger, &mut machine, "until 0204");
+        let output = run(&mut debugger, &mut machine, "x wTicks 1");
+        assert_eq!(output, "C000: 02\n");
+        let output = run(&mut debugger, &mut machine, "p [wTicks] == A");
+        assert_eq!(output, "1 (0x1)\n");
+    }
+}
@@ -0,0 +1,518 @@
+use std::{fmt::Display, iter::Peekable, str::Chars};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{hardware::machine::Machine, symbols::Symbols};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+}
+
+impl Register {
+    fn from_name(name: &str) -> Option<Register> {
+        Some(match name {
+            "A" => Register::A,
+            "B" => Register::B,
+            "C" => Register::C,
+            "D" => Register::D,
+            "E" => Register::E,
+            "F" => Register::F,
+            "H" => Register::H,
+            "L" => Register::L,
+            "AF" => Register::AF,
+            "BC" => Register::BC,
+            "DE" => Register::DE,
+            "HL" => Register::HL,
+            "SP" => Register::SP,
+            "PC" => Register::PC,
+            _ => return None,
+        })
+    }
+
+    fn value(&self, machine: &Machine) -> i64 {
+        let cpu = &machine.cpu;
+
+        (match self {
+            Register::A => cpu.registers.a as u16,
+            Register::B => cpu.registers.b as u16,
+            Register::C => cpu.registers.c as u16,
+            Register::D => cpu.registers.d as u16,
+            Register::E => cpu.registers.e as u16,
+            Register::F => u8::from(cpu.registers.f) as u16,
+            Register::H => cpu.registers.h as u16,
+            Register::L => cpu.registers.l as u16,
+            Register::AF => cpu.registers.af(),
+            Register::BC => cpu.registers.bc(),
+            Register::DE => cpu.registers.de(),
+            Register::HL => cpu.registers.hl(),
+            Register::SP => cpu.sp,
+            Register::PC => cpu.pc,
+        }) as i64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Zero,
+    Subtract,
+    HalfCarry,
+    Carry,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unary {
+    Not,
+    Negate,
+    Complement,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binary {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Subtract,
+}
+
+// Operators from the loosest binding to the tightest, with their symbols
+const PRECEDENCE: &[&[(&str, Binary)]] = &[
+    &[("||", Binary::Or)],
+    &[("&&", Binary::And)],
+    &[("|", Binary::BitOr)],
+    &[("^", Binary::BitXor)],
+    &[("&", Binary::BitAnd)],
+    &[("==", Binary::Equal), ("!=", Binary::NotEqual)],
+    &[
+        ("<=", Binary::LessEqual),
+        (">=", Binary::GreaterEqual),
+        ("<", Binary::Less),
+        (">", Binary::Greater),
+    ],
+    &[("+", Binary::Add), ("-", Binary::Subtract)],
+];
+
+/// A condition over the machine's registers, flags and memory, such as
+/// `[HL] == 0x3C`, `A > 0x10 && F.Z` or `[$C0A0].w == 1234`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expression {
+    Number(i64),
+    Register(Register),
+    Flag(Flag),
+
+    // A byte in memory, or a little endian word
+    Memory {
+        address: Box<Expression>,
+        word: bool,
+    },
+
+    Unary(Unary, Box<Expression>),
+    Binary(Binary, Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    /// Parses an expression, where labels from the symbols stand for their
+    /// addresses
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Expression> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            position: 0,
+            symbols,
+        };
+
+        let expression = parser.binary(0)?;
+        match parser.tokens.get(parser.position) {
+            Some(token) => bail!("Unexpected {} in expression", token),
+            None => Ok(expression),
+        }
+    }
+
+    /// Evaluates the expression. Comparisons and logic give 1 for true and
+    /// 0 for false, and any value other than 0 counts as true.
+    pub fn evaluate(&self, machine: &Machine) -> i64 {
+        let flags = machine.cpu.registers.f;
+
+        match self {
+            Expression::Number(value) => *value,
+            Expression::Register(register) => register.value(machine),
+            Expression::Flag(flag) => {
+                let set = match flag {
+                    Flag::Zero => flags.zero(),
+                    Flag::Subtract => flags.subtract(),
+                    Flag::HalfCarry => flags.half_carry(),
+                    Flag::Carry => flags.carry(),
+                };
+
+                set as i64
+            }
+            Expression::Memory { address, word } => {
+                let address = address.evaluate(machine) as u16;
+                let bus = &machine.cpu.bus;
+
+                match word {
+                    true => bus.peek_word(address) as i64,
+                    false => bus.peek(address) as i64,
+                }
+            }
+            Expression::Unary(op, operand) => {
+                let value = operand.evaluate(machine);
+
+                match op {
+                    Unary::Not => (value == 0) as i64,
+                    Unary::Negate => value.wrapping_neg(),
+                    Unary::Complement => !value,
+                }
+            }
+            Expression::Binary(op, left, right) => {
+                let left = left.evaluate(machine);
+
+                // Logic short circuits, like it reads
+                match op {
+                    Binary::Or if left != 0 => return 1,
+                    Binary::And if left == 0 => return 0,
+                    _ => {}
+                }
+
+                let right = right.evaluate(machine);
+
+                match op {
+                    Binary::Or | Binary::And => (right != 0) as i64,
+                    Binary::Equal => (left == right) as i64,
+                    Binary::NotEqual => (left != right) as i64,
+                    Binary::Less => (left < right) as i64,
+                    Binary::LessEqual => (left <= right) as i64,
+                    Binary::Greater => (left > right) as i64,
+                    Binary::GreaterEqual => (left >= right) as i64,
+                    Binary::BitOr => left | right,
+                    Binary::BitXor => left ^ right,
+                    Binary::BitAnd => left & right,
+                    Binary::Add => left.wrapping_add(right),
+                    Binary::Subtract => left.wrapping_sub(right),
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, machine: &Machine) -> bool {
+        self.evaluate(machine) != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+// Longer symbols come first, so "<=" isn't read as "<" then "="
+const SYMBOLS: &[&str] = &[
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "|", "^", "&", "+", "-", "!", "~", "(", ")", "[",
+    "]", ".",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '$' {
+            chars.next();
+            tokens.push(Token::Number(number(&mut chars, 16)?));
+        } else if c.is_ascii_digit() {
+            let rest: String = chars.clone().take(2).collect();
+
+            if rest.eq_ignore_ascii_case("0x") {
+                chars.nth(1);
+                tokens.push(Token::Number(number(&mut chars, 16)?));
+            } else {
+                tokens.push(Token::Number(number(&mut chars, 10)?));
+            }
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                name.push(c);
+            }
+
+            tokens.push(Token::Name(name));
+        } else {
+            let rest: String = chars.clone().take(2).collect();
+            let Some(symbol) = SYMBOLS.iter().find(|s| rest.starts_with(**s)) else {
+                bail!("Unexpected {} in expression", c);
+            };
+
+            chars.nth(symbol.len() - 1);
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn number(chars: &mut Peekable<Chars>, radix: u32) -> Result<i64> {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_digit(radix)) {
+        digits.push(c);
+    }
+
+    i64::from_str_radix(&digits, radix).map_err(|_| anyhow!("Bad number in expression"))
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    symbols: &'a Symbols,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found =
+            matches!(self.tokens.get(self.position), Some(Token::Symbol(s)) if *s == symbol);
+
+        if found {
+            self.position += 1;
+        }
+
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<()> {
+        match self.eat(symbol) {
+            true => Ok(()),
+            false => bail!("Expected {} in expression", symbol),
+        }
+    }
+
+    // Parses operators at this level of precedence and tighter
+    fn binary(&mut self, level: usize) -> Result<Expression> {
+        let Some(operators) = PRECEDENCE.get(level) else {
+            return self.unary();
+        };
+
+        let mut left = self.binary(level + 1)?;
+
+        'operators: loop {
+            for (symbol, op) in operators.iter() {
+                if self.eat(symbol) {
+                    let right = self.binary(level + 1)?;
+                    left = Expression::Binary(*op, Box::new(left), Box::new(right));
+
+                    continue 'operators;
+                }
+            }
+
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expression> {
+        for (symbol, op) in [
+            ("!", Unary::Not),
+            ("-", Unary::Negate),
+            ("~", Unary::Complement),
+        ] {
+            if self.eat(symbol) {
+                return Ok(Expression::Unary(op, Box::new(self.unary()?)));
+            }
+        }
+
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expression> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expression::Number(value)),
+            Some(Token::Symbol("(")) => {
+                let expression = self.binary(0)?;
+                self.expect(")")?;
+
+                Ok(expression)
+            }
+            Some(Token::Symbol("[")) => {
+                let address = self.binary(0)?;
+                self.expect("]")?;
+
+                let word = match self.eat(".") {
+                    true => match self.next() {
+                        Some(Token::Name(width)) if width.eq_ignore_ascii_case("w") => true,
+                        Some(Token::Name(width)) if width.eq_ignore_ascii_case("b") => false,
+                        _ => bail!("Memory is read as .b or .w"),
+                    },
+                    false => false,
+                };
+
+                Ok(Expression::Memory {
+                    address: Box::new(address),
+                    word,
+                })
+            }
+            Some(Token::Name(name)) => {
+                if let Some(address) = self.label(&name) {
+                    return Ok(Expression::Number(address as i64));
+                }
+
+                let name = name.to_ascii_uppercase();
+
+                if name == "F" && self.eat(".") {
+                    let flag = match self.next() {
+                        Some(Token::Name(flag)) => flag.to_ascii_uppercase(),
+                        _ => String::new(),
+                    };
+
+                    return match flag.as_str() {
+                        "Z" => Ok(Expression::Flag(Flag::Zero)),
+                        "N" => Ok(Expression::Flag(Flag::Subtract)),
+                        "H" => Ok(Expression::Flag(Flag::HalfCarry)),
+                        "C" => Ok(Expression::Flag(Flag::Carry)),
+                        _ => bail!("Flags are F.Z, F.N, F.H and F.C"),
+                    };
+                }
+
+                match Register::from_name(&name) {
+                    Some(register) => Ok(Expression::Register(register)),
+                    None => bail!("Unknown register {} in expression", name),
+                }
+            }
+            Some(token) => bail!("Unexpected {} in expression", token),
+            None => bail!("Expression ends too soon"),
+        }
+    }
+
+    // Labels come before registers. Local labels are written in full, like
+    // Main.loop, so the longest name that's a label is taken.
+    fn label(&mut self, name: &str) -> Option<u16> {
+        let mut name = name.to_owned();
+        let mut position = self.position;
+        let mut found = None;
+
+        loop {
+            if let Some((_, address)) = self.symbols.address(&name) {
+                found = Some((address, position));
+            }
+
+            match self.tokens.get(position..position + 2) {
+                Some([Token::Symbol("."), Token::Name(part)]) => {
+                    name = format!("{}.{}", name, part);
+                    position += 2;
+                }
+                _ => break,
+            }
+        }
+
+        let (address, position) = found?;
+        self.position = position;
+
+        Some(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::{bus::Bus, cartridge::Cartridge, model::Model, Memory};
+
+    fn evaluate(machine: &Machine, text: &str) -> i64 {
+        Expression::parse(text, &Symbols::default())
+            .unwrap()
+            .evaluate(machine)
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut machine = Machine::new(Bus::new(Cartridge::new(vec![0; 0x8000]), Model::DMG));
+        machine.cpu.registers.a = 0x11;
+        machine.cpu.registers.set_hl(0xC000);
+        machine.cpu.registers.f.set_zero(true);
+        machine.cpu.bus.write(0xC000, 0x3C);
+        machine.cpu.bus.write(0xC0A0, 0xD2);
+        machine.cpu.bus.write(0xC0A1, 0x04);
+
+        assert_eq!(evaluate(&machine, "[HL] == 0x3C"), 1);
+        assert_eq!(evaluate(&machine, "A > 0x10 && F.Z"), 1);
+        assert_eq!(evaluate(&machine, "a > $11 || f.c"), 0);
+        assert_eq!(evaluate(&machine, "[$C0A0].w == 1234"), 1);
+        assert_eq!(evaluate(&machine, "[HL + 0xA1]"), 0x04);
+        assert_eq!(evaluate(&machine, "1 + 2 & 6"), 2);
+        assert_eq!(evaluate(&machine, "!(PC == 0x100) | -1"), -1);
+        assert_eq!(evaluate(&machine, "A - 2 - 1"), 0x0E);
+
+        // Comparisons bind tighter than the bitwise operators, as in C
+        machine.cpu.bus.write(0xFF80, 0x10);
+        assert_eq!(evaluate(&machine, "[$FF80] & 0x10 == 0"), 0);
+        assert_eq!(evaluate(&machine, "([$FF80] & 0x10) == 0x10"), 1);
+        assert_eq!(evaluate(&machine, "A | 2 == 2"), 0x11);
+        assert_eq!(evaluate(&machine, "2 == 1 < 2"), 0);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for text in ["", "A ==", "[HL", "Q > 1", "F.X", "1 2", "[HL].q", "A @ 1"] {
+            assert!(
+                Expression::parse(text, &Symbols::default()).is_err(),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut machine = Machine::new(Bus::new(Cartridge::new(vec![0; 0x8000]), Model::DMG));
+        machine.cpu.bus.write(0xC001, 0x42);
+        machine.cpu.registers.a = 0x07;
+
+        let symbols = Symbols::parse("00:c000 wBuffer\n00:c001 wBuffer.end\n00:0100 a").unwrap();
+        let evaluate = |text| {
+            Expression::parse(text, &symbols)
+                .unwrap()
+                .evaluate(&machine)
+        };
+
+        assert_eq!(evaluate("[wBuffer + 1]"), 0x42);
+        assert_eq!(evaluate("[wBuffer.end]"), 0x42);
+        assert_eq!(evaluate("wBuffer.end - wBuffer"), 1);
+        // Labels are case sensitive, and come before registers
+        assert_eq!(evaluate("a"), 0x100);
+        assert_eq!(evaluate("A"), 0x07);
+    }
+}
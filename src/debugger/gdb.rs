@@ -0,0 +1,574 @@
+use std::{
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::hardware::{
+    error::EmulationError,
+    machine::Machine,
+    watchpoint::{Access, Watchpoint},
+    Memory,
+};
+
+// GDB has no idea what an SM83 is, so the registers are described to it.
+// The 8-bit registers come first, then SP and PC, all little endian.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gb-hinder.sm83">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="f" bitsize="8"/>
+    <reg name="b" bitsize="8"/>
+    <reg name="c" bitsize="8"/>
+    <reg name="d" bitsize="8"/>
+    <reg name="e" bitsize="8"/>
+    <reg name="h" bitsize="8"/>
+    <reg name="l" bitsize="8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTERS: usize = 10;
+
+// Instructions run between checks for an interrupt from the client
+const INTERRUPT_CHECK_INTERVAL: u64 = 0x4000;
+
+/// Listens for GDB on a local port, or any free one if it's 0
+pub fn listen(port: u16) -> Result<TcpListener> {
+    TcpListener::bind(("127.0.0.1", port)).with_context(|| format!("Can't listen on port {}", port))
+}
+
+/// Waits for a GDB client, then debugs the machine for it until it detaches
+/// or disconnects
+pub fn serve(listener: TcpListener, machine: &mut Machine) -> Result<()> {
+    let (stream, _) = listener.accept()?;
+
+    Stub::new(stream, machine)?.run()
+}
+
+// Speaks the GDB remote serial protocol over a single connection
+struct Stub<'a> {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    machine: &'a mut Machine,
+    breakpoints: Vec<u16>,
+
+    // Set once the client asks to stop acknowledging packets
+    no_ack: bool,
+}
+
+impl<'a> Stub<'a> {
+    fn new(stream: TcpStream, machine: &'a mut Machine) -> Result<Stub<'a>> {
+        Ok(Stub {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            machine,
+            breakpoints: Vec::new(),
+            no_ack: false,
+        })
+    }
+
+    fn run(&mut self) -> Result<()> {
+        while let Some(packet) = self.receive()? {
+            let reply = match self.handle(&packet) {
+                Ok(Some(reply)) => reply,
+                Ok(None) => {
+                    // Detaching is acknowledged, killing isn't
+                    if packet == "D" {
+                        self.send("OK")?;
+                    }
+
+                    return Ok(());
+                }
+                // Errors have a number, which GDB only shows
+                Err(_) => "E01".to_owned(),
+            };
+
+            self.send(&reply)?;
+
+            // The OK is still acknowledged, but nothing after it
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns the reply to a packet, or None once the client is done
+    fn handle(&mut self, packet: &str) -> Result<Option<String>> {
+        // Binary data isn't supported, so anything else is garbage
+        if !packet.is_ascii() {
+            bail!("Packet isn't ASCII");
+        }
+
+        let (command, args) = packet.split_at(packet.len().min(1));
+
+        let reply = match command {
+            "?" => "S05".to_owned(),
+            "g" => (0..REGISTERS).map(|n| self.register(n)).collect(),
+            "G" => {
+                let mut args = args;
+                for n in 0..REGISTERS {
+                    let width = register_width(n) * 2;
+                    let value = args.get(..width).ok_or(anyhow!("Too few registers"))?;
+                    self.set_register(n, value)?;
+                    args = &args[width..];
+                }
+
+                "OK".to_owned()
+            }
+            "p" => self.register(usize::from_str_radix(args, 16)?),
+            "P" => {
+                let (n, value) = args.split_once('=').ok_or(anyhow!("Missing value"))?;
+                self.set_register(usize::from_str_radix(n, 16)?, value)?;
+
+                "OK".to_owned()
+            }
+            "m" => {
+                let (address, length) = address_length(args)?;
+                let bus = &self.machine.cpu.bus;
+
+                (0..length)
+                    .map(|i| format!("{:02x}", bus.peek(address.wrapping_add(i))))
+                    .collect()
+            }
+            "M" => {
+                let (location, data) = args.split_once(':').ok_or(anyhow!("Missing data"))?;
+                let (address, length) = address_length(location)?;
+                let bytes = hex_bytes(data)?;
+                if bytes.len() != length as usize {
+                    bail!("Wrong amount of data");
+                }
+
+                for (i, byte) in bytes.into_iter().enumerate() {
+                    self.machine
+                        .cpu
+                        .bus
+                        .write(address.wrapping_add(i as u16), byte);
+                }
+
+                "OK".to_owned()
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", args)?,
+            "c" => self.resume(false)?,
+            "s" => self.resume(true)?,
+            "D" | "k" => return Ok(None),
+            "H" | "T" => "OK".to_owned(),
+            _ => self.query(packet)?,
+        };
+
+        Ok(Some(reply))
+    }
+
+    // General queries. Anything unsupported gets an empty reply.
+    fn query(&mut self, packet: &str) -> Result<String> {
+        if packet.starts_with("qSupported") {
+            return Ok("PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".to_owned());
+        }
+
+        match packet {
+            "QStartNoAckMode" => return Ok("OK".to_owned()),
+            "qAttached" => return Ok("1".to_owned()),
+            _ => {}
+        }
+
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, length) = range.split_once(',').ok_or(anyhow!("Missing length"))?;
+            let offset = usize::from_str_radix(offset, 16)?.min(TARGET_XML.len());
+            let end = (offset + usize::from_str_radix(length, 16)?).min(TARGET_XML.len());
+
+            let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+            return Ok(format!("{}{}", more, &TARGET_XML[offset..end]));
+        }
+
+        Ok(String::new())
+    }
+
+    // Sets or clears a breakpoint or watchpoint. Breakpoints are kept here
+    // rather than patched into memory, since most of it is ROM anyway.
+    fn breakpoint(&mut self, set: bool, args: &str) -> Result<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next().ok_or(anyhow!("Missing type"))?;
+        let address = u16::from_str_radix(fields.next().ok_or(anyhow!("Missing address"))?, 16)?;
+        let length = u16::from_str_radix(fields.next().ok_or(anyhow!("Missing kind"))?, 16)?;
+
+        let accesses: &[Access] = match kind {
+            "0" | "1" => {
+                match set {
+                    true => self.breakpoints.push(address),
+                    false => self.breakpoints.retain(|&b| b != address),
+                }
+
+                return Ok("OK".to_owned());
+            }
+            "2" => &[Access::Write],
+            "3" => &[Access::Read],
+            "4" => &[Access::Read, Access::Write],
+            _ => return Ok(String::new()),
+        };
+
+        let end = address.saturating_add(length.max(1) - 1);
+        let bus = &mut self.machine.cpu.bus;
+
+        for &access in accesses {
+            let watchpoint = Watchpoint::range(access, address, end)?;
+
+            if set {
+                bus.add_watchpoint(watchpoint);
+            } else if let Some(index) = bus.watchpoints().iter().position(|w| *w == watchpoint) {
+                bus.remove_watchpoint(index);
+            }
+        }
+
+        Ok("OK".to_owned())
+    }
+
+    // Runs until a breakpoint, watchpoint or error, or just for one
+    // instruction, and returns the stop reply
+    fn resume(&mut self, step: bool) -> Result<String> {
+        let mut steps = 0u64;
+
+        loop {
+            match self.machine.step() {
+                Ok(_) => {}
+                Err(EmulationError::IllegalOpcode { .. }) => return Ok("S04".to_owned()),
+                Err(EmulationError::UnmappedAccess { .. }) => return Ok("S0b".to_owned()),
+            }
+
+            let cpu = &self.machine.cpu;
+            if let Some(hit) = cpu.bus.watch_hits().first() {
+                let kind = match hit.access {
+                    Access::Read => "rwatch",
+                    Access::Write => "watch",
+                    Access::Execute => return Ok("S05".to_owned()),
+                };
+
+                return Ok(format!("T05{}:{:04x};", kind, hit.address));
+            }
+
+            if step || self.breakpoints.contains(&cpu.pc) {
+                return Ok("S05".to_owned());
+            }
+
+            steps += 1;
+            if steps.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && self.interrupted()? {
+                return Ok("S02".to_owned());
+            }
+        }
+    }
+
+    fn register(&self, n: usize) -> String {
+        let cpu = &self.machine.cpu;
+        let registers = &cpu.registers;
+
+        match n {
+            0 => format!("{:02x}", registers.a),
+            1 => format!("{:02x}", u8::from(registers.f)),
+            2 => format!("{:02x}", registers.b),
+            3 => format!("{:02x}", registers.c),
+            4 => format!("{:02x}", registers.d),
+            5 => format!("{:02x}", registers.e),
+            6 => format!("{:02x}", registers.h),
+            7 => format!("{:02x}", registers.l),
+            8 => hex_word(cpu.sp),
+            9 => hex_word(cpu.pc),
+            _ => String::new(),
+        }
+    }
+
+    fn set_register(&mut self, n: usize, hex: &str) -> Result<()> {
+        let bytes = hex_bytes(hex)?;
+        let value = match bytes[..] {
+            [byte] if register_width(n) == 1 => byte as u16,
+            [low, high] if register_width(n) == 2 => u16::from_le_bytes([low, high]),
+            _ => bail!("Wrong size for register {}", n),
+        };
+
+        let cpu = &mut self.machine.cpu;
+        let registers = &mut cpu.registers;
+
+        match n {
+            0 => registers.a = value as u8,
+            1 => registers.set_af(((registers.a as u16) << 8) | value),
+            2 => registers.b = value as u8,
+            3 => registers.c = value as u8,
+            4 => registers.d = value as u8,
+            5 => registers.e = value as u8,
+            6 => registers.h = value as u8,
+            7 => registers.l = value as u8,
+            8 => cpu.sp = value,
+            9 => cpu.pc = value,
+            _ => bail!("No register {}", n),
+        }
+
+        Ok(())
+    }
+
+    // Reads a packet's data, acknowledging it. Returns None once the client
+    // has gone.
+    fn receive(&mut self) -> Result<Option<String>> {
+        loop {
+            let mut skipped = Vec::new();
+            if self.reader.read_until(b'$', &mut skipped)? == 0 || skipped.last() != Some(&b'$') {
+                return Ok(None);
+            }
+
+            let mut data = Vec::new();
+            self.reader.read_until(b'#', &mut data)?;
+            if data.pop() != Some(b'#') {
+                return Ok(None);
+            }
+
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum)?;
+
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                == Some(checksum_of(&data));
+
+            if !self.no_ack {
+                self.writer.write_all(if valid { b"+" } else { b"-" })?;
+            }
+
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    // Sends a packet, resending it for as long as the client asks
+    fn send(&mut self, data: &str) -> Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+
+        loop {
+            self.writer.write_all(packet.as_bytes())?;
+
+            if self.no_ack {
+                return Ok(());
+            }
+
+            let mut ack = [0];
+            self.reader.read_exact(&mut ack)?;
+            if ack[0] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+
+    // Looks for a Ctrl-C from the client without waiting for one. A client
+    // that's gone counts as one too, so the session ends.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.reader.get_ref().set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.reader.read(&mut byte);
+        self.reader.get_ref().set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Ok(true),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+fn register_width(n: usize) -> usize {
+    if n < 8 {
+        1
+    } else {
+        2
+    }
+}
+
+fn hex_word(value: u16) -> String {
+    value
+        .to_le_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn hex_bytes(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        bail!("Odd number of hex digits");
+    }
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let digits = std::str::from_utf8(pair)?;
+            Ok(u8::from_str_radix(digits, 16)?)
+        })
+        .collect()
+}
+
+fn address_length(args: &str) -> Result<(u16, u16)> {
+    let (address, length) = args.split_once(',').ok_or(anyhow!("Missing length"))?;
+
+    Ok((
+        u16::from_str_radix(address, 16)?,
+        u16::from_str_radix(length, 16)?,
+    ))
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::hardware::{bus::Bus, cartridge::Cartridge, model::Model};
+
+    // A client that sends packets one at a time and waits for each reply
+    struct Client {
+        stream: BufReader<TcpStream>,
+        no_ack: bool,
+    }
+
+    impl Client {
+        fn request(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+            self.stream.get_mut().write_all(packet.as_bytes()).unwrap();
+
+            if !self.no_ack {
+                let mut ack = [0];
+                self.stream.read_exact(&mut ack).unwrap();
+                assert_eq!(ack[0], b'+');
+            }
+
+            let mut reply = Vec::new();
+            self.stream.read_until(b'$', &mut reply).unwrap();
+            reply.clear();
+            self.stream.read_until(b'#', &mut reply).unwrap();
+            reply.pop();
+
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            assert_eq!(
+                u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap(),
+                checksum_of(&reply)
+            );
+
+            if !self.no_ack {
+                self.stream.get_mut().write_all(b"+").unwrap();
+            }
+
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    // Calls a function at 0x0200 in a loop, which writes to 0xC000
+    fn machine() -> Machine {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0106].copy_from_slice(&[0xCD, 0x00, 0x02, 0x00, 0x18, 0xFA]);
+        rom[0x0200..0x0206].copy_from_slice(&[0x3C, 0xEA, 0x00, 0xC0, 0x00, 0xC9]);
+
+        Machine::new(Bus::new(Cartridge::new(rom), Model::DMG))
+    }
+
+    #[test]
+    fn test_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut client = Client {
+                stream: BufReader::new(TcpStream::connect(address).unwrap()),
+                no_ack: false,
+            };
+
+            assert!(client
+                .request("qSupported:xmlRegisters=i386")
+                .contains("qXfer"));
+            let xml = client.request("qXfer:features:read:target.xml:0,ffff");
+            assert!(xml.starts_with("l<?xml") && xml.contains(r#"name="pc""#));
+
+            assert_eq!(client.request("QStartNoAckMode"), "OK");
+            client.no_ack = true;
+
+            assert_eq!(client.request("?"), "S05");
+            assert_eq!(client.request("g"), "0180001300d8014dfeff0001");
+            assert_eq!(client.request("m0200,4"), "3cea00c0");
+
+            // Set A, and a byte of memory
+            assert_eq!(client.request("P0=41"), "OK");
+            assert_eq!(client.request("MC001,2:beef"), "OK");
+            assert_eq!(client.request("mc000,3"), "00beef");
+
+            assert_eq!(client.request("Z0,204,1"), "OK");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("p9"), "0402");
+            assert_eq!(client.request("mc000,1"), "42");
+
+            assert_eq!(client.request("z0,204,1"), "OK");
+            assert_eq!(client.request("s"), "S05");
+            assert_eq!(client.request("p9"), "0502");
+
+            assert_eq!(client.request("Z2,c000,1"), "OK");
+            assert_eq!(client.request("c"), "T05watch:c000;");
+            assert_eq!(client.request("p0"), "43");
+            assert_eq!(client.request("z2,c000,1"), "OK");
+
+            assert_eq!(client.request("m0200"), "E01");
+            assert_eq!(client.request("vMustReplyEmpty"), "");
+            assert_eq!(client.request("D"), "OK");
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut machine = machine();
+        Stub::new(stream, &mut machine).unwrap().run().unwrap();
+
+        client.join().unwrap();
+        assert!(machine.cpu.bus.watchpoints().is_empty());
+    }
+
+    #[test]
+    fn test_malformed_packets() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut client = Client {
+                stream: BufReader::new(TcpStream::connect(address).unwrap()),
+                no_ack: false,
+            };
+
+            for packet in [
+                "\u{e9}",
+                "MC000,1:\u{e9}",
+                "MC000,2:\u{e9}",
+                "MC000,1:abc",
+                "MC000,1:zz",
+                "G00\u{e9}",
+                "G0180",
+                "P0=\u{e9}",
+                "P0=4",
+                "P8=41",
+                "Pzz=41",
+                "\u{e9}C000,1:00",
+            ] {
+                assert_eq!(client.request(packet), "E01", "{:?}", packet);
+            }
+
+            // Binary writes aren't supported, so GDB falls back to M
+            assert_eq!(client.request("XC000,1:A"), "");
+            assert_eq!(client.request("p9"), "0001");
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut machine = machine();
+        Stub::new(stream, &mut machine).unwrap().run().unwrap();
+
+        client.join().unwrap();
+        assert_eq!(machine.cpu.bus.peek(0xC000), 0);
+    }
+}
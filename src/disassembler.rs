@@ -0,0 +1,837 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::{Display, Write},
+};
+
+use crate::hardware::{
+    cartridge::Cartridge,
+    cdl::{CodeDataLog, Usage},
+    opcode::{Condition, Opcode, Target, Target16},
+};
+use crate::symbols::Symbols;
+
+const BANK_SIZE: usize = 0x4000;
+
+// Where the boot ROM hands over to the cartridge
+const ENTRY_POINT: u16 = 0x0100;
+
+// The restart and interrupt vectors, which are reached without a jump
+const VECTORS: [u16; 13] = [
+    0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0x40, 0x48, 0x50, 0x58, 0x60,
+];
+
+// Bytes of data written out on each line
+const BYTES_PER_LINE: usize = 8;
+
+/// A place in ROM: the bank, and the address it's read from while mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Location {
+    pub bank: usize,
+    pub address: u16,
+}
+
+impl Location {
+    pub fn new(bank: usize, address: u16) -> Location {
+        Location { bank, address }
+    }
+
+    /// Returns where an address points when the code at this location runs.
+    /// Switchable ROM seen from bank 0 could be any bank, so isn't known.
+    pub fn resolve(&self, address: u16) -> Option<Location> {
+        match address {
+            0x0000..=0x3FFF => Some(Location::new(0, address)),
+            0x4000..=0x7FFF if self.bank > 0 => Some(Location::new(self.bank, address)),
+            _ => None,
+        }
+    }
+
+    fn from_offset(offset: usize) -> Location {
+        let bank = offset / BANK_SIZE;
+        let base = if bank == 0 { 0x0000 } else { 0x4000 };
+
+        Location::new(bank, (base + offset % BANK_SIZE) as u16)
+    }
+
+    fn offset(&self) -> usize {
+        self.bank * BANK_SIZE + self.address as usize % BANK_SIZE
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02X}:{:04X}", self.bank, self.address)
+    }
+}
+
+/// An instruction decoded from ROM
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub location: Location,
+    pub opcode: &'static Opcode,
+
+    // The opcode and its operands
+    pub bytes: Vec<u8>,
+}
+
+impl Instruction {
+    /// Decodes the instruction at a location. Illegal opcodes, and anything
+    /// RGBDS wouldn't assemble back into the same bytes, aren't instructions.
+    pub fn decode(rom: &[u8], location: Location) -> Option<Instruction> {
+        let offset = location.offset();
+        let opcode = Opcode::from_byte(*rom.get(offset)?);
+        let length = opcode.byte_count() as usize;
+
+        // Instructions don't carry on into the next bank
+        if offset % BANK_SIZE + length > BANK_SIZE {
+            return None;
+        }
+
+        let bytes = rom.get(offset..offset + length)?.to_vec();
+
+        match opcode {
+            Opcode::INV => None,
+            Opcode::STOP if bytes[1] != 0x00 => None,
+            _ => Some(Instruction {
+                location,
+                opcode,
+                bytes,
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns the address a jump, call or restart goes to
+    pub fn target(&self) -> Option<u16> {
+        self.opcode.target(&self.bytes[1..], self.location.address)
+    }
+
+    // Names labels after how they're reached, preferring calls since they
+    // usually start functions
+    fn label_kind(&self) -> Option<(u8, &'static str)> {
+        match self.opcode {
+            Opcode::CALL(_) => Some((0, "call")),
+            Opcode::JP(..) => Some((1, "jp")),
+            Opcode::JR(_) => Some((2, "jr")),
+            _ => None,
+        }
+    }
+}
+
+/// Something in a bank, in the order it's found
+#[derive(Debug, Clone)]
+pub enum Item {
+    Instruction(Instruction),
+    Data(u8),
+}
+
+/// Which bytes of ROM are code, found by following the code from the entry
+/// point and the restart and interrupt vectors
+#[derive(Debug, Clone)]
+pub struct CodeMap {
+    // Whether each byte of ROM is part of an instruction that was reached
+    code: Vec<bool>,
+    starts: BTreeSet<Location>,
+
+    // Where each jump, call and restart that could be followed goes
+    targets: BTreeMap<Location, Location>,
+}
+
+impl CodeMap {
+    /// Follows every jump, call and restart that can be worked out without
+    /// running anything. Jumps into switchable ROM from bank 0 are followed
+    /// when the bank was picked by `ld a, n8` then `ld [$2000-$3fff], a` on
+    /// the way there, or it's the bank mapped at boot.
+    ///
+    /// A code/data log from running the game adds every opcode it saw as
+    /// somewhere to start, which catches computed jumps, and stops the trace
+    /// at bytes that were only ever read as data.
+    pub fn trace(rom: &[u8], log: Option<&CodeDataLog>) -> CodeMap {
+        let banks = rom.len() / BANK_SIZE;
+        let mut map = CodeMap {
+            code: vec![false; rom.len()],
+            starts: BTreeSet::new(),
+            targets: BTreeMap::new(),
+        };
+
+        // Each path is where it starts and the bank mapped in while it runs
+        let mut paths = vec![(Location::new(0, ENTRY_POINT), Some(1))];
+        paths.extend(
+            VECTORS
+                .iter()
+                .map(|&vector| (Location::new(0, vector), None)),
+        );
+
+        if let Some(log) = log {
+            for offset in (0..rom.len()).filter(|&offset| log.has(offset, Usage::Opcode)) {
+                let location = Location::from_offset(offset);
+                let mapped = Some(location.bank).filter(|&bank| bank > 0);
+                paths.push((location, mapped));
+            }
+        }
+
+        let mut visited = HashSet::new();
+
+        while let Some((mut location, mut mapped)) = paths.pop() {
+            let mut a = None;
+
+            while visited.insert((location, mapped)) {
+                let Some(instruction) = Instruction::decode(rom, location) else {
+                    break;
+                };
+
+                let offset = location.offset();
+                let bytes = offset..offset + instruction.len();
+                if log.is_some_and(|log| bytes.clone().any(|offset| log.is_data(offset))) {
+                    break;
+                }
+
+                map.code[bytes].fill(true);
+                map.starts.insert(location);
+
+                let operands = &instruction.bytes[1..];
+                match instruction.opcode {
+                    Opcode::LD(Target::A, Target::Immediate) => a = Some(operands[0]),
+                    // Code in switchable ROM would switch itself out, so only
+                    // bank 0 is followed into the new bank
+                    Opcode::LD(Target::MImmediate, Target::A) if location.bank == 0 => {
+                        if let 0x2000..=0x3FFF = u16::from_le_bytes([operands[0], operands[1]]) {
+                            mapped = a.map(|value| (value as usize % banks).max(1));
+                        }
+                    }
+                    opcode if writes_a(opcode) => a = None,
+                    _ => {}
+                }
+
+                let target = instruction
+                    .target()
+                    .and_then(|address| match address {
+                        0x4000..=0x7FFF if location.bank == 0 => {
+                            mapped.map(|bank| Location::new(bank, address))
+                        }
+                        _ => location.resolve(address),
+                    })
+                    .filter(|target| target.bank < banks);
+
+                if let Some(target) = target {
+                    map.targets.insert(location, target);
+
+                    let mapped = if target.bank == 0 {
+                        mapped
+                    } else {
+                        Some(target.bank)
+                    };
+                    paths.push((target, mapped));
+                }
+
+                // Calls and restarts are assumed to come back
+                if matches!(
+                    instruction.opcode,
+                    Opcode::JP(Condition::None, _)
+                        | Opcode::JR(Condition::None)
+                        | Opcode::RET(Condition::None)
+                        | Opcode::RETI
+                ) {
+                    break;
+                }
+
+                let next = location.address as usize + instruction.len();
+                let end = if location.bank == 0 { 0x4000 } else { 0x8000 };
+                if next >= end {
+                    break;
+                }
+
+                location = Location::new(location.bank, next as u16);
+            }
+        }
+
+        map
+    }
+
+    pub fn is_code(&self, location: Location) -> bool {
+        self.code[location.offset()]
+    }
+
+    /// Splits each bank into runs of code and data
+    pub fn runs(&self) -> Vec<Run> {
+        let mut runs: Vec<Run> = Vec::new();
+
+        for offset in 0..self.code.len() {
+            let location = Location::from_offset(offset);
+            let code = self.is_code(location);
+
+            match runs.last_mut() {
+                Some(run) if run.code == code && run.end.bank == location.bank => {
+                    run.end = location
+                }
+                _ => runs.push(Run {
+                    start: location,
+                    end: location,
+                    code,
+                }),
+            }
+        }
+
+        runs
+    }
+}
+
+impl Display for CodeMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for run in self.runs() {
+            writeln!(f, "{}", run)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Bytes in a bank that are all code, or all data, from `start` to `end`
+/// inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Run {
+    pub start: Location,
+    pub end: Location,
+    pub code: bool,
+}
+
+impl Display for Run {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = if self.code { "code" } else { "data" };
+        write!(f, "{}-{} {}", self.start, self.end, kind)
+    }
+}
+
+// Whether an instruction leaves A with something that can't be known ahead of
+// time. Calls and restarts could do anything to it.
+fn writes_a(opcode: &Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::LD(Target::A, _)
+            | Opcode::LDH(Target::A, _)
+            | Opcode::LDI(Target::A, _)
+            | Opcode::LDD(Target::A, _)
+            | Opcode::ADD(_)
+            | Opcode::ADC(_)
+            | Opcode::SUB(_)
+            | Opcode::SBC(_)
+            | Opcode::AND(_)
+            | Opcode::OR(_)
+            | Opcode::XOR(_)
+            | Opcode::INC(Target::A)
+            | Opcode::DEC(Target::A)
+            | Opcode::POP(Target16::AF)
+            | Opcode::RL(Target::A)
+            | Opcode::CPL
+            | Opcode::DAA
+            | Opcode::RLCA
+            | Opcode::RRCA
+            | Opcode::RRA
+            | Opcode::PrefixCB
+            | Opcode::CALL(_)
+            | Opcode::RST(_)
+    )
+}
+
+/// Disassembles a whole ROM, bank by bank, into RGBDS assembly
+pub struct Disassembler<'a> {
+    rom: &'a [u8],
+
+    // Only set when the code has been traced, rather than swept
+    code_map: Option<CodeMap>,
+
+    symbols: Option<&'a Symbols>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(cartridge: &'a Cartridge) -> Disassembler<'a> {
+        Disassembler {
+            rom: &cartridge.rom,
+            code_map: None,
+            symbols: None,
+        }
+    }
+
+    /// Traces the code first, so only bytes it reaches are disassembled
+    pub fn traced(cartridge: &'a Cartridge, log: Option<&CodeDataLog>) -> Disassembler<'a> {
+        Disassembler {
+            rom: &cartridge.rom,
+            code_map: Some(CodeMap::trace(&cartridge.rom, log)),
+            symbols: None,
+        }
+    }
+
+    /// Names labels from a symbol file where it has them
+    pub fn with_symbols(self, symbols: &'a Symbols) -> Disassembler<'a> {
+        Disassembler {
+            symbols: Some(symbols),
+            ..self
+        }
+    }
+
+    pub fn banks(&self) -> usize {
+        self.rom.len() / BANK_SIZE
+    }
+
+    pub fn code_map(&self) -> Option<&CodeMap> {
+        self.code_map.as_ref()
+    }
+
+    /// Decodes a bank from start to end, treating anything that isn't an
+    /// instruction as data
+    pub fn sweep(&self, bank: usize) -> Vec<Item> {
+        let start = if bank == 0 { 0x0000 } else { 0x4000 };
+        let end = start + BANK_SIZE as u32;
+
+        let mut items = Vec::new();
+        let mut address = start;
+
+        while address < end {
+            let location = Location::new(bank, address as u16);
+
+            let instruction = match &self.code_map {
+                Some(map) if !map.starts.contains(&location) => None,
+                _ => Instruction::decode(self.rom, location),
+            };
+
+            match instruction {
+                Some(instruction) => {
+                    address += instruction.len() as u32;
+                    items.push(Item::Instruction(instruction));
+                }
+                None => {
+                    // A STOP that isn't followed by 0x00 still takes the byte
+                    // after it
+                    let offset = location.offset();
+                    let length = match self.rom[offset] {
+                        0x10 if self.code_map.is_none() && address + 1 < end => 2,
+                        _ => 1,
+                    };
+
+                    for byte in &self.rom[offset..offset + length] {
+                        items.push(Item::Data(*byte));
+                    }
+                    address += length as u32;
+                }
+            }
+        }
+
+        items
+    }
+
+    /// Returns where an instruction jumps, calls or restarts to, if it's known
+    pub fn target(&self, instruction: &Instruction) -> Option<Location> {
+        match &self.code_map {
+            Some(map) => map.targets.get(&instruction.location).copied(),
+            None => instruction
+                .target()
+                .and_then(|address| instruction.location.resolve(address)),
+        }
+    }
+
+    /// Writes out every bank as RGBDS assembly
+    pub fn rgbds(&self) -> String {
+        let banks: Vec<Vec<Item>> = (0..self.banks()).map(|bank| self.sweep(bank)).collect();
+        let labels = self.labels(banks.iter().flatten());
+
+        let mut out = String::new();
+
+        for (bank, items) in banks.iter().enumerate() {
+            if bank > 0 {
+                out.push('\n');
+            }
+
+            match bank {
+                0 => writeln!(out, "SECTION \"ROM Bank $000\", ROM0[$0000]"),
+                _ => writeln!(
+                    out,
+                    "SECTION \"ROM Bank ${:03x}\", ROMX[$4000], BANK[${:x}]",
+                    bank, bank
+                ),
+            }
+            .unwrap();
+
+            self.write_items(&mut out, items, &labels);
+        }
+
+        out
+    }
+
+    // Labels every jump and call target that's the start of an instruction,
+    // and every instruction the symbols have a label for. Targets part way
+    // through an instruction are left as addresses.
+    fn labels<'b>(
+        &self,
+        items: impl Iterator<Item = &'b Item> + Clone,
+    ) -> BTreeMap<Location, String> {
+        let starts: BTreeSet<Location> = items
+            .clone()
+            .filter_map(|item| match item {
+                Item::Instruction(instruction) => Some(instruction.location),
+                Item::Data(_) => None,
+            })
+            .collect();
+
+        let mut kinds: BTreeMap<Location, (u8, &str)> = BTreeMap::new();
+
+        for item in items {
+            let Item::Instruction(instruction) = item else {
+                continue;
+            };
+
+            let (Some(kind), Some(target)) = (instruction.label_kind(), self.target(instruction))
+            else {
+                continue;
+            };
+
+            if starts.contains(&target) {
+                let entry = kinds.entry(target).or_insert(kind);
+                *entry = (*entry).min(kind);
+            }
+        }
+
+        let mut labels: BTreeMap<Location, String> = kinds
+            .into_iter()
+            .map(|(location, (_, kind))| {
+                let name = format!("{}_{:03x}_{:04x}", kind, location.bank, location.address);
+                (location, name)
+            })
+            .collect();
+
+        if let Some(symbols) = self.symbols {
+            for location in starts {
+                if let Some(label) = symbols.label(location.bank, location.address) {
+                    labels.insert(location, label.to_owned());
+                }
+            }
+        }
+
+        labels
+    }
+
+    fn write_items(&self, out: &mut String, items: &[Item], labels: &BTreeMap<Location, String>) {
+        let mut data: Vec<u8> = Vec::new();
+
+        for item in items {
+            match item {
+                Item::Data(byte) => {
+                    data.push(*byte);
+
+                    if data.len() == BYTES_PER_LINE {
+                        write_data(out, &mut data);
+                    }
+                }
+                Item::Instruction(instruction) => {
+                    write_data(out, &mut data);
+
+                    let location = instruction.location;
+                    if let Some(label) = labels.get(&location) {
+                        writeln!(out, "\n{}:", label).unwrap();
+                    }
+
+                    let target =
+                        |address| match self.target(instruction).and_then(|l| labels.get(&l)) {
+                            Some(label) => label.clone(),
+                            None => format!("${:04x}", address),
+                        };
+
+                    writeln!(
+                        out,
+                        "    {}",
+                        instruction.opcode.rgbds_fmt(
+                            &instruction.bytes[1..],
+                            location.address,
+                            target
+                        )
+                    )
+                    .unwrap();
+                }
+            }
+        }
+
+        write_data(out, &mut data);
+    }
+}
+
+fn write_data(out: &mut String, data: &mut Vec<u8>) {
+    if data.is_empty() {
+        return;
+    }
+
+    let bytes: Vec<String> = data.iter().map(|byte| format!("${:02x}", byte)).collect();
+    writeln!(out, "    db {}", bytes.join(", ")).unwrap();
+
+    data.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cartridge(code: &[(u16, &[u8])]) -> Cartridge {
+        let mut rom = vec![0; 0x10000];
+        rom[0x0148] = 0x01;
+
+        for (address, bytes) in code {
+            let start = *address as usize;
+            rom[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+
+        Cartridge::new(rom)
+    }
+
+    // Disassembles a bank, leaving out the NOPs that fill it
+    fn listing(disassembler: &Disassembler, bank: usize) -> Vec<String> {
+        let banks: Vec<Item> = (0..disassembler.banks())
+            .flat_map(|b| disassembler.sweep(b))
+            .collect();
+
+        let mut out = String::new();
+        disassembler.write_items(
+            &mut out,
+            &disassembler.sweep(bank),
+            &disassembler.labels(banks.iter()),
+        );
+
+        out.lines()
+            .filter(|line| !line.is_empty() && *line != "    nop")
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn test_instructions() {
+        let cartridge = cartridge(&[(
+            0x0150,
+            &[
+                0x3E, 0x42, // ld a, $42
+                0xEA, 0x00, 0xC0, // ld [$c000], a
+                0xE0, 0x40, // ldh [$ff40], a
+                0xF2, // ldh a, [c]
+                0x22, // ld [hli], a
+                0x08, 0x00, 0xD0, // ld [$d000], sp
+                0xF8, 0xFE, // ld hl, sp - 2
+                0xE8, 0x02, // add sp, 2
+                0xCE, 0x01, // adc a, $01
+                0x96, // sub [hl]
+                0xCB, 0x7C, // bit 7, h
+                0xCB, 0x86, // res 0, [hl]
+                0xC8, // ret z
+                0x17, // rla
+                0xE9, // jp hl
+                0xD3, 0xDB, // illegal
+                0x10, 0x00, // stop
+                0x10, 0x01, // not a stop RGBDS can assemble
+            ],
+        )]);
+
+        let bank_0 = listing(&Disassembler::new(&cartridge), 0);
+        let start = bank_0.iter().position(|l| l == "    ld a, $42").unwrap();
+        assert_eq!(
+            bank_0[start..],
+            [
+                "    ld a, $42",
+                "    ld [$c000], a",
+                "    ldh [$ff40], a",
+                "    ldh a, [c]",
+                "    ld [hli], a",
+                "    ld [$d000], sp",
+                "    ld hl, sp - 2",
+                "    add sp, 2",
+                "    adc a, $01",
+                "    sub [hl]",
+                "    bit 7, h",
+                "    res 0, [hl]",
+                "    ret z",
+                "    rla",
+                "    jp hl",
+                "    db $d3, $db",
+                "    stop",
+                "    db $10, $01",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let cartridge = cartridge(&[
+            (0x0100, &[0xC3, 0x50, 0x01]), // jp $0150
+            (0x0150, &[0xCD, 0x00, 0x40, 0x18, 0xFB, 0xCD, 0x01, 0x01]),
+            (0x4000, &[0xC4, 0x10, 0x40, 0x20, 0x01, 0xC9]),
+            (0x4010, &[0xC9]),
+        ]);
+
+        let bank_0 = listing(&Disassembler::new(&cartridge), 0);
+        let start = bank_0.iter().position(|l| l == "jp_000_0150:").unwrap();
+        assert_eq!(
+            bank_0[start..start + 4],
+            [
+                "jp_000_0150:",
+                // Bank 0 can't tell which bank is mapped in
+                "    call $4000",
+                "    jr jp_000_0150",
+                // Part way through an instruction
+                "    call $0101",
+            ]
+        );
+
+        assert_eq!(
+            listing(&Disassembler::new(&cartridge), 1)[..5],
+            [
+                "    call nz, call_001_4010",
+                "    jr nz, jr_001_4006",
+                "    ret",
+                "jr_001_4006:",
+                "call_001_4010:",
+            ]
+        );
+
+        let rgbds = Disassembler::new(&cartridge).rgbds();
+        assert!(rgbds.starts_with("SECTION \"ROM Bank $000\", ROM0[$0000]\n"));
+        assert!(rgbds.contains("\nSECTION \"ROM Bank $003\", ROMX[$4000], BANK[$3]\n"));
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut cartridge = cartridge(&[
+            (0x0100, &[0x00, 0xC3, 0x50, 0x01]), // nop, jp $0150
+            (
+                0x0150,
+                &[
+                    0x3E, 0x02, // ld a, $02
+                    0xEA, 0x00, 0x20, // ld [$2000], a
+                    0xCD, 0x00, 0x40, // call $4000
+                    0x18, 0xF6, // jr $0150
+                    0x7E, 0x3C, 0x66, 0x99, // tiles
+                ],
+            ),
+            (0x4000, &[0x06, 0x05, 0xC9, 0x42]), // bank 1: ld b, $05, ret
+            (0x8000, &[0x06, 0x07, 0xC9, 0x42]), // bank 2: ld b, $07, ret
+        ]);
+
+        // The vectors return straight away
+        for vector in VECTORS {
+            cartridge.rom[vector as usize] = if vector < 0x40 { 0xC9 } else { 0xD9 };
+        }
+
+        let disassembler = Disassembler::traced(&cartridge, None);
+        let map = disassembler.code_map().unwrap();
+
+        assert!(map.is_code(Location::new(0, 0x0038)));
+        assert!(!map.is_code(Location::new(0, 0x0039)));
+        assert!(!map.is_code(Location::new(0, 0x0104)));
+        assert!(map.is_code(Location::new(0, 0x0159)));
+        assert!(!map.is_code(Location::new(0, 0x015A)));
+        assert!(!map.is_code(Location::new(1, 0x4000)));
+        assert!(map.is_code(Location::new(2, 0x4002)));
+        assert!(!map.is_code(Location::new(2, 0x4003)));
+
+        assert_eq!(
+            map.runs()[map.runs().len() - 4..],
+            [
+                Run {
+                    start: Location::new(1, 0x4000),
+                    end: Location::new(1, 0x7FFF),
+                    code: false,
+                },
+                Run {
+                    start: Location::new(2, 0x4000),
+                    end: Location::new(2, 0x4002),
+                    code: true,
+                },
+                Run {
+                    start: Location::new(2, 0x4003),
+                    end: Location::new(2, 0x7FFF),
+                    code: false,
+                },
+                Run {
+                    start: Location::new(3, 0x4000),
+                    end: Location::new(3, 0x7FFF),
+                    code: false,
+                },
+            ][..]
+        );
+        assert!(map
+            .to_string()
+            .contains("\n00:0150-00:0159 code\n00:015A-00:3FFF data\n"));
+
+        let bank_0 = listing(&disassembler, 0);
+        let start = bank_0.iter().position(|l| l == "jp_000_0150:").unwrap();
+        assert_eq!(
+            bank_0[start..start + 7],
+            [
+                "jp_000_0150:",
+                "    ld a, $02",
+                "    ld [$2000], a",
+                "    call call_002_4000",
+                "    jr jp_000_0150",
+                "    db $7e, $3c, $66, $99, $00, $00, $00, $00",
+                "    db $00, $00, $00, $00, $00, $00, $00, $00",
+            ]
+        );
+
+        assert_eq!(
+            listing(&disassembler, 1)[0],
+            "    db $06, $05, $c9, $42, $00, $00, $00, $00"
+        );
+        assert_eq!(
+            listing(&disassembler, 2)[..4],
+            [
+                "call_002_4000:",
+                "    ld b, $07",
+                "    ret",
+                "    db $42, $00, $00, $00, $00, $00, $00, $00",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trace_with_log() {
+        let cartridge = cartridge(&[
+            (0x0100, &[0x21, 0x50, 0x01, 0xE9]), // ld hl, $0150, jp hl
+            (0x0150, &[0x06, 0x01]),             // ld b, $01, then NOPs
+            (0x0160, &[0xC9, 0x3E]),             // a table that decodes as ret
+        ]);
+
+        let mut log = CodeDataLog::new(cartridge.rom.len());
+        log.mark(0x0150, Usage::Opcode);
+        log.mark(0x0160, Usage::Data);
+
+        let traced = CodeMap::trace(&cartridge.rom, None);
+        assert!(!traced.is_code(Location::new(0, 0x0150)));
+
+        // The code runs on into the table, but the log says it's data
+        let traced = CodeMap::trace(&cartridge.rom, Some(&log));
+        assert!(traced.is_code(Location::new(0, 0x0150)));
+        assert!(traced.is_code(Location::new(0, 0x015F)));
+        assert!(!traced.is_code(Location::new(0, 0x0160)));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let cartridge = cartridge(&[
+            (0x0100, &[0xC3, 0x50, 0x01]), // jp $0150
+            (0x0150, &[0xCD, 0x58, 0x01, 0x18, 0xFB]),
+            (0x0158, &[0xC9]),
+        ]);
+        let symbols = Symbols::parse("00:0150 Main\n00:0153 Main.loop\n00:c000 wBuffer").unwrap();
+
+        let disassembler = Disassembler::traced(&cartridge, None).with_symbols(&symbols);
+        let bank_0 = listing(&disassembler, 0);
+        let start = bank_0.iter().position(|l| l == "Main:").unwrap();
+        assert_eq!(
+            bank_0[start..start + 7],
+            [
+                "Main:",
+                "    call call_000_0158",
+                "Main.loop:",
+                "    jr Main",
+                "    db $00, $00, $00",
+                "call_000_0158:",
+                "    ret",
+            ]
+        );
+    }
+}
@@ -1,11 +1,27 @@
 pub mod boot_rom;
 pub mod bus;
 pub mod cartridge;
+pub mod cdl;
 pub mod cpu;
+pub mod error;
 pub mod io;
+pub mod machine;
 pub mod mbc;
+pub mod model;
+pub mod movie;
 pub mod opcode;
+pub mod ppu;
 pub mod registers;
+pub mod rewind;
+pub mod sgb;
+pub mod state;
+pub mod watchpoint;
+
+#[cfg(test)]
+mod test_roms;
+
+use anyhow::Result;
+use state::{Snapshot, StateReader, StateWriter};
 
 pub trait Memory {
     fn read(&self, address: u16) -> u8;
@@ -48,3 +64,13 @@ impl Memory for RAM {
         self.data[address as usize] = value;
     }
 }
+
+impl Snapshot for RAM {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.data);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<()> {
+        state.bytes_into(&mut self.data)
+    }
+}
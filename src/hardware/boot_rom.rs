@@ -1,7 +1,23 @@
-use super::Memory;
+use std::fs;
 
-// The boot ROM is a 256 byte ROM that is mapped to the first 256 bytes of
-// memory. It is disabled after the boot ROM has been executed.
+use anyhow::{bail, Result};
+
+use super::{model::Model, Memory};
+
+// Known dumps of each model's boot ROM, by MD5
+const KNOWN_BOOT_ROMS: [(&str, Model); 7] = [
+    ("a8f84a0ac44da5d3f0ee19f9cea80a8c", Model::DMG0),
+    ("32fbbd84168d3482956eb3c5051637f5", Model::DMG),
+    ("71a378e71ff30b2d8a1f02bf5c7896aa", Model::MGB),
+    ("d574d4f9c12f305074798f54c091a8b4", Model::SGB),
+    ("e0430bca9925fb9882148fd2dc2418c1", Model::SGB2),
+    ("7c773f3c0b01cb73bca8e83227287b7f", Model::CGB),
+    ("dbfce9db9deaa2567f6a84fde55f9680", Model::CGB),
+];
+
+// The boot ROM is mapped to the first 256 bytes of memory, and on the CGB
+// also to 0x0200-0x08FF, leaving the cartridge header visible in between. It
+// is disabled after the boot ROM has been executed.
 // https://gbdev.gg8.se/wiki/articles/Gameboy_Bootstrap_ROM
 pub struct BootROM {
     data: Vec<u8>,
@@ -13,6 +29,65 @@ impl BootROM {
 
         BootROM { data }
     }
+
+    /// Loads a boot ROM dump, checking that it can boot the given model
+    pub fn from_bytes(data: &[u8], model: Model) -> Result<BootROM> {
+        let expected_size = if model.is_cgb() { 0x900 } else { 0x100 };
+
+        if data.len() != expected_size {
+            bail!(
+                "{:?} boot ROMs are {} bytes, but this one is {} bytes",
+                model,
+                expected_size,
+                data.len()
+            );
+        }
+
+        let boot_rom = BootROM {
+            data: data.to_vec(),
+        };
+
+        // Unknown dumps are allowed, so custom boot ROMs can be used
+        match boot_rom.identify() {
+            // The GBA runs a modified CGB boot ROM
+            Some(Model::CGB) if model == Model::AGB => {}
+            Some(known) if known != model => {
+                bail!("This is a {:?} boot ROM, not a {:?} one", known, model)
+            }
+            _ => {}
+        }
+
+        Ok(boot_rom)
+    }
+
+    pub fn from_path(path: &str, model: Model) -> Result<BootROM> {
+        BootROM::from_bytes(&fs::read(path)?, model)
+    }
+
+    /// Returns the model this boot ROM was dumped from, if it's a known dump
+    pub fn identify(&self) -> Option<Model> {
+        let digest = format!("{:x}", md5::compute(&self.data));
+
+        KNOWN_BOOT_ROMS
+            .iter()
+            .find(|(hash, _)| *hash == digest)
+            .map(|(_, model)| *model)
+    }
+
+    /// Returns true if the boot ROM covers this address while it's mapped
+    pub fn maps(&self, address: u16) -> bool {
+        match address {
+            0x0000..=0x00FF => true,
+            0x0200..=0x08FF => self.data.len() > 0x100,
+            _ => false,
+        }
+    }
+}
+
+impl Default for BootROM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory for BootROM {
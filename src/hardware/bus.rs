@@ -1,13 +1,42 @@
+use std::cell::{Cell, Ref, RefCell};
+
+use anyhow::{bail, Result};
+
+use super::cdl::{CodeDataLog, Usage};
+use super::error::{AccessKind, EmulationError};
+use super::io::hdma::HDMA;
+use super::io::joypad::{Button, Joypad};
 use super::io::serial::Serial;
 use super::io::timer::Timer;
-use super::{boot_rom::BootROM, cartridge::Cartridge, io::IO, mbc::MBC1, Memory, RAM};
+use super::ppu::compatibility::{CompatibilityPalettes, ManualPalette};
+use super::sgb::SGB;
+use super::state::{Snapshot, StateReader, StateWriter};
+use super::watchpoint::{Access, WatchHit, Watchpoint};
+use super::{
+    boot_rom::BootROM,
+    cartridge::Cartridge,
+    io::IO,
+    mbc::MBC1,
+    model::Model,
+    ppu::{Mode, PPU},
+    Memory, RAM,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank,
+    LCD,
+    Timer,
+    Serial,
+    Joypad,
+}
 
 // The gameboy does not necessarily have a bus, but a bus is a close
 // representative of what it does have.
 pub struct Bus {
+    pub model: Model,
     mbc: MBC1,
     internal_ram: RAM,
-    vram: RAM,
     zero_page: RAM,
     boot_rom: Option<BootROM>,
     io: IO,
@@ -15,51 +44,452 @@ pub struct Bus {
     pub interrupt_enable: u8,
     pub interrupt_flags: u8,
     serial: Serial,
+    joypad: Joypad,
+    ppu: PPU,
+    hdma: HDMA,
+
+    // Only there on the Super Game Boy
+    sgb: Option<SGB>,
+
+    // Game Boy Color only registers are available
+    pub cgb_mode: bool,
+
+    // KEY0, written by the CGB boot ROM to pick CGB or DMG compatibility mode
+    // once it's unmapped
+    key0: u8,
+
+    // SVBK, which selects the bank mapped at 0xD000
+    wram_bank: u8,
+
+    // KEY1, used to switch the CGB CPU between normal and double speed
+    pub double_speed: bool,
+    speed_switch_armed: bool,
+
+    // The first access to an address nothing responds to since the CPU last
+    // checked. Reads happen through a shared reference, hence the Cell.
+    fault: Cell<Option<EmulationError>>,
+
+    // Set by the debugger. Every access is checked against these, so the
+    // checks are skipped entirely while there are none.
+    watchpoints: Vec<Watchpoint>,
+
+    // The PC and ROM bank of the instruction being run, and the watchpoints
+    // it has set off
+    instruction: (u16, usize),
+    watch_hits: RefCell<Vec<WatchHit>>,
+
+    // How each byte of ROM has been used, while it's being logged
+    code_data_log: Option<RefCell<CodeDataLog>>,
 }
 
 impl Bus {
-    pub fn new(cartridge: Cartridge) -> Bus {
+    /// Creates a bus in the state the boot ROM would leave it in
+    pub fn new(cartridge: Cartridge, model: Model) -> Bus {
+        let mut bus = Bus::power_on(cartridge, model, None);
+
+        bus.skip_boot_rom();
+        bus
+    }
+
+    /// Creates a bus with the boot ROM mapped, ready to run it from 0x0000
+    pub fn with_boot_rom(cartridge: Cartridge, model: Model, boot_rom: BootROM) -> Bus {
+        Bus::power_on(cartridge, model, Some(boot_rom))
+    }
+
+    fn power_on(cartridge: Cartridge, model: Model, boot_rom: Option<BootROM>) -> Bus {
+        let sgb = model.is_sgb().then(|| SGB::new(cartridge.supports_sgb()));
         let mbc = MBC1::new(cartridge);
 
+        // The CGB has 8 banks of internal RAM
+        let banks = if model.is_cgb() { 4 } else { 1 };
+
         Bus {
+            model,
             mbc,
-            internal_ram: RAM::new(0x2000),
-            boot_rom: None, //Some(BootROM::new()),
-            vram: RAM::new(0x2000),
+            internal_ram: RAM::new(0x2000 * banks),
+            boot_rom,
             zero_page: RAM::new(0x7F),
-            io: IO {},
-            timer: Timer {},
+            io: IO::new(),
+            timer: Timer::new(),
             interrupt_enable: 0,
             interrupt_flags: 0,
             serial: Serial::new(),
+            joypad: Joypad::new(),
+            ppu: PPU::new(model),
+            hdma: HDMA::new(),
+            sgb,
+            // The CGB boot ROM always runs in CGB mode
+            cgb_mode: model.is_cgb(),
+            key0: 0x00,
+            wram_bank: 1,
+            double_speed: false,
+            speed_switch_armed: false,
+            fault: Cell::new(None),
+            watchpoints: Vec::new(),
+            instruction: (0, 0),
+            watch_hits: RefCell::new(Vec::new()),
+            code_data_log: None,
         }
     }
-}
 
-impl Memory for Bus {
-    fn read(&self, address: u16) -> u8 {
+    /// Returns true while the boot ROM is mapped over the cartridge
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        self.mbc.cartridge()
+    }
+
+    /// Returns the ROM bank an address in cartridge ROM currently reads from
+    pub fn rom_bank(&self, address: u16) -> usize {
         match address {
-            0x0000..=0x100 => {
-                if let Some(boot_rom) = &self.boot_rom {
-                    boot_rom.read(address)
-                } else {
-                    self.mbc.read(address)
+            0x4000..=0x7FFF => self.mbc.rom_bank(),
+            _ => 0,
+        }
+    }
+
+    /// Colors a DMG game with one of the palettes the CGB boot ROM offers
+    /// for button combos. Does nothing unless a DMG game runs on a CGB.
+    pub fn set_compatibility_palette(&mut self, palette: ManualPalette) {
+        if self.model.is_cgb() && !self.cgb_mode {
+            self.ppu
+                .set_compatibility_palettes(&CompatibilityPalettes::manual(palette));
+        }
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut PPU {
+        &mut self.ppu
+    }
+
+    pub fn sgb(&self) -> Option<&SGB> {
+        self.sgb.as_ref()
+    }
+
+    // Puts the hardware in the state the boot ROM leaves it in
+    fn skip_boot_rom(&mut self) {
+        self.cgb_mode = self.model.is_cgb() && self.cartridge().supports_cgb();
+        self.io.skip_boot_rom(self.model);
+        self.ppu.skip_boot_rom(self.model, self.cgb_mode);
+
+        if self.model.is_cgb() && !self.cgb_mode {
+            let palettes = CompatibilityPalettes::for_cartridge(self.cartridge());
+            self.ppu.set_compatibility_palettes(&palettes);
+        }
+        self.timer.set_counter(self.model.initial_div_counter());
+        self.serial.control = if self.model.is_cgb() { 0x7F } else { 0x7E };
+        self.interrupt_flags = 0xE1;
+
+        // Both button groups are left selected
+        self.joypad.write(0xFF00, 0x00);
+    }
+
+    // Offset into internal RAM. 0xC000-0xCFFF is always bank 0, the second
+    // half is switchable on the CGB.
+    fn wram_offset(&self, address: u16) -> u16 {
+        let address = address & 0x1FFF;
+
+        match address {
+            0x0000..=0x0FFF => address,
+            _ => (self.wram_bank as u16 * 0x1000) + (address - 0x1000),
+        }
+    }
+
+    /// Advances the rest of the hardware by the given number of machine cycles.
+    /// In double speed mode the timer runs along with the CPU, but the PPU
+    /// keeps its pace.
+    pub fn tick(&mut self, cycles: u8) {
+        let dots = if self.double_speed { 2 } else { 4 };
+
+        for _ in 0..cycles {
+            if self.timer.tick() {
+                self.request_interrupt(Interrupt::Timer);
+            }
+
+            let interrupts = self.ppu.tick(dots, self.cgb_mode);
+            self.interrupt_flags |= interrupts;
+
+            if interrupts & (1 << Interrupt::VBlank as u8) != 0 {
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.frame_completed(self.ppu.framebuffer());
                 }
             }
+
+            if self.ppu.take_hblank() {
+                self.hdma.hblank_started();
+            }
+        }
+    }
+
+    /// Copies the VRAM DMA blocks that are due. The CPU is stalled while they
+    /// are copied, but the rest of the hardware keeps running.
+    pub fn run_hdma(&mut self) {
+        while let Some((source, destination)) = self.hdma.next_block() {
+            for offset in 0..0x10 {
+                let byte = self.read_as(source.wrapping_add(offset), Usage::Dma);
+                self.ppu.write(destination + offset, byte);
+            }
+
+            // Each block takes the same time at either speed
+            self.tick(if self.double_speed { 16 } else { 8 });
+        }
+    }
+
+    /// HBlank DMA doesn't copy anything while the CPU is halted
+    pub fn pause_hdma(&mut self) {
+        self.hdma.skip_block();
+    }
+
+    // Copies 160 bytes from 0xXX00 to OAM. Sources past 0xDFFF read from
+    // internal RAM, like echo RAM does.
+    fn oam_dma(&mut self, value: u8) {
+        let mut source = (value as u16) << 8;
+
+        if source >= 0xE000 {
+            source -= 0x2000;
+        }
+
+        for offset in 0..0xA0 {
+            let byte = self.read_as(source + offset, Usage::Dma);
+            self.ppu.write(0xFE00 + offset, byte);
+        }
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flags |= 1 << interrupt as u8;
+    }
+
+    /// Returns the interrupts that are both requested and enabled
+    pub fn pending_interrupts(&self) -> u8 {
+        // Only the lower 5 bits map to interrupt sources
+        self.interrupt_enable & self.interrupt_flags & 0x1F
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.joypad.set_pressed(button, pressed) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    /// Returns true if a selected joypad line is low, which ends STOP mode
+    pub fn joypad_line_low(&self) -> bool {
+        self.joypad.any_line_low()
+    }
+
+    pub fn reset_div(&mut self) {
+        self.timer.write(0xFF04, 0);
+    }
+
+    /// Switches between normal and double speed if a switch was requested
+    /// through KEY1, returning true if the speed changed
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb_mode || !self.speed_switch_armed {
+            return false;
+        }
+
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+
+        true
+    }
+
+    fn write_cgb_register(&mut self, address: u16, value: u8) {
+        match address {
+            // Can only be written by the boot ROM
+            0xFF4C if self.boot_rom.is_some() => self.key0 = value,
+
+            // Undocumented registers that remain in DMG mode
+            0xFF72..=0xFF73 => self.io.write(address, value),
+            0xFF75 => self.io.write(address, value & 0x70),
+
+            _ if !self.cgb_mode => {}
+
+            // Speed switch
+            0xFF4D => self.speed_switch_armed = value & 0x01 != 0,
+
+            0xFF4F | 0xFF68..=0xFF6C => self.ppu.write(address, value),
+
+            // VRAM DMA
+            0xFF51..=0xFF54 => self.hdma.write(address, value),
+            0xFF55 => self.hdma.start(value, self.ppu.mode() == Mode::HBlank),
+
+            // Bank 0 can't be selected, it maps bank 1 instead
+            0xFF70 => self.wram_bank = (value & 0x07).max(1),
+
+            0xFF56 => self.io.write(address, value & 0xC1),
+            0xFF74 => self.io.write(address, value),
+            _ => {}
+        }
+    }
+
+    /// Returns the bytes sent over the serial port so far
+    pub fn serial_output(&self) -> &[u8] {
+        &self.serial.output
+    }
+
+    /// Returns the first unmapped access since the last call, if any
+    pub fn take_fault(&mut self) -> Option<EmulationError> {
+        self.fault.take()
+    }
+
+    // Records the access and behaves like an open bus, which reads as 0xFF
+    fn unmapped(&self, address: u16, kind: AccessKind) -> u8 {
+        if self.fault.get().is_none() {
+            self.fault
+                .set(Some(EmulationError::UnmappedAccess { address, kind }));
+        }
+
+        0xFF
+    }
+
+    /// Reads a byte without setting off watchpoints or recording a fault,
+    /// for looking at memory from outside the emulation
+    pub fn peek(&self, address: u16) -> u8 {
+        let fault = self.fault.take();
+        let value = self.read_mapped(address);
+        self.fault.set(fault);
+
+        value
+    }
+
+    pub fn peek_word(&self, address: u16) -> u16 {
+        u16::from_le_bytes([self.peek(address), self.peek(address.wrapping_add(1))])
+    }
+
+    /// Adds a watchpoint, returning its number
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.watchpoints.push(watchpoint);
+        self.watchpoints.len() - 1
+    }
+
+    /// Removes a watchpoint. Later ones move down a number.
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        // Hits refer to watchpoints by number, which no longer line up
+        self.watch_hits.get_mut().clear();
+        (index < self.watchpoints.len()).then(|| self.watchpoints.remove(index))
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Returns the watchpoints set off by the last instruction
+    pub fn watch_hits(&self) -> Ref<'_, [WatchHit]> {
+        Ref::map(self.watch_hits.borrow(), Vec::as_slice)
+    }
+
+    /// Called by the CPU before anything else happens in a step, which
+    /// includes dispatching an interrupt
+    pub fn begin_instruction(&mut self, pc: u16) {
+        self.watch_hits.get_mut().clear();
+        if self.watchpoints.is_empty() {
+            return;
+        }
+
+        self.instruction = (pc, self.rom_bank(pc));
+    }
+
+    /// Called by the CPU right before it fetches an opcode
+    pub fn fetch_instruction(&mut self, pc: u16) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+
+        self.instruction = (pc, self.rom_bank(pc));
+        self.watch(Access::Execute, pc, self.peek(pc));
+    }
+
+    fn watch(&self, access: Access, address: u16, value: u8) {
+        let (pc, bank) = self.instruction;
+
+        for (index, watchpoint) in self.watchpoints.iter().enumerate() {
+            if watchpoint.matches(access, address, value) {
+                self.watch_hits.borrow_mut().push(WatchHit {
+                    watchpoint: index,
+                    access,
+                    address,
+                    value,
+                    pc,
+                    bank,
+                });
+            }
+        }
+    }
+
+    /// Starts logging how each byte of ROM is used, carrying on from an
+    /// earlier log if there is one
+    pub fn log_code_and_data(&mut self, log: Option<CodeDataLog>) {
+        let log = log.unwrap_or_else(|| CodeDataLog::new(self.cartridge().rom.len()));
+        self.code_data_log = Some(RefCell::new(log));
+    }
+
+    pub fn code_data_log(&self) -> Option<Ref<'_, CodeDataLog>> {
+        self.code_data_log.as_ref().map(RefCell::borrow)
+    }
+
+    /// Reads a byte of the instruction being run
+    pub fn read_instruction(&self, address: u16, usage: Usage) -> u8 {
+        self.read_as(address, usage)
+    }
+
+    fn read_as(&self, address: u16, usage: Usage) -> u8 {
+        let value = self.read_mapped(address);
+
+        if !self.watchpoints.is_empty() {
+            self.watch(Access::Read, address, value);
+        }
+
+        if let Some(log) = &self.code_data_log {
+            self.log_rom_access(log, address, usage);
+        }
+
+        value
+    }
+
+    fn log_rom_access(&self, log: &RefCell<CodeDataLog>, address: u16, usage: Usage) {
+        let from_boot_rom = self.boot_rom.as_ref().is_some_and(|b| b.maps(address));
+        if address > 0x7FFF || from_boot_rom {
+            return;
+        }
+
+        let offset = self.rom_bank(address) * 0x4000 + address as usize % 0x4000;
+        log.borrow_mut().mark(offset, usage);
+    }
+
+    fn read_mapped(&self, address: u16) -> u8 {
+        // The boot ROM sits on top of the cartridge until it's unmapped
+        if let Some(boot_rom) = self.boot_rom.as_ref().filter(|b| b.maps(address)) {
+            return boot_rom.read(address);
+        }
+
+        match address {
             // Cartridge ROM
             0x0000..=0x7FFF => self.mbc.read(address),
 
             // Video RAM
-            0x8000..=0x9FFF => self.vram.read(address - 0x8000),
+            0x8000..=0x9FFF => self.ppu.read(address),
 
-            // Internal RAM
-            0xC000..=0xDFFF => self.internal_ram.read(address - 0xC000),
+            // Cartridge RAM
+            0xA000..=0xBFFF if self.mbc.has_ram() => self.mbc.read(address),
 
-            // Echo RAM
-            0xE000..=0xFDFF => self.internal_ram.read(address - 0xE000),
+            // Internal RAM, and Echo RAM
+            0xC000..=0xFDFF => self.internal_ram.read(self.wram_offset(address)),
 
             // Object Attribute Memory (OAM)
-            0xFE00..=0xFE9F => 0, //self.io.write(address, value),
+            0xFE00..=0xFE9F => self.ppu.read(address),
+
+            // Unusable, nothing responds but that's not a mistake
+            0xFEA0..=0xFEFF => 0xFF,
+
+            // Joypad, which the SGB can swap for one of several
+            0xFF00 => match &self.sgb {
+                Some(sgb) => sgb.read_joypad(self.joypad.read(address)),
+                None => self.joypad.read(address),
+            },
 
             // Serial transfer
             0xFF01..=0xFF02 => self.serial.read(address),
@@ -67,11 +497,38 @@ impl Memory for Bus {
             // Timer
             0xFF04..=0xFF07 => self.timer.read(address),
 
-            // Interrupt status
-            0xFF0F => self.interrupt_flags,
+            // Interrupt status, the upper bits always read as set
+            0xFF0F => self.interrupt_flags | 0xE0,
+
+            // Speed switch
+            0xFF4D if self.cgb_mode => {
+                0x7E | ((self.double_speed as u8) << 7) | self.speed_switch_armed as u8
+            }
 
-            // IO Ports
-            0xFF00..=0xFF7F => self.io.read(address),
+            // LCD
+            0xFF40..=0xFF4B => self.ppu.read(address),
+
+            // VRAM and WRAM bank selection
+            0xFF4F if self.cgb_mode => self.ppu.read(address),
+            0xFF70 if self.cgb_mode => 0xF8 | self.wram_bank,
+
+            // VRAM DMA
+            0xFF51..=0xFF55 if self.cgb_mode => self.hdma.read(address),
+
+            // Infrared port, which never receives any light
+            0xFF56 if self.cgb_mode => (self.io.read(address) & 0xC1) | 0x3E,
+
+            // Color palettes, and object priority mode
+            0xFF68..=0xFF6C if self.cgb_mode => self.ppu.read(address),
+
+            // Undocumented registers, some of which remain in DMG mode
+            0xFF72..=0xFF73 if self.model.is_cgb() => self.io.read(address),
+            0xFF74 if self.cgb_mode => self.io.read(address),
+            0xFF75 if self.model.is_cgb() => 0x8F | self.io.read(address),
+
+            // IO Ports. Unused ones read as an open bus.
+            0xFF00..=0xFF7F if IO::is_writable(address) => self.io.read(address),
+            0xFF03..=0xFF7F => 0xFF,
 
             // Zero Page
             0xFF80..=0xFFFE => self.zero_page.read(address - 0xFF80),
@@ -79,29 +536,46 @@ impl Memory for Bus {
             // Interrupt enabled register
             0xFFFF => self.interrupt_enable,
 
-            _ => panic!("Attempted to read from invalid address: 0x{:04X}", address),
+            _ => self.unmapped(address, AccessKind::Read),
         }
     }
 
-    fn write(&mut self, address: u16, value: u8) {
+    fn write_mapped(&mut self, address: u16, value: u8) {
         match address {
             // Cartridge ROM
             0x0000..=0x7FFF => self.mbc.write(address, value),
 
             // Video RAM
-            0x8000..=0x9FFF => self.vram.write(address - 0x8000, value),
+            0x8000..=0x9FFF => self.ppu.write(address, value),
 
-            // Internal RAM
-            0xC000..=0xDFFF => self.internal_ram.write(address - 0xC000, value),
+            // Cartridge RAM
+            0xA000..=0xBFFF if self.mbc.has_ram() => self.mbc.write(address, value),
 
-            // Echo RAM
-            0xE000..=0xFDFF => self.internal_ram.write(address - 0xE000, value),
+            // Internal RAM, and Echo RAM
+            0xC000..=0xFDFF => self.internal_ram.write(self.wram_offset(address), value),
 
             // Object Attribute Memory (OAM)
-            0xFE00..=0xFE9F => {} //self.io.write(address, value),
+            0xFE00..=0xFE9F => self.ppu.write(address, value),
 
-            // Disable boot ROM when writing to this I/O address
-            0xFF50 => self.boot_rom = None,
+            // Unusable, writes go nowhere
+            0xFEA0..=0xFEFF => {}
+
+            // Setting bit 0 unmaps the boot ROM. It can't be mapped again, so
+            // only the first such write has any effect.
+            0xFF50 => {
+                if value & 0x01 != 0 && self.boot_rom.take().is_some() && self.model.is_cgb() {
+                    self.cgb_mode = self.key0 & 0x04 == 0;
+                }
+            }
+
+            // Joypad, which also carries packets to the SGB
+            0xFF00 => {
+                self.joypad.write(address, value);
+
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_joypad(value);
+                }
+            }
 
             // Serial transfer
             0xFF01..=0xFF02 => self.serial.write(address, value),
@@ -112,8 +586,26 @@ impl Memory for Bus {
             // Interrupt status
             0xFF0F => self.interrupt_flags = value,
 
-            // IO Ports
-            0xFF00..=0xFF7F => self.io.write(address, value),
+            // OAM DMA
+            0xFF46 => {
+                self.ppu.write(address, value);
+                self.oam_dma(value);
+            }
+
+            // LCD
+            0xFF40..=0xFF4B => self.ppu.write(address, value),
+
+            // CGB registers are locked in DMG compatibility mode, but writes
+            // to them aren't unmapped either
+            0xFF4C | 0xFF4D | 0xFF4F | 0xFF51..=0xFF56 | 0xFF68..=0xFF6C | 0xFF70..=0xFF75
+                if self.model.is_cgb() =>
+            {
+                self.write_cgb_register(address, value)
+            }
+
+            // IO Ports. Games write to unused ones, which ignore it.
+            0xFF00..=0xFF7F if IO::is_writable(address) => self.io.write(address, value),
+            0xFF03..=0xFF7F => {}
 
             // Zero Page
             0xFF80..=0xFFFE => self.zero_page.write(address - 0xFF80, value),
@@ -121,7 +613,370 @@ impl Memory for Bus {
             // Interrupt enabled register
             0xFFFF => self.interrupt_enable = value,
 
-            _ => panic!("Attempted to write to invalid address: 0x{:04X}", address),
+            _ => {
+                self.unmapped(address, AccessKind::Write);
+            }
+        }
+    }
+}
+
+impl Memory for Bus {
+    fn read(&self, address: u16) -> u8 {
+        self.read_as(address, Usage::Data)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if !self.watchpoints.is_empty() {
+            self.watch(Access::Write, address, value);
+        }
+
+        self.write_mapped(address, value);
+    }
+}
+
+// The boot ROM itself isn't saved, only whether it's still mapped. A state
+// saved while it's mapped needs the bus to have been created with it.
+impl Snapshot for Bus {
+    fn save(&self, state: &mut StateWriter) {
+        self.mbc.save(state);
+        self.internal_ram.save(state);
+        self.zero_page.save(state);
+        state.bool(self.boot_rom.is_some());
+        self.io.save(state);
+        self.timer.save(state);
+        state.u8(self.interrupt_enable);
+        state.u8(self.interrupt_flags);
+        self.serial.save(state);
+        self.joypad.save(state);
+        self.ppu.save(state);
+        self.hdma.save(state);
+
+        if let Some(sgb) = &self.sgb {
+            sgb.save(state);
+        }
+
+        state.bool(self.cgb_mode);
+        state.u8(self.key0);
+        state.u8(self.wram_bank);
+        state.bool(self.double_speed);
+        state.bool(self.speed_switch_armed);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<()> {
+        self.mbc.load(state)?;
+        self.internal_ram.load(state)?;
+        self.zero_page.load(state)?;
+
+        if !state.bool()? {
+            self.boot_rom = None;
+        } else if self.boot_rom.is_none() {
+            bail!("Save state was made while the boot ROM was running");
+        }
+
+        self.io.load(state)?;
+        self.timer.load(state)?;
+        self.interrupt_enable = state.u8()?;
+        self.interrupt_flags = state.u8()?;
+        self.serial.load(state)?;
+        self.joypad.load(state)?;
+        self.ppu.load(state)?;
+        self.hdma.load(state)?;
+
+        if let Some(sgb) = &mut self.sgb {
+            sgb.load(state)?;
+        }
+
+        self.cgb_mode = state.bool()?;
+        self.key0 = state.u8()?;
+        self.wram_bank = state.u8()?;
+        self.double_speed = state.bool()?;
+        self.speed_switch_armed = state.bool()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bus(model: Model, cgb_flag: u8) -> Bus {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0143] = cgb_flag;
+
+        Bus::new(Cartridge::new(rom), model)
+    }
+
+    #[test]
+    fn test_wram_banking() {
+        let mut bus = bus(Model::CGB, 0x80);
+        assert!(bus.cgb_mode);
+        assert_eq!(bus.read(0xFF70), 0xF9);
+
+        bus.write(0xC000, 0x10);
+        bus.write(0xD000, 0x11);
+        bus.write(0xFF70, 0x02);
+        assert_eq!(bus.read(0xC000), 0x10);
+        assert_eq!(bus.read(0xD000), 0x00);
+
+        bus.write(0xD000, 0x12);
+        assert_eq!(bus.read(0xF000), 0x12);
+
+        // Selecting bank 0 selects bank 1
+        bus.write(0xFF70, 0x00);
+        assert_eq!(bus.read(0xFF70), 0xF9);
+        assert_eq!(bus.read(0xD000), 0x11);
+    }
+
+    #[test]
+    fn test_vram_banking() {
+        let mut bus = bus(Model::CGB, 0xC0);
+
+        bus.write(0x8000, 0x20);
+        bus.write(0xFF4F, 0xFF);
+        assert_eq!(bus.read(0xFF4F), 0xFF);
+        assert_eq!(bus.read(0x8000), 0x00);
+
+        bus.write(0x9FFF, 0x21);
+        bus.write(0xFF4F, 0x00);
+        assert_eq!(bus.read(0xFF4F), 0xFE);
+        assert_eq!(bus.read(0x8000), 0x20);
+        assert_eq!(bus.read(0x9FFF), 0x00);
+    }
+
+    #[test]
+    fn test_dmg_compatibility_mode() {
+        let mut bus = bus(Model::CGB, 0x00);
+        assert!(!bus.cgb_mode);
+
+        // Bank switching is locked, and the registers read as unused
+        bus.write(0xD000, 0x11);
+        bus.write(0xFF70, 0x02);
+        bus.write(0xFF4F, 0x01);
+        bus.write(0xFF4D, 0x01);
+        assert_eq!(bus.read(0xD000), 0x11);
+        assert_eq!(bus.read(0xFF70), 0xFF);
+        assert_eq!(bus.read(0xFF4F), 0xFF);
+        assert_eq!(bus.read(0xFF4D), 0xFF);
+        assert!(!bus.switch_speed());
+        assert!(bus.take_fault().is_none());
+
+        // Some undocumented registers stay available
+        bus.write(0xFF72, 0x5A);
+        assert_eq!(bus.read(0xFF72), 0x5A);
+        assert_eq!(bus.read(0xFF75), 0x8F);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut bus = bus(Model::DMG, 0x00);
+
+        for offset in 0..0xA0 {
+            bus.write(0xC100 + offset, offset as u8);
         }
+
+        // 0xE1 reads from echo RAM, so from 0xC100 as well
+        bus.write(0xFF46, 0xE1);
+        assert_eq!(bus.read(0xFF46), 0xE1);
+        assert_eq!(bus.read(0xFE00), 0x00);
+        assert_eq!(bus.read(0xFE9F), 0x9F);
+    }
+
+    #[test]
+    fn test_palettes_locked_in_dmg_mode() {
+        let mut bus = bus(Model::CGB, 0x00);
+
+        bus.write(0xFF68, 0x80);
+        bus.write(0xFF69, 0x00);
+        assert_eq!(bus.read(0xFF68), 0xFF);
+        assert_eq!(bus.ppu().bg_palettes.color(0, 0), 0x7FFF);
+
+        bus = self::bus(Model::CGB, 0x80);
+
+        bus.write(0xFF68, 0x80);
+        bus.write(0xFF69, 0x00);
+        assert_eq!(bus.read(0xFF68), 0xC1);
+        assert_eq!(bus.ppu().bg_palettes.color(0, 0), 0x7F00);
+    }
+
+    // Points VRAM DMA from 0xC000 to 0x8000, with 0x40 bytes to copy
+    fn hdma_bus() -> Bus {
+        let mut bus = bus(Model::CGB, 0x80);
+
+        for offset in 0..0x40 {
+            bus.write(0xC000 + offset, offset as u8 + 1);
+        }
+
+        for (address, value) in [
+            (0xFF51, 0xC0),
+            (0xFF52, 0x00),
+            (0xFF53, 0x00),
+            (0xFF54, 0x00),
+        ] {
+            bus.write(address, value);
+        }
+
+        bus
+    }
+
+    #[test]
+    fn test_general_purpose_dma() {
+        let mut bus = hdma_bus();
+        let div = bus.read(0xFF04);
+
+        bus.write(0xFF55, 0x03);
+        bus.run_hdma();
+
+        assert_eq!(bus.read(0x8000), 0x01);
+        assert_eq!(bus.read(0x803F), 0x40);
+        assert_eq!(bus.read(0xFF55), 0xFF);
+
+        // The CPU was stalled for 4 blocks of 8 machine cycles, which moves
+        // the divider from 0x1EA0 to 0x1F20
+        assert_eq!(bus.read(0xFF04), div + 1);
+    }
+
+    #[test]
+    fn test_hblank_dma() {
+        let mut bus = hdma_bus();
+
+        // Wait for the end of the current HBlank
+        while bus.ppu.mode() == Mode::HBlank {
+            bus.tick(1);
+        }
+
+        bus.write(0xFF55, 0x83);
+        assert_eq!(bus.read(0xFF55), 0x03);
+
+        // One block is copied per HBlank
+        while bus.ppu.mode() != Mode::HBlank {
+            bus.tick(1);
+            bus.run_hdma();
+        }
+
+        assert_eq!(bus.read(0xFF55), 0x02);
+        assert_eq!(bus.read(0x800F), 0x10);
+        assert_eq!(bus.read(0x8010), 0x00);
+
+        // Clearing bit 7 stops the transfer
+        bus.write(0xFF55, 0x00);
+        assert_eq!(bus.read(0xFF55), 0x82);
+
+        for _ in 0..456 {
+            bus.tick(1);
+            bus.run_hdma();
+        }
+
+        assert_eq!(bus.read(0x8010), 0x00);
+    }
+
+    #[test]
+    fn test_hblank_dma_paused_while_halted() {
+        let mut bus = hdma_bus();
+        bus.write(0xFF55, 0x83);
+
+        for _ in 0..456 {
+            bus.tick(1);
+            bus.pause_hdma();
+        }
+
+        bus.run_hdma();
+        assert_eq!(bus.read(0xFF55), 0x03);
+    }
+
+    #[test]
+    fn test_compatibility_palettes() {
+        let mut bus = bus(Model::CGB, 0x00);
+        let default = CompatibilityPalettes::manual(ManualPalette::RightA);
+        assert_eq!(bus.ppu.bg_palettes.color(0, 1), default.bg[1]);
+
+        bus.set_compatibility_palette(ManualPalette::LeftB);
+        assert_eq!(bus.ppu.bg_palettes.color(0, 1), 0x5294);
+        assert_eq!(bus.ppu.obj_palettes.color(1, 2), 0x294A);
+
+        // CGB games pick their own palettes
+        let mut bus = self::bus(Model::CGB, 0x80);
+        bus.set_compatibility_palette(ManualPalette::LeftB);
+        assert_eq!(bus.ppu.bg_palettes.color(0, 1), 0x7FFF);
+    }
+
+    #[test]
+    fn test_cgb_registers_on_dmg() {
+        let mut bus = bus(Model::DMG, 0x80);
+        assert!(!bus.cgb_mode);
+        assert_eq!(bus.read(0xFF70), 0xFF);
+
+        // Like any unused register, it ignores writes without faulting
+        bus.write(0xFF70, 0x02);
+        assert_eq!(bus.take_fault(), None);
+        assert_eq!(bus.read(0xFF70), 0xFF);
+    }
+
+    #[test]
+    fn test_boot_rom_selects_dmg_mode() {
+        let rom = Cartridge::new(vec![0; 0x8000]);
+        let mut bus = Bus::with_boot_rom(
+            rom,
+            Model::CGB,
+            BootROM::from_bytes(&[0; 0x900], Model::CGB).unwrap(),
+        );
+        assert!(bus.cgb_mode);
+
+        bus.write(0xFF4C, 0x04);
+        bus.write(0xFF50, 0x11);
+        assert!(!bus.cgb_mode);
+
+        // KEY0 is locked once the boot ROM is gone
+        bus.write(0xFF4C, 0x80);
+        bus.write(0xFF50, 0x11);
+        assert!(!bus.cgb_mode);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut rom = vec![0; 0x10000];
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x01;
+        let mut bus = Bus::new(Cartridge::new(rom), Model::DMG);
+
+        bus.add_watchpoint(Watchpoint::range(Access::Write, 0xC000, 0xC00F).unwrap());
+        bus.add_watchpoint(Watchpoint::new(Access::Read, 0xC008).with_value(0x42));
+
+        bus.write(0x2000, 0x02);
+        bus.begin_instruction(0x4123);
+        bus.write(0xC008, 0x42);
+        bus.write(0xC010, 0x42);
+        assert_eq!(bus.read(0xC008), 0x42);
+
+        assert_eq!(
+            &*bus.watch_hits(),
+            [
+                WatchHit {
+                    watchpoint: 0,
+                    access: Access::Write,
+                    address: 0xC008,
+                    value: 0x42,
+                    pc: 0x4123,
+                    bank: 2,
+                },
+                WatchHit {
+                    watchpoint: 1,
+                    access: Access::Read,
+                    address: 0xC008,
+                    value: 0x42,
+                    pc: 0x4123,
+                    bank: 2,
+                },
+            ]
+        );
+
+        // Peeking doesn't count, and each instruction starts afresh
+        bus.begin_instruction(0x4126);
+        bus.peek(0xC008);
+        assert!(bus.watch_hits().is_empty());
+
+        bus.remove_watchpoint(0);
+        bus.write(0xC000, 0x01);
+        assert!(bus.watch_hits().is_empty());
     }
 }
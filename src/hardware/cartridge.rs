@@ -41,10 +41,19 @@ impl From<u8> for Destination {
 }
 
 impl Cartridge {
+    pub fn new(mut rom: Vec<u8>) -> Cartridge {
+        // Real cartridges are at least two banks, and always a whole number of
+        // banks. Padding truncated dumps keeps bank lookups in range.
+        let size = rom.len().max(0x8000).next_multiple_of(0x4000);
+        rom.resize(size, 0xFF);
+
+        Cartridge { rom }
+    }
+
     pub fn from_path(path: &str) -> Result<Cartridge> {
         let rom = fs::read(path)?;
 
-        Ok(Cartridge { rom })
+        Ok(Cartridge::new(rom))
     }
 
     /// Returns the name of the cartridge
@@ -93,10 +102,39 @@ impl Cartridge {
             0x03 => (4, 8 * 1024),
             0x04 => (16, 8 * 1024),
             0x05 => (8, 8 * 1024),
-            _ => panic!("Unknown RAM size: {}", self.rom[0x0149]),
+            // Treat unknown sizes as having no RAM, so accesses are unmapped
+            _ => (0, 0),
+        }
+    }
+
+    /// Returns true if the cartridge header enables Game Boy Color features
+    pub fn supports_cgb(&self) -> bool {
+        self.rom[0x0143] & 0x80 != 0
+    }
+
+    /// Returns true if the cartridge header enables Super Game Boy features,
+    /// which also needs the new licensee code
+    pub fn supports_sgb(&self) -> bool {
+        self.rom[0x0146] == 0x03 && self.rom[0x014B] == 0x33
+    }
+
+    /// Returns true if either licensee code in the header is Nintendo's
+    pub fn is_licensed_by_nintendo(&self) -> bool {
+        match self.rom[0x014B] {
+            0x01 => true,
+            0x33 => &self.rom[0x0144..=0x0145] == b"01",
+            _ => false,
         }
     }
 
+    /// Returns the sum of the title bytes, which the CGB boot ROM uses to pick
+    /// a palette for monochrome games
+    pub fn title_checksum(&self) -> u8 {
+        self.rom[0x0134..=0x0143]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+    }
+
     pub fn destination(&self) -> Destination {
         self.rom[0x014A].into()
     }
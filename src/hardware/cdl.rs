@@ -0,0 +1,97 @@
+use std::fs;
+
+use anyhow::{bail, Result};
+
+/// The ways a byte of ROM can be used while a game runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usage {
+    Opcode = 0x01,
+    Operand = 0x02,
+    Data = 0x04,
+    Dma = 0x08,
+}
+
+/// A code/data log: how each byte of ROM has been used so far. It's saved as
+/// one byte of `Usage` flags per byte of ROM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeDataLog {
+    flags: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(rom_size: usize) -> CodeDataLog {
+        CodeDataLog {
+            flags: vec![0; rom_size],
+        }
+    }
+
+    /// Loads a log saved for a ROM of the given size
+    pub fn from_bytes(data: &[u8], rom_size: usize) -> Result<CodeDataLog> {
+        if data.len() != rom_size {
+            bail!(
+                "The code/data log covers {} bytes, but the ROM is {} bytes",
+                data.len(),
+                rom_size
+            );
+        }
+
+        Ok(CodeDataLog {
+            flags: data.to_vec(),
+        })
+    }
+
+    pub fn from_path(path: &str, rom_size: usize) -> Result<CodeDataLog> {
+        CodeDataLog::from_bytes(&fs::read(path)?, rom_size)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        Ok(fs::write(path, &self.flags)?)
+    }
+
+    pub fn mark(&mut self, offset: usize, usage: Usage) {
+        if let Some(flags) = self.flags.get_mut(offset) {
+            *flags |= usage as u8;
+        }
+    }
+
+    pub fn has(&self, offset: usize, usage: Usage) -> bool {
+        self.flags
+            .get(offset)
+            .is_some_and(|flags| flags & usage as u8 != 0)
+    }
+
+    /// Returns true if the byte was run as part of an instruction
+    pub fn is_code(&self, offset: usize) -> bool {
+        self.has(offset, Usage::Opcode) || self.has(offset, Usage::Operand)
+    }
+
+    /// Returns true if the byte was only ever read as data
+    pub fn is_data(&self, offset: usize) -> bool {
+        !self.is_code(offset) && (self.has(offset, Usage::Data) || self.has(offset, Usage::Dma))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags() {
+        let mut log = CodeDataLog::new(0x8000);
+        log.mark(0x0100, Usage::Opcode);
+        log.mark(0x0101, Usage::Operand);
+        log.mark(0x0101, Usage::Data);
+        log.mark(0x4000, Usage::Dma);
+        log.mark(0x8000, Usage::Data);
+
+        assert!(log.is_code(0x0100));
+        assert!(log.is_code(0x0101) && !log.is_data(0x0101));
+        assert!(log.is_data(0x4000) && !log.is_code(0x4000));
+        assert!(!log.is_code(0x0102) && !log.is_data(0x0102));
+
+        let saved = log.flags.clone();
+        assert_eq!(saved[0x0101], 0x06);
+        assert_eq!(CodeDataLog::from_bytes(&saved, 0x8000).unwrap(), log);
+        assert!(CodeDataLog::from_bytes(&saved, 0x10000).is_err());
+    }
+}
@@ -1,5 +1,17 @@
-use super::{bus::Bus, opcode::Opcode, registers::Registers, Memory};
-use crate::hardware::opcode::execute_opcode;
+use std::rc::Rc;
+
+use anyhow::Result;
+
+use super::{
+    bus::Bus,
+    cdl::Usage,
+    error::{EmulationError, EmulationMode},
+    opcode::Opcode,
+    registers::Registers,
+    state::{Snapshot, StateReader, StateWriter},
+    Memory,
+};
+use crate::{hardware::opcode::execute_opcode, symbols::Symbols};
 
 pub struct CPU {
     // Standard registers
@@ -17,58 +29,140 @@ pub struct CPU {
     // Halt flag
     pub halted: bool,
 
+    // Set when HALT is executed with IME off and an interrupt already pending.
+    // The next byte is then read without incrementing the program counter.
+    pub halt_bug: bool,
+
+    // Set by STOP, the CPU and LCD are paused until a joypad line goes low
+    pub stopped: bool,
+
+    // Set when an illegal opcode hangs the CPU in hardware accurate mode
+    pub locked: bool,
+
+    // How illegal opcodes and unmapped memory accesses are handled
+    pub mode: EmulationMode,
+
     // Address bus
     pub bus: Bus,
 
-    // These are used to delay the enabling/disabling of interrupts
+    // EI only enables interrupts after the instruction that follows it
     pub interrupt_enable_counter: u8,
-    pub interrupt_disable_counter: u8,
 
     // This is used to print out the state of the CPU after each instruction
     debug: bool,
+
+    // Labels shown in the trace
+    symbols: Option<Rc<Symbols>>,
 }
 
 impl CPU {
     pub fn new(bus: Bus) -> CPU {
+        // Without a boot ROM, start where it would have handed over
+        let (registers, pc, sp) = if bus.boot_rom_mapped() {
+            (Registers::zeroed(), 0x0000, 0x0000)
+        } else {
+            let registers = bus.model.initial_registers(bus.cartridge());
+            (registers, 0x0100, 0xFFFE)
+        };
+
         CPU {
-            registers: Registers::new(),
-            pc: 0x100,
-            sp: 0xFFFE,
+            registers,
+            pc,
+            sp,
             bus,
             ime: false,
             halted: false,
+            halt_bug: false,
+            stopped: false,
+            locked: false,
+            mode: EmulationMode::default(),
             debug: false,
+            symbols: None,
             interrupt_enable_counter: 0,
-            interrupt_disable_counter: 0,
         }
     }
 
-    pub fn execute_next_instruction(&mut self) -> u8 {
-        self.update_ime();
-        self.handleinterrupt();
+    /// Prints the registers before each instruction, along with any
+    /// watchpoints it sets off
+    pub fn set_trace(&mut self, trace: bool) {
+        self.debug = trace;
+    }
 
-        if !self.debug {
-            // If we're not in debug mode, just execute the next instruction
-            let opcode = Opcode::from_byte(self.next_byte());
+    /// Puts a line with the label before instructions that have one, while
+    /// tracing
+    pub fn set_symbols(&mut self, symbols: Rc<Symbols>) {
+        self.symbols = Some(symbols);
+    }
 
-            execute_opcode(self, opcode)
-        } else {
-            let original_pc = self.pc;
-            let m0 = self.peek_byte_at_offset(0);
-            let m1 = self.peek_byte_at_offset(1);
-            let m2 = self.peek_byte_at_offset(2);
-            let m3 = self.peek_byte_at_offset(3);
+    pub fn execute_next_instruction(&mut self) -> Result<u8, EmulationError> {
+        self.bus.begin_instruction(self.pc);
 
-            let op = self.next_byte();
+        if self.locked {
+            // A locked up CPU never fetches another instruction, but the rest
+            // of the hardware keeps running
+            self.bus.tick(1);
+            return Ok(1);
+        }
+
+        if self.stopped {
+            // The main oscillator is off, so nothing else runs either
+            if !self.bus.joypad_line_low() {
+                return Ok(1);
+            }
 
-            let opcode = Opcode::from_byte(op);
+            self.stopped = false;
+        }
 
+        if self.halted {
+            if self.bus.pending_interrupts() == 0 {
+                self.bus.tick(1);
+                self.bus.pause_hdma();
+                return Ok(1);
+            }
+
+            // Any enabled interrupt wakes the CPU, even with IME off
+            self.halted = false;
+        }
+
+        self.update_ime();
+        let interrupt_cycles = self.handleinterrupt();
+
+        if self.debug {
+            let symbols = self.symbols.as_deref();
+            if let Some(label) = symbols.and_then(|s| s.label(self.bus.rom_bank(self.pc), self.pc))
+            {
+                println!("{}:", label);
+            }
+
+            // Print out the state of the CPU before executing the instruction
             println!(
                 "{} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
-                self.registers, self.sp, original_pc, m0, m1, m2, m3
+                self.registers,
+                self.sp,
+                self.pc,
+                self.peek_byte_at_offset(0),
+                self.peek_byte_at_offset(1),
+                self.peek_byte_at_offset(2),
+                self.peek_byte_at_offset(3)
             );
+        }
 
-            execute_opcode(self, opcode)
+        self.bus.fetch_instruction(self.pc);
+        let opcode = Opcode::from_byte(self.fetch(Usage::Opcode));
+        let cycles = execute_opcode(self, opcode)?;
+
+        if self.debug {
+            for hit in self.bus.watch_hits().iter() {
+                println!("{}", hit);
+            }
+        }
+
+        self.bus.tick(cycles);
+        self.bus.run_hdma();
+
+        match self.bus.take_fault() {
+            Some(fault) if self.mode == EmulationMode::Strict => Err(fault),
+            _ => Ok(interrupt_cycles + cycles),
         }
     }
 
@@ -81,51 +175,58 @@ impl CPU {
             }
             _ => 0,
         };
-
-        self.interrupt_disable_counter = match self.interrupt_disable_counter {
-            2 => 1,
-            1 => {
-                self.ime = false;
-                0
-            }
-            _ => 0,
-        };
     }
 
-    fn handleinterrupt(&mut self) -> u32 {
-        if self.ime == false && self.halted == false {
+    // Dispatching an interrupt takes 5 machine cycles, and the hardware keeps
+    // running in between. The interrupt to service is only chosen after the
+    // high byte of PC is pushed, so a push that overwrites IE can change it,
+    // or cancel the dispatch entirely, which jumps to 0x0000.
+    fn handleinterrupt(&mut self) -> u8 {
+        if !self.ime || self.bus.pending_interrupts() == 0 {
             return 0;
         }
 
-        let triggered = self.bus.interrupt_enable & self.bus.interrupt_flags;
-        if triggered == 0 {
-            return 0;
-        }
-
-        self.halted = false;
-        if self.ime == false {
-            return 0;
-        }
         self.ime = false;
+        self.bus.tick(2);
 
-        let n = triggered.trailing_zeros() as u16;
-        if n >= 5 {
-            panic!("Invalid interrupt triggered");
-        }
+        self.push_byte((self.pc >> 8) as u8);
+        self.bus.tick(1);
+
+        let triggered = self.bus.pending_interrupts();
 
-        // Disable the handled interrupt
-        self.bus.interrupt_flags &= !(1 << n);
+        self.push_byte(self.pc as u8);
+        self.bus.tick(1);
 
-        self.push_word(self.pc);
-        self.pc = 0x0040 | (n << 3);
+        self.pc = if triggered == 0 {
+            0x0000
+        } else {
+            let n = triggered.trailing_zeros() as u16;
 
-        return 4;
+            // Disable the handled interrupt
+            self.bus.interrupt_flags &= !(1 << n);
+
+            0x0040 | (n << 3)
+        };
+        self.bus.tick(1);
+
+        5
     }
 
     // Reads the next byte and increments the program counter
     pub fn next_byte(&mut self) -> u8 {
-        let byte = self.bus.read(self.pc);
-        self.pc = self.pc.wrapping_add(1);
+        self.fetch(Usage::Operand)
+    }
+
+    // Like next_byte, but says whether the byte is an opcode for the
+    // code/data log
+    fn fetch(&mut self, usage: Usage) -> u8 {
+        let byte = self.bus.read_instruction(self.pc, usage);
+
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
 
         byte
     }
@@ -138,25 +239,25 @@ impl CPU {
     }
 
     pub fn peek_byte(&self) -> u8 {
-        self.bus.read(self.pc)
+        self.bus.peek(self.pc)
     }
 
     pub fn peek_byte_at_offset(&self, offset: u16) -> u8 {
-        self.bus.read(self.pc + offset)
+        self.bus.peek(self.pc + offset)
     }
 
     pub fn peek_word(&self) -> u16 {
-        let low = self.bus.read(self.pc) as u16;
-        let high = self.bus.read(self.pc + 1) as u16;
+        let low = self.bus.peek(self.pc) as u16;
+        let high = self.bus.peek(self.pc + 1) as u16;
 
         low | (high << 8)
     }
 
     pub fn peek_double(&self) -> u32 {
-        let low = self.bus.read(self.pc) as u32;
-        let high = self.bus.read(self.pc + 1) as u32;
-        let low2 = self.bus.read(self.pc + 2) as u32;
-        let high2 = self.bus.read(self.pc + 3) as u32;
+        let low = self.bus.peek(self.pc) as u32;
+        let high = self.bus.peek(self.pc + 1) as u32;
+        let low2 = self.bus.peek(self.pc + 2) as u32;
+        let high2 = self.bus.peek(self.pc + 3) as u32;
 
         low | (high << 8) | (low2 << 16) | (high2 << 24)
     }
@@ -191,11 +292,473 @@ impl CPU {
         low | (high << 8)
     }
 
-    pub fn set_halted(&mut self, halted: bool) {
-        self.halted = halted;
+    pub fn halt(&mut self) {
+        let ime = self.ime || self.interrupt_enable_counter > 0;
+
+        if !ime && self.bus.pending_interrupts() != 0 {
+            // The CPU doesn't halt, and trips over the HALT bug instead
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
     }
 
     pub fn stop(&mut self) {
-        panic!("STOP instruction executed");
+        // STOP is followed by a padding byte that is skipped
+        self.next_byte();
+        self.bus.reset_div();
+
+        // On the CGB, STOP is also used to switch speeds when armed via KEY1
+        if !self.bus.switch_speed() {
+            self.stopped = true;
+        }
+    }
+
+    // Real hardware hangs when it hits one of the unused opcodes, only a reset
+    // brings it back.
+    pub fn illegal_opcode(&mut self) -> Result<(), EmulationError> {
+        let pc = self.pc.wrapping_sub(1);
+
+        match self.mode {
+            EmulationMode::Strict => Err(EmulationError::IllegalOpcode {
+                pc,
+                opcode: self.bus.peek(pc),
+            }),
+            EmulationMode::HardwareAccurate => {
+                self.locked = true;
+                Ok(())
+            }
+        }
+    }
+}
+
+// The emulation mode and debug output are settings, and stay as they are
+impl Snapshot for CPU {
+    fn save(&self, state: &mut StateWriter) {
+        for register in [
+            self.registers.a,
+            self.registers.b,
+            self.registers.c,
+            self.registers.d,
+            self.registers.e,
+            self.registers.f.into(),
+            self.registers.h,
+            self.registers.l,
+        ] {
+            state.u8(register);
+        }
+
+        state.u16(self.pc);
+        state.u16(self.sp);
+        state.bool(self.ime);
+        state.u8(self.interrupt_enable_counter);
+        state.bool(self.halted);
+        state.bool(self.halt_bug);
+        state.bool(self.stopped);
+        state.bool(self.locked);
+
+        self.bus.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<()> {
+        self.registers.a = state.u8()?;
+        self.registers.b = state.u8()?;
+        self.registers.c = state.u8()?;
+        self.registers.d = state.u8()?;
+        self.registers.e = state.u8()?;
+        self.registers.f = state.u8()?.into();
+        self.registers.h = state.u8()?;
+        self.registers.l = state.u8()?;
+
+        self.pc = state.u16()?;
+        self.sp = state.u16()?;
+        self.ime = state.bool()?;
+        self.interrupt_enable_counter = state.u8()?;
+        self.halted = state.bool()?;
+        self.halt_bug = state.bool()?;
+        self.stopped = state.bool()?;
+        self.locked = state.bool()?;
+
+        self.bus.load(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::{
+        boot_rom::BootROM, cartridge::Cartridge, error::AccessKind, io::joypad::Button,
+        model::Model,
+    };
+
+    // Builds a CPU with the program placed at the entry point
+    fn cpu_with_program(program: &[u8]) -> CPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+
+        CPU::new(Bus::new(Cartridge::new(rom), Model::DMG))
+    }
+
+    #[test]
+    fn test_illegal_opcode_strict() {
+        let mut cpu = cpu_with_program(&[0x00, 0xD3]);
+
+        assert_eq!(cpu.execute_next_instruction(), Ok(1));
+        assert_eq!(
+            cpu.execute_next_instruction(),
+            Err(EmulationError::IllegalOpcode {
+                pc: 0x101,
+                opcode: 0xD3
+            })
+        );
+    }
+
+    #[test]
+    fn test_illegal_opcode_locks_up() {
+        let mut cpu = cpu_with_program(&[0xFD, 0x3C]);
+        cpu.mode = EmulationMode::HardwareAccurate;
+
+        assert!(cpu.execute_next_instruction().is_ok());
+        assert!(cpu.locked);
+
+        // The CPU never gets to the INC A that follows
+        assert!(cpu.execute_next_instruction().is_ok());
+        assert_eq!(cpu.pc, 0x101);
+        assert_eq!(cpu.registers.a, 0x01);
+    }
+
+    #[test]
+    fn test_halt_wakes_without_ime() {
+        // HALT, INC A
+        let mut cpu = cpu_with_program(&[0x76, 0x3C]);
+        cpu.bus.interrupt_enable = 0x04;
+
+        cpu.execute_next_instruction().unwrap();
+        assert!(cpu.halted);
+
+        // Halted CPUs don't fetch instructions
+        cpu.execute_next_instruction().unwrap();
+        assert_eq!(cpu.pc, 0x101);
+
+        // The interrupt wakes the CPU, but isn't serviced with IME off
+        cpu.bus.interrupt_flags = 0x04;
+        cpu.execute_next_instruction().unwrap();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.pc, 0x102);
+        assert_eq!(cpu.registers.a, 0x02);
+        assert_eq!(cpu.bus.interrupt_flags, 0x04);
+    }
+
+    #[test]
+    fn test_code_data_log() {
+        let mut cpu = cpu_with_program(&[
+            0xFA, 0x00, 0x03, // ld a, [$0300]
+            0x3E, 0x02, // ld a, $02
+            0xE0, 0x46, // ldh [$ff46], a
+        ]);
+        cpu.bus.log_code_and_data(None);
+
+        for _ in 0..3 {
+            cpu.execute_next_instruction().unwrap();
+        }
+
+        let log = cpu.bus.code_data_log().unwrap();
+        for (offset, usage) in [
+            (0x0100, Usage::Opcode),
+            (0x0102, Usage::Operand),
+            (0x0103, Usage::Opcode),
+            (0x0106, Usage::Operand),
+            (0x0300, Usage::Data),
+            (0x0200, Usage::Dma),
+            (0x029F, Usage::Dma),
+        ] {
+            assert!(log.has(offset, usage), "{:04X} {:?}", offset, usage);
+        }
+
+        assert!(!log.has(0x0102, Usage::Data));
+        assert!(!log.is_code(0x0107) && !log.is_data(0x02A0));
+    }
+
+    #[test]
+    fn test_halt_keeps_timer_running() {
+        // HALT with the timer incrementing every 4 machine cycles
+        let mut cpu = cpu_with_program(&[0x76]);
+        cpu.bus.interrupt_enable = 0x04;
+        cpu.bus.write(0xFF06, 0xFE);
+        cpu.bus.write(0xFF05, 0xFE);
+        cpu.bus.write(0xFF07, 0x05);
+
+        cpu.execute_next_instruction().unwrap();
+
+        let mut cycles = 0;
+        while cpu.halted {
+            cycles += cpu.execute_next_instruction().unwrap() as u32;
+            assert!(cycles < 100);
+        }
+
+        assert_eq!(cpu.bus.interrupt_flags & 0x04, 0x04);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        // HALT, INC A, with an interrupt already pending and IME off
+        let mut cpu = cpu_with_program(&[0x76, 0x3C, 0x00]);
+        cpu.bus.interrupt_enable = 0x01;
+        cpu.bus.interrupt_flags = 0x01;
+
+        cpu.execute_next_instruction().unwrap();
+        assert!(!cpu.halted);
+
+        // INC A is read twice, since PC fails to increment the first time
+        cpu.execute_next_instruction().unwrap();
+        cpu.execute_next_instruction().unwrap();
+        assert_eq!(cpu.registers.a, 0x03);
+        assert_eq!(cpu.pc, 0x102);
+    }
+
+    #[test]
+    fn test_stop_waits_for_joypad() {
+        // Select the buttons, STOP, INC A
+        let mut cpu = cpu_with_program(&[0x10, 0x00, 0x3C]);
+        cpu.bus.write(0xFF00, 0x10);
+        cpu.bus.write(0xFF07, 0x05);
+        cpu.bus.tick(200);
+
+        cpu.execute_next_instruction().unwrap();
+        assert!(cpu.stopped);
+        assert_eq!(cpu.pc, 0x102);
+        assert_eq!(cpu.bus.read(0xFF04), 0x00);
+
+        // Nothing runs until a button is pressed, including the timer
+        let tima = cpu.bus.read(0xFF05);
+        for _ in 0..1000 {
+            cpu.execute_next_instruction().unwrap();
+        }
+        assert_eq!(cpu.pc, 0x102);
+        assert_eq!(cpu.bus.read(0xFF05), tima);
+
+        // The d-pad isn't selected, so its line stays high
+        cpu.bus.set_button(Button::Up, true);
+        cpu.execute_next_instruction().unwrap();
+        assert!(cpu.stopped);
+
+        cpu.bus.set_button(Button::Start, true);
+        cpu.execute_next_instruction().unwrap();
+        assert!(!cpu.stopped);
+        assert_eq!(cpu.registers.a, 0x02);
+    }
+
+    #[test]
+    fn test_stop_switches_speed() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0143] = 0x80;
+        rom[0x0100..0x0103].copy_from_slice(&[0x10, 0x00, 0x3C]);
+
+        let mut cpu = CPU::new(Bus::new(Cartridge::new(rom), Model::CGB));
+        cpu.bus.write(0xFF4D, 0x01);
+        assert_eq!(cpu.bus.read(0xFF4D), 0x7F);
+
+        cpu.execute_next_instruction().unwrap();
+        assert!(!cpu.stopped);
+        assert!(cpu.bus.double_speed);
+        assert_eq!(cpu.bus.read(0xFF4D), 0xFE);
+    }
+
+    #[test]
+    fn test_boot_rom_matches_skipped_boot() {
+        // The boot ROM locks up unless the header has the Nintendo logo and a
+        // valid checksum
+        let mut rom = vec![0; 0x8000];
+        rom[0x0104..0x0134].copy_from_slice(&[
+            0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C,
+            0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6,
+            0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC,
+            0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+        ]);
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        rom[0x014D] = rom[0x0134..=0x014C]
+            .iter()
+            .fold(0u8, |x, byte| x.wrapping_sub(*byte).wrapping_sub(1));
+        let cartridge = Cartridge::new(rom);
+
+        let expected = CPU::new(Bus::new(Cartridge::new(cartridge.rom.clone()), Model::DMG));
+
+        let bus = Bus::with_boot_rom(cartridge, Model::DMG, BootROM::new());
+        let mut cpu = CPU::new(bus);
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(cpu.bus.read(0x0000), 0x31);
+
+        while cpu.pc != 0x0100 {
+            cpu.execute_next_instruction().unwrap();
+        }
+
+        assert!(!cpu.bus.boot_rom_mapped());
+        assert_eq!(cpu.bus.read(0x0000), 0x00);
+        assert_eq!(cpu.registers.af(), expected.registers.af());
+        assert_eq!(cpu.registers.bc(), expected.registers.bc());
+        assert_eq!(cpu.registers.de(), expected.registers.de());
+        assert_eq!(cpu.registers.hl(), expected.registers.hl());
+        assert_eq!(cpu.sp, expected.sp);
+    }
+
+    #[test]
+    fn test_boot_rom_validation() {
+        let dmg = include_bytes!("../../boot/dmg_boot.bin");
+
+        assert!(BootROM::from_bytes(dmg, Model::DMG).is_ok());
+        assert!(BootROM::from_bytes(dmg, Model::SGB).is_err());
+        assert!(BootROM::from_bytes(dmg, Model::CGB).is_err());
+        assert!(BootROM::from_bytes(&dmg[..0xFF], Model::DMG).is_err());
+
+        // Unknown boot ROMs of the right size are accepted
+        assert!(BootROM::from_bytes(&[0; 0x900], Model::CGB).is_ok());
+    }
+
+    #[test]
+    #[ignore = "needs the blargg test ROM in priv/"]
+    fn test_blargg_halt_bug() {
+        let cartridge = Cartridge::from_path("priv/halt_bug.gb").unwrap();
+        let mut cpu = CPU::new(Bus::new(cartridge, Model::DMG));
+
+        for _ in 0..5_000 {
+            for _ in 0..10_000 {
+                cpu.execute_next_instruction().unwrap();
+            }
+
+            let output = String::from_utf8_lossy(cpu.bus.serial_output());
+            if output.contains("Failed") {
+                panic!("{}", output);
+            }
+            if output.contains("Passed") {
+                return;
+            }
+        }
+
+        panic!("Test ROM did not finish");
+    }
+
+    #[test]
+    fn test_interrupt_dispatch() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.ime = true;
+        cpu.bus.interrupt_enable = 0x1F;
+        cpu.bus.interrupt_flags = 0x0C;
+
+        // The dispatch and the first instruction of the handler
+        assert_eq!(cpu.execute_next_instruction(), Ok(5 + 1));
+        assert_eq!(cpu.pc, 0x51);
+        assert_eq!(cpu.sp, 0xFFFC);
+        assert_eq!(cpu.bus.read_word(0xFFFC), 0x100);
+        assert!(!cpu.ime);
+
+        // Only the serviced interrupt is acknowledged
+        assert_eq!(cpu.bus.interrupt_flags, 0x08);
+    }
+
+    #[test]
+    fn test_interrupt_dispatch_cancelled_by_ie_push() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.ime = true;
+        cpu.sp = 0x0000;
+        cpu.bus.interrupt_enable = 0x04;
+        cpu.bus.interrupt_flags = 0x04;
+
+        // Pushing the high byte of PC (0x01) to 0xFFFF disables the timer
+        // interrupt before the vector is chosen
+        cpu.execute_next_instruction().unwrap();
+        assert_eq!(cpu.bus.interrupt_enable, 0x01);
+        assert_eq!(cpu.bus.interrupt_flags, 0x04);
+        assert_eq!(cpu.pc, 0x0001);
+    }
+
+    #[test]
+    fn test_interrupt_dispatch_redirected_by_ie_push() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.ime = true;
+        cpu.sp = 0x0000;
+        cpu.pc = 0x0200;
+        cpu.bus.interrupt_enable = 0x01;
+        cpu.bus.interrupt_flags = 0x03;
+
+        // The push enables the LCD interrupt instead of VBlank
+        cpu.execute_next_instruction().unwrap();
+        assert_eq!(cpu.bus.interrupt_flags, 0x01);
+        assert_eq!(cpu.pc, 0x0049);
+    }
+
+    #[test]
+    fn test_ei_delay() {
+        // EI, NOP, NOP
+        let mut cpu = cpu_with_program(&[0xFB, 0x00, 0x00]);
+        cpu.bus.interrupt_enable = 0x01;
+        cpu.bus.interrupt_flags = 0x01;
+
+        // The instruction after EI always executes
+        cpu.execute_next_instruction().unwrap();
+        cpu.execute_next_instruction().unwrap();
+        assert_eq!(cpu.pc, 0x102);
+
+        cpu.execute_next_instruction().unwrap();
+        assert_eq!(cpu.pc, 0x41);
+    }
+
+    #[test]
+    fn test_ei_then_di() {
+        // EI, DI, NOP
+        let mut cpu = cpu_with_program(&[0xFB, 0xF3, 0x00]);
+        cpu.bus.interrupt_enable = 0x01;
+        cpu.bus.interrupt_flags = 0x01;
+
+        for _ in 0..3 {
+            cpu.execute_next_instruction().unwrap();
+        }
+
+        assert!(!cpu.ime);
+        assert_eq!(cpu.pc, 0x103);
+    }
+
+    #[test]
+    fn test_reti_enables_ime_immediately() {
+        // RETI to 0x0200
+        let mut cpu = cpu_with_program(&[0xD9]);
+        cpu.push_word(0x0200);
+        cpu.bus.interrupt_enable = 0x01;
+        cpu.bus.interrupt_flags = 0x01;
+
+        cpu.execute_next_instruction().unwrap();
+        assert!(cpu.ime);
+        assert_eq!(cpu.pc, 0x200);
+
+        // The pending interrupt is serviced before the next instruction
+        cpu.execute_next_instruction().unwrap();
+        assert_eq!(cpu.bus.read_word(cpu.sp), 0x200);
+        assert_eq!(cpu.pc, 0x41);
+    }
+
+    #[test]
+    fn test_unmapped_access() {
+        // LD A, (0xA000) without cartridge RAM
+        let mut cpu = cpu_with_program(&[0xFA, 0x00, 0xA0]);
+
+        assert_eq!(
+            cpu.execute_next_instruction(),
+            Err(EmulationError::UnmappedAccess {
+                address: 0xA000,
+                kind: AccessKind::Read
+            })
+        );
+
+        let mut cpu = cpu_with_program(&[0xFA, 0x00, 0xA0]);
+        cpu.mode = EmulationMode::HardwareAccurate;
+
+        assert!(cpu.execute_next_instruction().is_ok());
+        assert_eq!(cpu.registers.a, 0xFF);
+
+        // Regions real hardware ignores are fine even when strict, like
+        // LD A, (0xFEA0) and LDH (0x7F), A
+        let mut cpu = cpu_with_program(&[0xFA, 0xA0, 0xFE, 0xE0, 0x7F]);
+        assert!(cpu.execute_next_instruction().is_ok());
+        assert_eq!(cpu.registers.a, 0xFF);
+        assert!(cpu.execute_next_instruction().is_ok());
     }
 }
@@ -0,0 +1,71 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, Error};
+
+/// Describes what the CPU was doing when it touched an unmapped address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+impl Display for AccessKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccessKind::Read => write!(f, "read"),
+            AccessKind::Write => write!(f, "write"),
+        }
+    }
+}
+
+/// Errors that stop emulation of the current ROM without taking down the
+/// rest of the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmulationError {
+    /// One of the 11 unused opcodes (0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB,
+    /// 0xEC, 0xED, 0xF4, 0xFC, 0xFD) was executed
+    IllegalOpcode { pc: u16, opcode: u8 },
+
+    /// Nothing on the bus responds to this address
+    UnmappedAccess { address: u16, kind: AccessKind },
+}
+
+impl Display for EmulationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmulationError::IllegalOpcode { pc, opcode } => {
+                write!(f, "Illegal opcode 0x{:02X} at 0x{:04X}", opcode, pc)
+            }
+            EmulationError::UnmappedAccess { address, kind } => {
+                write!(f, "Unmapped {} at 0x{:04X}", kind, address)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmulationError {}
+
+/// Controls how the emulator reacts to things real hardware tolerates, but
+/// which usually mean a ROM (or the emulator) has gone off the rails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmulationMode {
+    /// Illegal opcodes and unmapped accesses stop emulation with an error
+    #[default]
+    Strict,
+
+    /// Illegal opcodes lock up the CPU until it is reset, unmapped reads
+    /// return 0xFF and unmapped writes are ignored
+    HardwareAccurate,
+}
+
+impl FromStr for EmulationMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Ok(EmulationMode::Strict),
+            "accurate" => Ok(EmulationMode::HardwareAccurate),
+            _ => Err(anyhow!("Unknown mode: {}, try strict or accurate", s)),
+        }
+    }
+}
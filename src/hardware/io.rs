@@ -1,9 +1,73 @@
+pub mod hdma;
+pub mod joypad;
 pub mod serial;
 pub mod timer;
 
-use super::Memory;
+use anyhow::Result;
 
-pub struct IO {}
+use super::{
+    model::Model,
+    state::{Snapshot, StateReader, StateWriter},
+    Memory,
+};
+
+// Registers for hardware that isn't emulated yet. They read back whatever
+// was last written, starting from the values the boot ROM leaves behind.
+pub struct IO {
+    registers: [u8; 0x80],
+}
+
+impl IO {
+    pub fn new() -> IO {
+        let mut registers = [0xFF; 0x80];
+
+        // Audio and wave RAM are cleared at power on
+        registers[0x10..=0x26].fill(0x00);
+        registers[0x30..=0x3F].fill(0x00);
+
+        // CGB only registers, which the bus hides on other models
+        registers[0x56] = 0x00;
+        registers[0x72..=0x75].fill(0x00);
+
+        IO { registers }
+    }
+
+    // Sets the registers to the values the model's boot ROM leaves behind
+    pub fn skip_boot_rom(&mut self, model: Model) {
+        // https://gbdev.io/pandocs/Power_Up_Sequence.html#hardware-registers
+        for (address, value) in [
+            // Audio
+            (0xFF10, 0x80),
+            (0xFF11, 0xBF),
+            (0xFF12, 0xF3),
+            (0xFF14, 0xBF),
+            (0xFF16, 0x3F),
+            (0xFF17, 0x00),
+            (0xFF19, 0xBF),
+            (0xFF1A, 0x7F),
+            (0xFF1C, 0x9F),
+            (0xFF1E, 0xBF),
+            (0xFF21, 0x00),
+            (0xFF22, 0x00),
+            (0xFF23, 0xBF),
+            (0xFF24, 0x77),
+            (0xFF25, 0xF3),
+            (0xFF26, if model.is_sgb() { 0xF0 } else { 0xF1 }),
+        ] {
+            self.registers[address - 0xFF00] = value;
+        }
+    }
+
+    /// Returns true if a register the IO block knows about lives at this
+    /// address. The rest of 0xFF00-0xFF7F is either handled by the bus or
+    /// unused, which reads as 0xFF and ignores writes.
+    pub fn is_writable(address: u16) -> bool {
+        matches!(
+            address,
+            0xFF10..=0xFF26 | 0xFF30..=0xFF3F
+        )
+    }
+}
 
 use bitfield_struct::bitfield;
 
@@ -30,39 +94,38 @@ pub struct Interrupts {
     joypad: bool,
 }
 
+impl Default for IO {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Memory for IO {
     fn read(&self, address: u16) -> u8 {
-        match address {
-            0xFF01 => {
-                println!("SB: 0x{:02X}", 0x00);
-                0x00
-            }
-            0xFF02 => {
-                println!("SC: 0x{:02X}", 0x00);
-                0x00
-            }
-            // Hard coded for Gameboy Doctor
-            0xFF44 => 0x90,
-
-            // Hard coded for Gameboy
-            0xFF4D => 0xFF,
-            _ => 0x00,
-        }
+        self.registers[(address - 0xFF00) as usize]
     }
 
     fn write(&mut self, address: u16, value: u8) {
         match address {
-            0xFF00 => println!("JOYP: 0x{:02X}", value),
-
-            0xFF01 => println!("SB: 0x{:02X}", value),
-            0xFF02 => println!("SC: 0x{:02X}", value),
+            // Audio
+            0xFF10..=0xFF26 => self.registers[(address - 0xFF00) as usize] = value,
 
-            // GPU
-            0xFF40..=0xFF4B => {}
+            // Wave pattern RAM
+            0xFF30..=0xFF3F => self.registers[(address - 0xFF00) as usize] = value,
 
-            // Audio
-            0xFF10..=0xFF26 => {}
-            _ => panic!("Invalid address: 0x{:04X}", address),
+            // CGB registers, which the bus only forwards on the CGB
+            0xFF56 | 0xFF72..=0xFF75 => self.registers[(address - 0xFF00) as usize] = value,
+            _ => {}
         }
     }
 }
+
+impl Snapshot for IO {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.registers);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<()> {
+        state.bytes_into(&mut self.registers)
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::Result;
+
+use crate::hardware::{
+    state::{Snapshot, StateReader, StateWriter},
+    Memory,
+};
+
+// CGB VRAM DMA, which copies blocks of 16 bytes into video RAM. A general
+// purpose transfer copies everything at once, an HBlank transfer copies one
+// block at the start of each HBlank.
+// https://gbdev.io/pandocs/CGB_Registers.html#lcd-vram-dma-transfers
+pub struct HDMA {
+    source: u16,
+    destination: u16,
+
+    // Blocks left to copy, minus one, as HDMA5 reports it
+    remaining: u8,
+
+    active: bool,
+    hblank: bool,
+
+    // Blocks due to be copied at the next opportunity
+    pending: u8,
+}
+
+impl HDMA {
+    pub fn new() -> HDMA {
+        HDMA {
+            source: 0x0000,
+            destination: 0x8000,
+            remaining: 0x7F,
+            active: false,
+            hblank: false,
+            pending: 0,
+        }
+    }
+
+    /// Requests the next block of an HBlank transfer
+    pub fn hblank_started(&mut self) {
+        if self.active && self.hblank {
+            self.pending = 1;
+        }
+    }
+
+    /// Drops a requested block. HBlank transfers are paused while the CPU is
+    /// halted.
+    pub fn skip_block(&mut self) {
+        if self.hblank {
+            self.pending = 0;
+        }
+    }
+
+    /// Returns the source and destination of the next block to copy, and
+    /// moves on to the one after it
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        if self.pending == 0 {
+            return None;
+        }
+
+        let block = (self.source, self.destination);
+
+        self.pending -= 1;
+        self.source = self.source.wrapping_add(0x10);
+        self.destination += 0x10;
+        self.remaining = self.remaining.wrapping_sub(1);
+
+        // The transfer ends early if the destination leaves video RAM
+        if self.remaining == 0xFF || self.destination > 0x9FFF {
+            self.active = false;
+            self.pending = 0;
+            self.destination = 0x8000 | (self.destination & 0x1FF0);
+        }
+
+        Some(block)
+    }
+
+    /// Starts a transfer or stops an HBlank transfer. HBlank transfers
+    /// started during HBlank copy their first block straight away.
+    pub fn start(&mut self, value: u8, in_hblank: bool) {
+        if self.active && value & 0x80 == 0 {
+            self.active = false;
+            self.pending = 0;
+            return;
+        }
+
+        self.remaining = value & 0x7F;
+        self.active = true;
+        self.hblank = value & 0x80 != 0;
+        self.pending = match self.hblank {
+            true => in_hblank as u8,
+            false => self.remaining + 1,
+        };
+    }
+}
+
+impl Default for HDMA {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for HDMA {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            // Bit 7 is clear while a transfer is running
+            0xFF55 => ((!self.active as u8) << 7) | self.remaining,
+
+            // The address registers are write only
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF51 => self.source = (self.source & 0x00FF) | ((value as u16) << 8),
+            0xFF52 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            0xFF53 => {
+                self.destination =
+                    0x8000 | (self.destination & 0x00FF) | (((value & 0x1F) as u16) << 8)
+            }
+            0xFF54 => self.destination = (self.destination & 0xFF00) | (value & 0xF0) as u16,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Snapshot for HDMA {
+    fn save(&self, state: &mut StateWriter) {
+        state.u16(self.source);
+        state.u16(self.destination);
+        state.u8(self.remaining);
+        state.bool(self.active);
+        state.bool(self.hblank);
+        state.u8(self.pending);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<()> {
+        self.source = state.u16()?;
+        self.destination = state.u16()?;
+        self.remaining = state.u8()?;
+        self.active = state.bool()?;
+        self.hblank = state.bool()?;
+        self.pending = state.u8()?;
+
+        Ok(())
+    }
+}
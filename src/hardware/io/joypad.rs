@@ -0,0 +1,135 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+
+use crate::hardware::{
+    state::{Snapshot, StateReader, StateWriter},
+    Memory,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+}
+
+impl FromStr for Button {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "right" => Ok(Button::Right),
+            "left" => Ok(Button::Left),
+            "up" => Ok(Button::Up),
+            "down" => Ok(Button::Down),
+            "a" => Ok(Button::A),
+            "b" => Ok(Button::B),
+            "select" => Ok(Button::Select),
+            "start" => Ok(Button::Start),
+            _ => Err(anyhow!("Unknown button: {}", s)),
+        }
+    }
+}
+
+// https://gbdev.io/pandocs/Joypad_Input.html
+pub struct Joypad {
+    // Bits 4 and 5 of P1, which select the d-pad and/or the buttons. A
+    // cleared bit selects that group.
+    select: u8,
+
+    // Currently pressed buttons, one bit per Button
+    pressed: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: 0x30,
+            pressed: 0,
+        }
+    }
+
+    // Updates the state of a button, returning true if one of the input
+    // lines went low, which requests the joypad interrupt
+    pub fn set_pressed(&mut self, button: Button, pressed: bool) -> bool {
+        let before = self.lines();
+
+        if pressed {
+            self.pressed |= 1 << button as u8;
+        } else {
+            self.pressed &= !(1 << button as u8);
+        }
+
+        before & !self.lines() != 0
+    }
+
+    /// Returns true if any of the selected input lines are low
+    pub fn any_line_low(&self) -> bool {
+        self.lines() != 0x0F
+    }
+
+    // The low nibble of P1, where a pressed button in a selected group
+    // pulls its line low
+    fn lines(&self) -> u8 {
+        let mut lines = 0x0F;
+
+        if self.select & 0x10 == 0 {
+            lines &= !(self.pressed & 0x0F);
+        }
+
+        if self.select & 0x20 == 0 {
+            lines &= !(self.pressed >> 4);
+        }
+
+        lines
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for Joypad {
+    fn read(&self, _address: u16) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    fn write(&mut self, _address: u16, value: u8) {
+        self.select = value & 0x30;
+    }
+}
+
+impl Snapshot for Joypad {
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.select);
+        state.u8(self.pressed);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<()> {
+        self.select = state.u8()?;
+        self.pressed = state.u8()?;
+
+        Ok(())
+    }
+}
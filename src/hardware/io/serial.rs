@@ -1,8 +1,16 @@
-use crate::hardware::Memory;
+use anyhow::Result;
+
+use crate::hardware::{
+    state::{Snapshot, StateReader, StateWriter},
+    Memory,
+};
 
 pub struct Serial {
     pub data: u8,
     pub control: u8,
+
+    // Everything the ROM has sent so far, used by the test ROMs to report
+    pub output: Vec<u8>,
 }
 
 impl Serial {
@@ -10,10 +18,17 @@ impl Serial {
         Serial {
             data: 0x00,
             control: 0x00,
+            output: Vec::new(),
         }
     }
 }
 
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Memory for Serial {
     fn read(&self, address: u16) -> u8 {
         match address {
@@ -31,6 +46,7 @@ impl Memory for Serial {
             0xFF02 => {
                 if value == 0x81 {
                     print!("{}", self.data as char);
+                    self.output.push(self.data);
                 }
                 self.control = value;
             }
@@ -39,3 +55,18 @@ impl Memory for Serial {
         }
     }
 }
+
+// What was sent before is kept, it isn't part of the hardware
+impl Snapshot for Serial {
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.data);
+        state.u8(self.control);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<()> {
+        self.data = state.u8()?;
+        self.control = state.u8()?;
+
+        Ok(())
+    }
+}
@@ -1,21 +1,127 @@
-use crate::hardware::Memory;
+use anyhow::Result;
 
-pub struct Timer {}
+use crate::hardware::{
+    state::{Snapshot, StateReader, StateWriter},
+    Memory,
+};
+
+// https://gbdev.io/pandocs/Timer_and_Divider_Registers.html
+pub struct Timer {
+    // DIV is the upper 8 bits of this counter, which increments every clock
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+
+    // Set when TIMA overflows outside of a tick, such as after a DIV write
+    overflowed: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflowed: false,
+        }
+    }
+
+    // Sets the internal counter, whose upper byte is DIV
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+    }
+
+    // Advances the timer by one machine cycle, returning true if the timer
+    // interrupt should be requested
+    pub fn tick(&mut self) -> bool {
+        let before = self.timer_bit();
+        self.counter = self.counter.wrapping_add(4);
+        self.detect_falling_edge(before);
+
+        std::mem::take(&mut self.overflowed)
+    }
+
+    // TIMA increments whenever the selected counter bit goes from high to low,
+    // which is ANDed with the enable bit
+    fn timer_bit(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0x00 => 9,
+            0x01 => 3,
+            0x02 => 5,
+            _ => 7,
+        };
+
+        self.tac & 0x04 != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn detect_falling_edge(&mut self, before: bool) {
+        if !before || self.timer_bit() {
+            return;
+        }
+
+        let (tima, overflow) = self.tima.overflowing_add(1);
+
+        if overflow {
+            self.tima = self.tma;
+            self.overflowed = true;
+        } else {
+            self.tima = tima;
+        }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Memory for Timer {
     fn read(&self, address: u16) -> u8 {
         match address {
-            _ => 0x00,
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => self.tac | 0xF8,
+            _ => unreachable!(),
         }
     }
 
-    fn write(&mut self, address: u16, _value: u8) {
+    fn write(&mut self, address: u16, value: u8) {
+        let before = self.timer_bit();
+
         match address {
-            0xFF04 => (),
-            0xFF05 => (),
-            0xFF06 => (),
-            0xFF07 => (),
+            // Any write resets the whole counter
+            0xFF04 => self.counter = 0,
+            0xFF05 => self.tima = value,
+            0xFF06 => self.tma = value,
+            0xFF07 => self.tac = value & 0x07,
             _ => unreachable!(),
         }
+
+        // Resetting DIV or changing TAC can cause a falling edge
+        self.detect_falling_edge(before);
+    }
+}
+
+impl Snapshot for Timer {
+    fn save(&self, state: &mut StateWriter) {
+        state.u16(self.counter);
+        state.u8(self.tima);
+        state.u8(self.tma);
+        state.u8(self.tac);
+        state.bool(self.overflowed);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<()> {
+        self.counter = state.u16()?;
+        self.tima = state.u8()?;
+        self.tma = state.u8()?;
+        self.tac = state.u8()?;
+        self.overflowed = state.bool()?;
+
+        Ok(())
     }
 }
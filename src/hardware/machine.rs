@@ -0,0 +1,310 @@
+use std::{fs::File, io::BufWriter};
+
+use anyhow::{bail, Result};
+
+use super::{
+    bus::Bus,
+    cpu::CPU,
+    error::EmulationError,
+    ppu::{SCREEN_HEIGHT, SCREEN_WIDTH},
+    sgb::{SGB_HEIGHT, SGB_WIDTH},
+    state::{Snapshot, StateReader, StateWriter},
+};
+
+// A frame is 70224 dots, 4 to a machine cycle in normal speed
+const CYCLES_PER_FRAME: u32 = 70224 / 4;
+
+// The whole Game Boy, run a frame at a time
+pub struct Machine {
+    pub cpu: CPU,
+
+    // Frames and machine cycles run so far
+    frames: u64,
+    cycles: u64,
+
+    // Machine cycles into the current frame
+    frame_cycles: u32,
+}
+
+impl Machine {
+    pub fn new(bus: Bus) -> Machine {
+        Machine {
+            cpu: CPU::new(bus),
+            frames: 0,
+            cycles: 0,
+            frame_cycles: 0,
+        }
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Runs a single instruction, returning the machine cycles it took. A
+    /// frame is counted once the PPU finishes one, or with the LCD off, once
+    /// as long as a frame would take has passed.
+    pub fn step(&mut self) -> Result<u8, EmulationError> {
+        let ppu_frames = self.cpu.bus.ppu().frames();
+        let cycles = self.cpu.execute_next_instruction()?;
+        self.cycles += cycles as u64;
+        self.frame_cycles += cycles as u32;
+
+        let cycles_per_frame = match self.cpu.bus.double_speed {
+            true => CYCLES_PER_FRAME * 2,
+            false => CYCLES_PER_FRAME,
+        };
+
+        if self.cpu.bus.ppu().frames() != ppu_frames || self.frame_cycles >= cycles_per_frame {
+            self.frames += 1;
+            self.frame_cycles = 0;
+        }
+
+        Ok(cycles)
+    }
+
+    /// Runs until the end of the frame
+    pub fn run_frame(&mut self) -> Result<(), EmulationError> {
+        self.run_frame_with(|_| {})
+    }
+
+    /// Runs a frame like `run_frame`, calling `before_step` before each
+    /// instruction
+    pub fn run_frame_with(
+        &mut self,
+        mut before_step: impl FnMut(&mut Machine),
+    ) -> Result<(), EmulationError> {
+        let start = self.frames;
+
+        while self.frames == start {
+            before_step(self);
+            self.step()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the size of the picture, which includes the border on the SGB
+    pub fn screen_size(&self) -> (usize, usize) {
+        match self.cpu.bus.sgb() {
+            Some(_) => (SGB_WIDTH, SGB_HEIGHT),
+            None => (SCREEN_WIDTH, SCREEN_HEIGHT),
+        }
+    }
+
+    /// Returns the last completed frame as 8-bit RGB triplets
+    pub fn frame_rgb(&self) -> Vec<u8> {
+        let ppu = self.cpu.bus.ppu();
+
+        match self.cpu.bus.sgb() {
+            Some(sgb) => sgb.frame_rgb(ppu.framebuffer()),
+            None => ppu.frame_rgb(),
+        }
+    }
+
+    /// Writes the last completed frame to a PNG file
+    pub fn save_screenshot(&self, path: &str) -> Result<()> {
+        self.save_screenshot_scaled(path, 1)
+    }
+
+    /// Writes the last completed frame to a PNG file, with each pixel scaled
+    /// up to a square of `scale` pixels
+    pub fn save_screenshot_scaled(&self, path: &str, scale: usize) -> Result<()> {
+        if scale == 0 {
+            bail!("Screenshots can't be scaled by 0");
+        }
+
+        let (width, height) = self.screen_size();
+        let pixels = upscale(&self.frame_rgb(), width, scale);
+
+        write_png(path, width * scale, height * scale, &pixels)
+    }
+
+    /// Returns a snapshot of the whole machine, which `load_state` restores
+    pub fn save_state(&self) -> Vec<u8> {
+        let bus = &self.cpu.bus;
+        let mut state = StateWriter::new(bus.model, bus.cartridge());
+
+        state.u64(self.frames);
+        state.u64(self.cycles);
+        state.u32(self.frame_cycles);
+        self.cpu.save(&mut state);
+
+        state.finish()
+    }
+
+    /// Restores a snapshot from `save_state`. States from another version,
+    /// model or ROM are refused, and leave the machine as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<()> {
+        let backup = self.save_state();
+
+        self.restore(data).inspect_err(|_| {
+            self.restore(&backup)
+                .expect("The machine's own state should always load")
+        })
+    }
+
+    fn restore(&mut self, data: &[u8]) -> Result<()> {
+        let bus = &self.cpu.bus;
+        let mut state = StateReader::new(data, bus.model, bus.cartridge())?;
+
+        self.frames = state.u64()?;
+        self.cycles = state.u64()?;
+        self.frame_cycles = state.u32()?;
+        self.cpu.load(&mut state)?;
+
+        state.finish()
+    }
+}
+
+/// Writes 8-bit RGB pixels to a PNG file
+pub fn write_png(path: &str, width: usize, height: usize, pixels: &[u8]) -> Result<()> {
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(path)?),
+        width as u32,
+        height as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+
+    Ok(())
+}
+
+// Scales RGB pixels up by repeating each one, and each row
+fn upscale(pixels: &[u8], width: usize, scale: usize) -> Vec<u8> {
+    if scale == 1 {
+        return pixels.to_vec();
+    }
+
+    let mut scaled = Vec::with_capacity(pixels.len() * scale * scale);
+
+    for row in pixels.chunks_exact(width * 3) {
+        let start = scaled.len();
+
+        for pixel in row.chunks_exact(3) {
+            for _ in 0..scale {
+                scaled.extend_from_slice(pixel);
+            }
+        }
+
+        let scaled_row = scaled[start..].to_vec();
+
+        for _ in 1..scale {
+            scaled.extend_from_slice(&scaled_row);
+        }
+    }
+
+    scaled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::{cartridge::Cartridge, model::Model};
+
+    fn machine(model: Model) -> Machine {
+        // Loops forever with the boot ROM's picture on screen
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0102].copy_from_slice(&[0x18, 0xFE]);
+
+        Machine::new(Bus::new(Cartridge::new(rom), model))
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut machine = machine(Model::DMG);
+        machine.run_frame().unwrap();
+        machine.run_frame().unwrap();
+
+        assert_eq!(machine.frames(), 2);
+        assert_eq!(machine.cpu.bus.ppu().frames(), 2);
+    }
+
+    // Scribbles over VRAM and scrolls the background, while timer interrupts
+    // count up in B
+    fn busy_machine(model: Model) -> Machine {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0050..0x0052].copy_from_slice(&[0x04, 0xD9]);
+        rom[0x0100..0x0103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x0150..0x0164].copy_from_slice(&[
+            0x3E, 0x05, 0xE0, 0x07, 0x3E, 0x04, 0xE0, 0xFF, 0xFB, 0x21, 0x00, 0x80, 0x3C, 0x22,
+            0xE0, 0x43, 0xCB, 0xAC, 0x18, 0xF8,
+        ]);
+
+        Machine::new(Bus::new(Cartridge::new(rom), model))
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        for model in [Model::DMG, Model::SGB, Model::CGB] {
+            let mut original = busy_machine(model);
+            for _ in 0..3 {
+                original.run_frame().unwrap();
+            }
+
+            let state = original.save_state();
+            for _ in 0..5 {
+                original.run_frame().unwrap();
+            }
+
+            let mut restored = busy_machine(model);
+            restored.load_state(&state).unwrap();
+            assert_eq!(restored.save_state(), state);
+
+            for _ in 0..5 {
+                restored.run_frame().unwrap();
+            }
+
+            assert_eq!(restored.save_state(), original.save_state());
+            assert_eq!(restored.frame_rgb(), original.frame_rgb());
+            assert_ne!(restored.cpu.registers.b, 0);
+        }
+    }
+
+    #[test]
+    fn test_load_state_failure() {
+        let mut machine = busy_machine(Model::DMG);
+        machine.run_frame().unwrap();
+        let state = machine.save_state();
+
+        machine.run_frame().unwrap();
+        let before = machine.save_state();
+
+        assert!(machine.load_state(&state[..state.len() - 1]).is_err());
+        assert!(busy_machine(Model::CGB).load_state(&state).is_err());
+        assert_eq!(machine.save_state(), before);
+    }
+
+    #[test]
+    fn test_upscale() {
+        let pixels = [1, 1, 1, 2, 2, 2];
+
+        assert_eq!(
+            upscale(&pixels, 2, 2),
+            [1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2]
+        );
+    }
+
+    #[test]
+    fn test_save_screenshot() {
+        let path = std::env::temp_dir().join("gb-hinder-screenshot.png");
+        let path = path.to_str().unwrap();
+
+        let mut machine = machine(Model::SGB);
+        machine.run_frame().unwrap();
+        machine.save_screenshot_scaled(path, 2).unwrap();
+
+        let decoder = png::Decoder::new(File::open(path).unwrap());
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().width, 512);
+        assert_eq!(reader.info().height, 448);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
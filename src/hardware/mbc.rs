@@ -1,4 +1,10 @@
-use super::{cartridge::Cartridge, Memory};
+use anyhow::Result;
+
+use super::{
+    cartridge::Cartridge,
+    state::{Snapshot, StateReader, StateWriter},
+    Memory,
+};
 
 enum BankMode {
     ROM,
@@ -17,6 +23,7 @@ impl From<u8> for BankMode {
 
 pub struct MBC1 {
     cartridge: Cartridge,
+    ram: Vec<u8>,
     ram_enabled: bool,
     rom_bank: u8,
     ram_bank: u8,
@@ -25,17 +32,50 @@ pub struct MBC1 {
 
 impl MBC1 {
     pub fn new(cartridge: Cartridge) -> MBC1 {
+        let (banks, bank_size) = cartridge.ram_banks();
+
         MBC1 {
             cartridge,
+            ram: vec![0; banks * bank_size],
             ram_enabled: false,
             rom_bank: 1,
-            ram_bank: 1,
+            ram_bank: 0,
             bank_mode: BankMode::ROM,
         }
     }
 
     pub fn set_rom_bank(&mut self, bank: u8) {
-        self.rom_bank = bank;
+        // Bank numbers wrap around based on the number of banks in the ROM
+        let mask = self.rom_banks().next_power_of_two() - 1;
+
+        self.rom_bank = (bank as usize & mask) as u8;
+    }
+
+    /// Returns the bank mapped at 0x4000-0x7FFF
+    pub fn rom_bank(&self) -> usize {
+        self.rom_bank as usize % self.rom_banks()
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    /// Returns true if the cartridge has RAM mapped at 0xA000-0xBFFF
+    pub fn has_ram(&self) -> bool {
+        !self.ram.is_empty()
+    }
+
+    fn rom_banks(&self) -> usize {
+        self.cartridge.rom.len() / 0x4000
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let bank = match self.bank_mode {
+            BankMode::ROM => 0,
+            BankMode::RAM => self.ram_bank as usize,
+        };
+
+        ((bank * 0x2000) + (address - 0xA000) as usize) % self.ram.len()
     }
 }
 
@@ -47,14 +87,17 @@ impl Memory for MBC1 {
 
             // Cartridge ROM
             0x4000..=0x7FFF => {
-                let bank = self.rom_bank as usize;
+                let bank = self.rom_bank();
                 let offset = (address - 0x4000) as usize;
                 self.cartridge.rom[(bank * 0x4000) + offset]
             }
 
-            // Cartridge RAM
-            0xA000..=0xBFFF => unimplemented!(),
-            _ => panic!("Invalid address: 0x{:04X}", address),
+            // Cartridge RAM, which reads as an open bus while disabled
+            0xA000..=0xBFFF if self.ram_enabled && self.has_ram() => {
+                self.ram[self.ram_offset(address)]
+            }
+
+            _ => 0xFF,
         }
     }
 
@@ -69,9 +112,12 @@ impl Memory for MBC1 {
                 let current_bank = self.rom_bank & !0x1F;
 
                 // Writing a 0 to this register will actually set the bank to 1
-                let value = if value == 0x00 { 0x01 } else { value };
+                let value = match value & 0x1F {
+                    0x00 => 0x01,
+                    value => value,
+                };
 
-                self.set_rom_bank(current_bank | (value & 0x1F));
+                self.set_rom_bank(current_bank | value);
             }
 
             // RAM Bank Number - or - Upper Bits of ROM Bank Number
@@ -92,32 +138,63 @@ impl Memory for MBC1 {
             0x6000..=0x7FFF => self.bank_mode = value.into(),
 
             // Cartridge RAM
-            0xA000..=0xBFFF => unimplemented!(),
+            0xA000..=0xBFFF if self.ram_enabled && self.has_ram() => {
+                let offset = self.ram_offset(address);
+                self.ram[offset] = value;
+            }
 
-            _ => panic!("Invalid address: 0x{:04X}", address),
+            _ => {}
         }
     }
 }
 
+impl Snapshot for MBC1 {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.ram);
+        state.bool(self.ram_enabled);
+        state.u8(self.rom_bank);
+        state.u8(self.ram_bank);
+        state.u8(matches!(self.bank_mode, BankMode::RAM) as u8);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<()> {
+        state.bytes_into(&mut self.ram)?;
+        self.ram_enabled = state.bool()?;
+        self.rom_bank = state.u8()?;
+        self.ram_bank = state.u8()?;
+        self.bank_mode = state.u8()?.into();
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // A 64 KiB MBC1 cartridge, the same shape as cpu_instrs.gb
+    fn cartridge(ram_size: u8) -> Cartridge {
+        let mut rom = vec![0; 0x10000];
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x01;
+        rom[0x0149] = ram_size;
+
+        Cartridge::new(rom)
+    }
+
     #[test]
     fn test_write_byte_ram_enabled() {
-        let cartridge = Cartridge::from_path("priv/cpu_instrs.gb").unwrap();
-        let mut mbc = MBC1::new(cartridge);
+        let mut mbc = MBC1::new(cartridge(0x00));
         mbc.write(0x0000, 0x0A);
-        assert_eq!(mbc.ram_enabled, true);
+        assert!(mbc.ram_enabled);
 
         mbc.write(0x0000, 0x00);
-        assert_eq!(mbc.ram_enabled, false);
+        assert!(!mbc.ram_enabled);
     }
 
     #[test]
     fn test_write_byte_rom_bank_number() {
-        let cartridge = Cartridge::from_path("priv/cpu_instrs.gb").unwrap();
-        let mut mbc = MBC1::new(cartridge);
+        let mut mbc = MBC1::new(cartridge(0x00));
         mbc.write(0x2000, 0x01);
         assert_eq!(mbc.rom_bank, 1);
 
@@ -130,4 +207,20 @@ mod tests {
         mbc.write(0x2000, 0x00);
         assert_eq!(mbc.rom_bank as usize, 1);
     }
+
+    #[test]
+    fn test_cartridge_ram() {
+        let mut mbc = MBC1::new(cartridge(0x02));
+        assert!(mbc.has_ram());
+
+        // Disabled RAM ignores writes and reads as an open bus
+        mbc.write(0xA000, 0x42);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0xA000, 0x42);
+        assert_eq!(mbc.read(0xA000), 0x42);
+
+        assert!(!MBC1::new(cartridge(0x00)).has_ram());
+    }
 }
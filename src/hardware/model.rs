@@ -0,0 +1,173 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+
+use super::{cartridge::Cartridge, registers::Registers};
+
+// https://gbdev.io/pandocs/Power_Up_Sequence.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Model {
+    /// Original Game Boy with the early boot ROM
+    DMG0,
+
+    /// Original Game Boy
+    #[default]
+    DMG,
+
+    /// Game Boy Pocket and Game Boy Light
+    MGB,
+
+    /// Super Game Boy
+    SGB,
+
+    /// Super Game Boy 2
+    SGB2,
+
+    /// Game Boy Color
+    CGB,
+
+    /// Game Boy Advance, running Game Boy software
+    AGB,
+}
+
+impl Model {
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::CGB | Model::AGB)
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::SGB | Model::SGB2)
+    }
+
+    /// Returns the registers as the boot ROM leaves them, which games use to
+    /// detect the hardware they're running on
+    pub fn initial_registers(&self, cartridge: &Cartridge) -> Registers {
+        let mut registers = Registers::new();
+
+        // Zero and the carries are set by the boot ROM's last comparisons
+        let (a, f, bc, de, hl) = match self {
+            Model::DMG0 => (0x01, 0x00, 0xFF13, 0x00C1, 0x8403),
+            Model::DMG | Model::MGB => {
+                // H and C are set unless the header checksum is zero
+                let f = if cartridge.rom[0x014D] == 0 {
+                    0x80
+                } else {
+                    0xB0
+                };
+                let a = if *self == Model::DMG { 0x01 } else { 0xFF };
+
+                (a, f, 0x0013, 0x00D8, 0x014D)
+            }
+            Model::SGB => (0x01, 0x00, 0x0014, 0x0000, 0xC060),
+            Model::SGB2 => (0xFF, 0x00, 0x0014, 0x0000, 0xC060),
+            Model::CGB | Model::AGB if cartridge.supports_cgb() => {
+                (0x11, 0x80, 0x0000, 0xFF56, 0x000D)
+            }
+            Model::CGB | Model::AGB => {
+                // The compatibility palette lookup leaves the title checksum
+                // in B for Nintendo published games
+                let b = if cartridge.is_licensed_by_nintendo() {
+                    cartridge.title_checksum()
+                } else {
+                    0x00
+                };
+                let hl = if b == 0x43 || b == 0x58 {
+                    0x991A
+                } else {
+                    0x007C
+                };
+
+                (0x11, 0x80, (b as u16) << 8, 0x0008, hl)
+            }
+        };
+
+        registers.a = a;
+        registers.f = f.into();
+        registers.set_bc(bc);
+        registers.set_de(de);
+        registers.set_hl(hl);
+
+        // The GBA boot ROM runs an extra INC B, which is how games tell it
+        // apart from a CGB
+        if *self == Model::AGB {
+            registers.b = registers.b.wrapping_add(1);
+            registers.f.set_zero(registers.b == 0);
+            registers.f.set_subtract(false);
+            registers.f.set_half_carry(registers.b & 0x0F == 0);
+        }
+
+        registers
+    }
+
+    /// Returns the internal divider counter at the point the boot ROM hands
+    /// over to the cartridge. DIV is the upper byte.
+    pub fn initial_div_counter(&self) -> u16 {
+        match self {
+            Model::DMG0 => 0x1830,
+            Model::DMG | Model::MGB => 0xABCC,
+            Model::SGB | Model::SGB2 => 0xD85C,
+            Model::CGB | Model::AGB => 0x1EA0,
+        }
+    }
+}
+
+impl FromStr for Model {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "DMG0" => Ok(Model::DMG0),
+            "DMG" => Ok(Model::DMG),
+            "MGB" => Ok(Model::MGB),
+            "SGB" => Ok(Model::SGB),
+            "SGB2" => Ok(Model::SGB2),
+            "CGB" => Ok(Model::CGB),
+            "AGB" => Ok(Model::AGB),
+            _ => Err(anyhow!("Unknown model: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cartridge(cgb_flag: u8, header_checksum: u8) -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0143] = cgb_flag;
+        rom[0x014D] = header_checksum;
+
+        Cartridge::new(rom)
+    }
+
+    #[test]
+    fn test_dmg_registers() {
+        let registers = Model::DMG.initial_registers(&cartridge(0x00, 0x42));
+        assert_eq!(registers.af(), 0x01B0);
+        assert_eq!(registers.bc(), 0x0013);
+        assert_eq!(registers.de(), 0x00D8);
+        assert_eq!(registers.hl(), 0x014D);
+
+        let registers = Model::DMG.initial_registers(&cartridge(0x00, 0x00));
+        assert_eq!(registers.af(), 0x0180);
+    }
+
+    #[test]
+    fn test_cgb_registers() {
+        let registers = Model::CGB.initial_registers(&cartridge(0x80, 0x00));
+        assert_eq!(registers.af(), 0x1180);
+        assert_eq!(registers.de(), 0xFF56);
+        assert_eq!(registers.hl(), 0x000D);
+
+        // Games tell the GBA apart from the CGB by B
+        let registers = Model::AGB.initial_registers(&cartridge(0x80, 0x00));
+        assert_eq!(registers.a, 0x11);
+        assert_eq!(registers.b, 0x01);
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("cgb".parse::<Model>().unwrap(), Model::CGB);
+        assert!("gba".parse::<Model>().is_err());
+    }
+}
@@ -0,0 +1,364 @@
+use std::fs;
+
+use anyhow::{bail, Context, Result};
+
+use super::{
+    cartridge::Cartridge,
+    error::EmulationError,
+    io::joypad::Button,
+    machine::Machine,
+    model::Model,
+    state::{Format, StateReader, StateWriter},
+};
+
+const MOVIE: Format = Format {
+    magic: b"GBHM",
+    version: 1,
+    name: "Movie",
+};
+
+/// A joypad change, stamped with the machine cycle it happened on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Input {
+    pub frame: u64,
+    pub cycle: u64,
+    pub button: Button,
+    pub pressed: bool,
+}
+
+/// A recorded session: the state it started from and every joypad change
+/// after it. Hashes of the state along the way show whether a replay still
+/// matches the recording.
+pub struct Movie {
+    model: Model,
+    start: Vec<u8>,
+    inputs: Vec<Input>,
+    end_frame: u64,
+
+    // Frames between hashes, 0 if there are none
+    hash_interval: u64,
+    hashes: Vec<(u64, [u8; 16])>,
+}
+
+impl Movie {
+    pub fn inputs(&self) -> &[Input] {
+        &self.inputs
+    }
+
+    /// Returns the frame the recording stopped at
+    pub fn end_frame(&self) -> u64 {
+        self.end_frame
+    }
+
+    pub fn to_bytes(&self, cartridge: &Cartridge) -> Vec<u8> {
+        let mut movie = StateWriter::with_format(&MOVIE, self.model, cartridge);
+
+        movie.bytes(&self.start);
+        movie.u64(self.end_frame);
+
+        movie.u32(self.inputs.len() as u32);
+        for input in &self.inputs {
+            movie.u64(input.frame);
+            movie.u64(input.cycle);
+            movie.u8(input.button as u8);
+            movie.bool(input.pressed);
+        }
+
+        movie.u64(self.hash_interval);
+        movie.u32(self.hashes.len() as u32);
+        for (frame, hash) in &self.hashes {
+            movie.u64(*frame);
+            movie.bytes(hash);
+        }
+
+        movie.finish()
+    }
+
+    /// Reads a movie recorded on this model, with this ROM
+    pub fn from_bytes(data: &[u8], model: Model, cartridge: &Cartridge) -> Result<Movie> {
+        let mut movie = StateReader::with_format(data, &MOVIE, model, cartridge)?;
+
+        let start = movie.bytes()?;
+        let end_frame = movie.u64()?;
+
+        let inputs = (0..movie.u32()?)
+            .map(|_| {
+                let frame = movie.u64()?;
+                let cycle = movie.u64()?;
+                let button = movie.u8()?;
+                let Some(&button) = Button::ALL.get(button as usize) else {
+                    bail!("Movie has an invalid button: {}", button);
+                };
+
+                Ok(Input {
+                    frame,
+                    cycle,
+                    button,
+                    pressed: movie.bool()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let hash_interval = movie.u64()?;
+        let hashes = (0..movie.u32()?)
+            .map(|_| {
+                let frame = movie.u64()?;
+                let mut hash = [0; 16];
+                movie.bytes_into(&mut hash)?;
+
+                Ok((frame, hash))
+            })
+            .collect::<Result<_>>()?;
+
+        movie.finish()?;
+
+        Ok(Movie {
+            model,
+            start,
+            inputs,
+            end_frame,
+            hash_interval,
+            hashes,
+        })
+    }
+
+    pub fn from_path(path: &str, model: Model, cartridge: &Cartridge) -> Result<Movie> {
+        let data = fs::read(path)?;
+
+        Movie::from_bytes(&data, model, cartridge).with_context(|| format!("Can't load {}", path))
+    }
+
+    pub fn save(&self, path: &str, cartridge: &Cartridge) -> Result<()> {
+        fs::write(path, self.to_bytes(cartridge))?;
+
+        Ok(())
+    }
+}
+
+/// Records a movie. Joypad changes have to go through the recorder, and so
+/// do frames, for the hashes.
+pub struct Recorder {
+    movie: Movie,
+}
+
+impl Recorder {
+    /// Starts recording from the machine's current state, hashing it every
+    /// `hash_interval` frames, or never if it's 0
+    pub fn start(machine: &Machine, hash_interval: u64) -> Recorder {
+        Recorder {
+            movie: Movie {
+                model: machine.cpu.bus.model,
+                start: machine.save_state(),
+                inputs: Vec::new(),
+                end_frame: machine.frames(),
+                hash_interval,
+                hashes: Vec::new(),
+            },
+        }
+    }
+
+    pub fn set_button(&mut self, machine: &mut Machine, button: Button, pressed: bool) {
+        machine.cpu.bus.set_button(button, pressed);
+
+        self.movie.inputs.push(Input {
+            frame: machine.frames(),
+            cycle: machine.cycles(),
+            button,
+            pressed,
+        });
+    }
+
+    pub fn run_frame(&mut self, machine: &mut Machine) -> Result<(), EmulationError> {
+        machine.run_frame()?;
+        self.frame_ended(machine);
+
+        Ok(())
+    }
+
+    /// Runs a single instruction, for recording one step at a time
+    pub fn step(&mut self, machine: &mut Machine) -> Result<u8, EmulationError> {
+        let frames = machine.frames();
+        let cycles = machine.step()?;
+
+        if machine.frames() != frames {
+            self.frame_ended(machine);
+        }
+
+        Ok(cycles)
+    }
+
+    fn frame_ended(&mut self, machine: &Machine) {
+        let frame = machine.frames();
+        self.movie.end_frame = frame;
+
+        if self.movie.hash_interval != 0 && frame.is_multiple_of(self.movie.hash_interval) {
+            self.movie.hashes.push((frame, state_hash(machine)));
+        }
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Plays a movie back, pressing its buttons on the same cycles they were
+/// pressed on when it was recorded
+pub struct Player {
+    movie: Movie,
+    next_input: usize,
+    next_hash: usize,
+
+    // The first frame whose hash didn't match the recording
+    divergence: Option<u64>,
+}
+
+impl Player {
+    /// Puts the machine in the state the movie starts from
+    pub fn start(movie: Movie, machine: &mut Machine) -> Result<Player> {
+        machine
+            .load_state(&movie.start)
+            .context("Can't load the movie's starting state")?;
+
+        Ok(Player {
+            movie,
+            next_input: 0,
+            next_hash: 0,
+            divergence: None,
+        })
+    }
+
+    /// Returns true once the machine has reached the end of the movie
+    pub fn finished(&self, machine: &Machine) -> bool {
+        machine.frames() >= self.movie.end_frame
+    }
+
+    /// Returns the first frame where the replay didn't match the recording
+    pub fn divergence(&self) -> Option<u64> {
+        self.divergence
+    }
+
+    pub fn run_frame(&mut self, machine: &mut Machine) -> Result<(), EmulationError> {
+        let inputs = &self.movie.inputs;
+        let next_input = &mut self.next_input;
+
+        machine.run_frame_with(|machine| {
+            while let Some(input) = inputs
+                .get(*next_input)
+                .filter(|i| i.cycle <= machine.cycles())
+            {
+                machine.cpu.bus.set_button(input.button, input.pressed);
+                *next_input += 1;
+            }
+        })?;
+
+        let frame = machine.frames();
+
+        while let Some(&(hash_frame, hash)) = self.movie.hashes.get(self.next_hash) {
+            if hash_frame > frame {
+                break;
+            }
+
+            if hash_frame == frame && hash != state_hash(machine) {
+                self.divergence.get_or_insert(frame);
+            }
+
+            self.next_hash += 1;
+        }
+
+        Ok(())
+    }
+}
+
+/// Plays a whole movie, returning the first frame where it diverged
+pub fn replay(movie: Movie, machine: &mut Machine) -> Result<Option<u64>> {
+    let mut player = Player::start(movie, machine)?;
+
+    while !player.finished(machine) {
+        player.run_frame(machine)?;
+    }
+
+    Ok(player.divergence())
+}
+
+fn state_hash(machine: &Machine) -> [u8; 16] {
+    md5::compute(machine.save_state()).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::bus::Bus;
+
+    // Copies the joypad into VRAM and the scroll registers, so every press
+    // changes what's on screen
+    fn cartridge() -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x0150..0x0161].copy_from_slice(&[
+            0x21, 0x00, 0x80, 0x3E, 0x20, 0xE0, 0x00, 0xF0, 0x00, 0x22, 0xE0, 0x43, 0xCB, 0xAC,
+            0x18, 0xF7, 0x00,
+        ]);
+
+        Cartridge::new(rom)
+    }
+
+    fn machine() -> Machine {
+        Machine::new(Bus::new(cartridge(), Model::DMG))
+    }
+
+    fn record() -> Movie {
+        let mut machine = machine();
+        machine.run_frame().unwrap();
+
+        let mut recorder = Recorder::start(&machine, 2);
+        for frame in 0..20 {
+            if frame % 3 == 0 {
+                recorder.set_button(&mut machine, Button::Right, frame % 2 == 0);
+            }
+
+            // Mid-frame presses need the exact cycle to be replayed
+            recorder.step(&mut machine).unwrap();
+            recorder.set_button(&mut machine, Button::Down, frame % 5 == 0);
+
+            recorder.run_frame(&mut machine).unwrap();
+        }
+
+        recorder.finish()
+    }
+
+    #[test]
+    fn test_replay() {
+        let movie = record();
+        let bytes = movie.to_bytes(&cartridge());
+        let movie = Movie::from_bytes(&bytes, Model::DMG, &cartridge()).unwrap();
+        assert_eq!(movie.end_frame(), 21);
+        assert_eq!(movie.inputs().len(), 27);
+
+        let mut machine = machine();
+        assert_eq!(replay(movie, &mut machine).unwrap(), None);
+        assert_eq!(machine.frames(), 21);
+    }
+
+    #[test]
+    fn test_divergence() {
+        let mut movie = record();
+
+        // Holding up from frame 8 changes what the ROM reads, which shows in
+        // the next hash
+        let press = movie.inputs.iter().position(|i| i.frame >= 8).unwrap();
+        movie.inputs[press].button = Button::Up;
+        movie.inputs[press].pressed = true;
+
+        let mut machine = machine();
+        let divergence = replay(movie, &mut machine).unwrap();
+        assert_eq!(divergence, Some(10));
+    }
+
+    #[test]
+    fn test_wrong_rom() {
+        let bytes = record().to_bytes(&cartridge());
+
+        assert!(Movie::from_bytes(&bytes, Model::CGB, &cartridge()).is_err());
+        assert!(Movie::from_bytes(&bytes, Model::DMG, &Cartridge::new(vec![0; 0x8000])).is_err());
+    }
+}
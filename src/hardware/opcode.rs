@@ -11,8 +11,8 @@ mod targets;
 
 use std::fmt::Display;
 
-use self::targets::{Condition, Target, Target16};
-use super::cpu::CPU;
+pub use self::targets::{Condition, Target, Target16};
+use super::{bus::Bus, cpu::CPU, error::EmulationError};
 
 #[derive(Debug, Clone, Copy)]
 pub enum Opcode {
@@ -115,32 +115,239 @@ impl Opcode {
         &OPCODES[byte as usize]
     }
 
+    // Returns the length of the instruction, including its operands
     pub fn byte_count(&self) -> u8 {
         match self {
-            Self::JP(_, Target16::Immediate) => 3,
-            Self::CALL(_) => 3,
+            Self::JP(_, Target16::Immediate) | Self::CALL(_) => 3,
+            Self::LD16(_, Target16::Immediate) | Self::LD16(Target16::MImmediate, _) => 3,
+            Self::LD(Target::MImmediate, _) | Self::LD(_, Target::MImmediate) => 3,
+            Self::JR(_) | Self::LDADD(_) | Self::LDH(_, _) | Self::PrefixCB | Self::STOP => 2,
+            Self::LD(_, Target::Immediate)
+            | Self::ADD(Target::Immediate)
+            | Self::ADC(Target::Immediate)
+            | Self::SUB(Target::Immediate)
+            | Self::SBC(Target::Immediate)
+            | Self::AND(Target::Immediate)
+            | Self::OR(Target::Immediate)
+            | Self::XOR(Target::Immediate)
+            | Self::CP(Target::Immediate) => 2,
+            _ => 1,
+        }
+    }
+
+    /// Formats the instruction at the address with its operands filled in,
+    /// reading them from memory
+    pub fn debug_fmt(&self, bus: &Bus, address: u16) -> String {
+        let operand = address.wrapping_add(1);
+        let offset = bus.peek(operand) as i8;
+
+        // Conditions show as "NZ," but "NC", so the commas are evened out
+        let condition = |condition: &Condition| match condition {
+            Condition::None => String::new(),
+            condition => format!("{}, ", condition.to_string().trim_end_matches(',')),
+        };
+
+        match self {
+            Self::RET(Condition::None) => "RET".to_owned(),
+            Self::RET(c) => format!("RET {}", condition(c).trim_end_matches(", ")),
+            Self::JR(c) => format!(
+                "JR {}{:04X}",
+                condition(c),
+                address.wrapping_add(2).wrapping_add(offset as u16)
+            ),
+            Self::JP(c, target) => format!("JP {}{}", condition(c), target.debug_fmt(bus, operand)),
+            Self::CALL(c) => format!("CALL {}{:04X}", condition(c), bus.peek_word(operand)),
+            Self::RST(vector) => format!("RST {:02X}", vector),
+            Self::LDADD(Target16::SP) => format!("ADD SP, {}", offset),
+            Self::LDADD(target) => format!("LD {}, SP{:+}", target, offset),
+            Self::PrefixCB => bits::CB_OPCODES[bus.peek(operand) as usize].to_string(),
+
+            Self::LD(target, from)
+            | Self::LDD(target, from)
+            | Self::LDH(target, from)
+            | Self::LDI(target, from) => {
+                let mnemonic = self.to_string();
+                let mnemonic = mnemonic.split(' ').next().unwrap();
+
+                format!(
+                    "{} {}, {}",
+                    mnemonic,
+                    target.debug_fmt(bus, operand),
+                    from.debug_fmt(bus, operand)
+                )
+            }
+            Self::LD16(target, from) => format!(
+                "LD {}, {}",
+                target.debug_fmt(bus, operand),
+                from.debug_fmt(bus, operand)
+            ),
+
+            Self::AND(target)
+            | Self::OR(target)
+            | Self::ADD(target)
+            | Self::SUB(target)
+            | Self::ADC(target)
+            | Self::SBC(target)
+            | Self::XOR(target)
+            | Self::INC(target)
+            | Self::DEC(target)
+            | Self::CP(target) => {
+                let mnemonic = self.to_string();
+                let mnemonic = mnemonic.split(' ').next().unwrap();
+
+                format!("{} {}", mnemonic, target.debug_fmt(bus, operand))
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    /// Returns the address a jump, call or restart at the address goes to,
+    /// given the bytes after the opcode. Jumps to HL aren't known.
+    pub fn target(&self, operands: &[u8], address: u16) -> Option<u16> {
+        match self {
+            Self::JR(_) => Some(
+                address
+                    .wrapping_add(2)
+                    .wrapping_add(operands[0] as i8 as u16),
+            ),
+            Self::JP(_, Target16::Immediate) | Self::CALL(_) => {
+                Some(u16::from_le_bytes([operands[0], operands[1]]))
+            }
+            Self::RST(vector) => Some(*vector),
+            _ => None,
+        }
+    }
+
+    /// Formats the instruction as RGBDS assembly, given the bytes after the
+    /// opcode. `target` names the address a jump or call goes to. Illegal
+    /// opcodes have no mnemonic, and need writing out as data instead.
+    pub fn rgbds_fmt(
+        &self,
+        operands: &[u8],
+        address: u16,
+        target: impl Fn(u16) -> String,
+    ) -> String {
+        let offset = || operands[0] as i8;
+        // Most instructions are their mnemonic and operands
+        let mnemonic = || self.to_string().split(' ').next().unwrap().to_lowercase();
+
+        match self {
+            Self::RET(Condition::None) => "ret".to_owned(),
+            Self::RET(c) => format!("ret {}", c.rgbds_fmt().trim_end_matches(", ")),
+            Self::RL(Target::A) => "rla".to_owned(),
+            Self::JP(_, Target16::HL) => "jp hl".to_owned(),
+            Self::JR(c) | Self::JP(c, _) | Self::CALL(c) => format!(
+                "{} {}{}",
+                mnemonic(),
+                c.rgbds_fmt(),
+                target(self.target(operands, address).unwrap())
+            ),
+            Self::RST(vector) => format!("rst ${:02x}", vector),
+            Self::LDADD(Target16::SP) => format!("add sp, {}", offset()),
+            Self::LDADD(target) => format!(
+                "ld {}, sp {} {}",
+                target.rgbds_fmt(operands),
+                if offset() < 0 { '-' } else { '+' },
+                offset().unsigned_abs()
+            ),
+            Self::STOP => "stop".to_owned(),
+
+            // The CB opcodes only differ from RGBDS by case and brackets
+            Self::PrefixCB => bits::CB_OPCODES[operands[0] as usize]
+                .to_string()
+                .to_lowercase()
+                .replace("(hl)", "[hl]"),
+
+            Self::LD(Target::MC, from) => format!("ldh [c], {}", from.rgbds_fmt(operands)),
+            Self::LD(target, Target::MC) => format!("ldh {}, [c]", target.rgbds_fmt(operands)),
+            Self::LDI(Target::MHL, from) => format!("ld [hli], {}", from.rgbds_fmt(operands)),
+            Self::LDI(target, _) => format!("ld {}, [hli]", target.rgbds_fmt(operands)),
+            Self::LDD(Target::MHL, from) => format!("ld [hld], {}", from.rgbds_fmt(operands)),
+            Self::LDD(target, _) => format!("ld {}, [hld]", target.rgbds_fmt(operands)),
+            Self::LD(target, from) | Self::LDH(target, from) => format!(
+                "{} {}, {}",
+                mnemonic(),
+                target.rgbds_fmt(operands),
+                from.rgbds_fmt(operands)
+            ),
+            Self::LD16(target, from) => format!(
+                "ld {}, {}",
+                target.rgbds_fmt(operands),
+                from.rgbds_fmt(operands)
+            ),
+
+            // RGBDS wants the accumulator spelled out for these
+            Self::ADD(target) | Self::ADC(target) | Self::SBC(target) => {
+                format!("{} a, {}", mnemonic(), target.rgbds_fmt(operands))
+            }
+            Self::ADD16(target) => format!("add hl, {}", target.rgbds_fmt(operands)),
+            Self::AND(target)
+            | Self::OR(target)
+            | Self::SUB(target)
+            | Self::XOR(target)
+            | Self::INC(target)
+            | Self::DEC(target)
+            | Self::CP(target) => format!("{} {}", mnemonic(), target.rgbds_fmt(operands)),
+            Self::INC16(target) | Self::DEC16(target) | Self::PUSH(target) | Self::POP(target) => {
+                format!("{} {}", mnemonic(), target.rgbds_fmt(operands))
+            }
+            _ => self.to_string().to_lowercase(),
+        }
+    }
+
+    // Returns the number of machine cycles the instruction takes. Conditional
+    // instructions take longer when the branch is taken.
+    pub fn cycles(&self, branch_taken: bool) -> u8 {
+        match self {
+            Self::LD(target, from)
+            | Self::LDD(target, from)
+            | Self::LDH(target, from)
+            | Self::LDI(target, from) => 1 + target.access_cycles() + from.access_cycles(),
+            Self::LD16(Target16::SP, Target16::HL) => 2,
+            Self::LD16(target, from) => 1 + target.access_cycles() + from.access_cycles(),
+            Self::ADD(target)
+            | Self::ADC(target)
+            | Self::SUB(target)
+            | Self::SBC(target)
+            | Self::AND(target)
+            | Self::OR(target)
+            | Self::XOR(target)
+            | Self::CP(target) => 1 + target.access_cycles(),
+
+            // Read-modify-write instructions touch memory twice
+            Self::INC(target) | Self::DEC(target) => 1 + 2 * target.access_cycles(),
+            Self::INC16(_) | Self::DEC16(_) | Self::ADD16(_) => 2,
+            Self::LDADD(Target16::SP) => 4,
+            Self::LDADD(_) => 3,
+            Self::PUSH(_) => 4,
+            Self::POP(_) => 3,
+            Self::JP(_, Target16::HL) => 1,
+            Self::JP(_, _) if branch_taken => 4,
+            Self::JP(_, _) => 3,
+            Self::JR(_) if branch_taken => 3,
             Self::JR(_) => 2,
-            Self::LD16(_, Target16::Immediate) => 3,
-            Self::LD16(Target16::Immediate, _) => 3,
-            Self::LD(_, Target::Immediate) => 2,
-            Self::LD(Target::Immediate, _) => 2,
-            Self::LDH(_, Target::Immediate) => 2,
-            Self::LDH(Target::Immediate, _) => 2,
-            Self::ADD(Target::Immediate) => 2,
-            Self::ADC(Target::Immediate) => 2,
-            Self::SUB(Target::Immediate) => 2,
-            Self::AND(Target::Immediate) => 2,
-            Self::OR(Target::Immediate) => 2,
-            Self::XOR(Target::Immediate) => 2,
-            Self::LDH(_, _) => 2,
-            Self::CP(Target::Immediate) => 2,
-            Self::PrefixCB => 3,
+            Self::CALL(_) if branch_taken => 6,
+            Self::CALL(_) => 3,
+            Self::RET(Condition::None) => 4,
+            Self::RET(_) if branch_taken => 5,
+            Self::RET(_) => 2,
+            Self::RETI | Self::RST(_) => 4,
             _ => 1,
         }
     }
 }
 
-pub fn execute_opcode(cpu: &mut CPU, opcode: &Opcode) -> u8 {
+pub fn execute_opcode(cpu: &mut CPU, opcode: &Opcode) -> Result<u8, EmulationError> {
+    // Flags are checked before the instruction runs, since the jump
+    // instructions don't modify them
+    let branch_taken = match opcode {
+        Opcode::JP(condition, _)
+        | Opcode::JR(condition)
+        | Opcode::CALL(condition)
+        | Opcode::RET(condition) => condition.test(cpu),
+        _ => false,
+    };
+
     match opcode {
         Opcode::NOP => nop(cpu),
 
@@ -167,7 +374,7 @@ pub fn execute_opcode(cpu: &mut CPU, opcode: &Opcode) -> u8 {
         Opcode::OR(target) => logic::or(cpu, target),
         Opcode::XOR(target) => logic::xor(cpu, target),
 
-        Opcode::PrefixCB => bits::prefix_cb(cpu),
+        Opcode::PrefixCB => return Ok(bits::prefix_cb(cpu)),
 
         Opcode::JP(condition, target) => jump::jp(cpu, condition, target),
         Opcode::JR(condition) => jump::jr(cpu, condition),
@@ -188,12 +395,12 @@ pub fn execute_opcode(cpu: &mut CPU, opcode: &Opcode) -> u8 {
         Opcode::RRA => bits::rra(cpu),
         Opcode::CP(target) => cp::cp(cpu, target),
 
-        Opcode::HALT => cpu.set_halted(true),
+        Opcode::HALT => cpu.halt(),
         Opcode::STOP => cpu.stop(),
-        Opcode::INV => panic!("Invalid opcode found"),
+        Opcode::INV => cpu.illegal_opcode()?,
     }
 
-    1
+    Ok(opcode.cycles(branch_taken))
 }
 
 pub static OPCODES: [Opcode; 0x100] = [
@@ -472,3 +679,74 @@ pub static OPCODES: [Opcode; 0x100] = [
 ];
 
 pub fn nop(_: &mut CPU) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::{cartridge::Cartridge, model::Model};
+
+    #[test]
+    fn test_byte_count() {
+        #[rustfmt::skip]
+        let lengths = [
+            [1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1],
+            [2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1],
+            [2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1],
+            [2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1],
+            [1; 16], [1; 16], [1; 16], [1; 16],
+            [1; 16], [1; 16], [1; 16], [1; 16],
+            [1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1],
+            [1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1],
+            [2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1],
+            [2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1],
+        ];
+
+        for byte in 0..=0xFF {
+            assert_eq!(
+                Opcode::from_byte(byte).byte_count(),
+                lengths[byte as usize >> 4][byte as usize & 0x0F],
+                "Opcode 0x{:02X}",
+                byte
+            );
+        }
+    }
+
+    #[test]
+    fn test_debug_fmt() {
+        let mut rom = vec![0; 0x8000];
+        let code = [
+            0x3E, 0x42, // LD A, 42
+            0x20, 0xFC, // JR NZ, 0100
+            0xEA, 0x00, 0xC0, // LD [C000], A
+            0xCB, 0x7C, // BIT 7, H
+            0xF8, 0xFE, // LD HL, SP-2
+            0xE0, 0x40, // LDH [FF40], A
+            0xC4, 0x50, 0x01, // CALL NZ, 0150
+            0xFF, // RST 38
+        ];
+        rom[0x0100..0x0100 + code.len()].copy_from_slice(&code);
+        let bus = Bus::new(Cartridge::new(rom), Model::DMG);
+
+        let mut address = 0x0100;
+        let mut lines = Vec::new();
+        while address < 0x0100 + code.len() as u16 {
+            let opcode = Opcode::from_byte(bus.peek(address));
+            lines.push(opcode.debug_fmt(&bus, address));
+            address += opcode.byte_count() as u16;
+        }
+
+        assert_eq!(
+            lines,
+            [
+                "LD A, 42",
+                "JR NZ, 0100",
+                "LD [C000], A",
+                "BIT 7, H",
+                "LD HL, SP-2",
+                "LDH [FF40], A",
+                "CALL NZ, 0150",
+                "RST 38",
+            ]
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt::Display;
+
 use crate::hardware::{cpu::CPU, Memory};
 
 use super::Target;
@@ -66,7 +68,60 @@ impl BitTarget {
     }
 }
 
-pub fn prefix_cb(cpu: &mut CPU) {
+impl CBOpcode {
+    // Returns the number of machine cycles, including the prefix byte
+    pub fn cycles(&self) -> u8 {
+        match self {
+            CBOpcode::BIT(BitTarget::MHL, _) => 3,
+            CBOpcode::RLC(Target::MHL)
+            | CBOpcode::RRC(Target::MHL)
+            | CBOpcode::RL(Target::MHL)
+            | CBOpcode::RR(Target::MHL)
+            | CBOpcode::SLA(Target::MHL)
+            | CBOpcode::SRA(Target::MHL)
+            | CBOpcode::SWAP(Target::MHL)
+            | CBOpcode::SRL(Target::MHL)
+            | CBOpcode::RES(BitTarget::MHL, _)
+            | CBOpcode::SET(BitTarget::MHL, _) => 4,
+            _ => 2,
+        }
+    }
+}
+
+impl Display for CBOpcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CBOpcode::RLC(target) => write!(f, "RLC {}", target),
+            CBOpcode::RRC(target) => write!(f, "RRC {}", target),
+            CBOpcode::RL(target) => write!(f, "RL {}", target),
+            CBOpcode::RR(target) => write!(f, "RR {}", target),
+            CBOpcode::SLA(target) => write!(f, "SLA {}", target),
+            CBOpcode::SRA(target) => write!(f, "SRA {}", target),
+            CBOpcode::SWAP(target) => write!(f, "SWAP {}", target),
+            CBOpcode::SRL(target) => write!(f, "SRL {}", target),
+            CBOpcode::BIT(target, bit) => write!(f, "BIT {}, {}", bit, target),
+            CBOpcode::RES(target, bit) => write!(f, "RES {}, {}", bit, target),
+            CBOpcode::SET(target, bit) => write!(f, "SET {}, {}", bit, target),
+        }
+    }
+}
+
+impl Display for BitTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BitTarget::A => write!(f, "A"),
+            BitTarget::B => write!(f, "B"),
+            BitTarget::C => write!(f, "C"),
+            BitTarget::D => write!(f, "D"),
+            BitTarget::E => write!(f, "E"),
+            BitTarget::H => write!(f, "H"),
+            BitTarget::L => write!(f, "L"),
+            BitTarget::MHL => write!(f, "(HL)"),
+        }
+    }
+}
+
+pub fn prefix_cb(cpu: &mut CPU) -> u8 {
     let op = cpu.next_byte();
     let opcode = &CB_OPCODES[op as usize];
 
@@ -83,6 +138,8 @@ pub fn prefix_cb(cpu: &mut CPU) {
         CBOpcode::RRC(target) => rrc(cpu, target, true),
         CBOpcode::SWAP(target) => swap(cpu, target),
     }
+
+    opcode.cycles()
 }
 
 fn test_bit(cpu: &mut CPU, target: &BitTarget, bit: &u8) {
@@ -188,7 +245,7 @@ pub fn srl(cpu: &mut CPU, target: &Target) {
 pub fn swap(cpu: &mut CPU, target: &Target) {
     let value = target.get_value(cpu);
 
-    let result = (value << 4) | (value >> 4);
+    let result = value.rotate_left(4);
 
     cpu.registers.f.set_zero(result == 0);
     cpu.registers.f.set_subtract(false);
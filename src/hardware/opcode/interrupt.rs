@@ -4,6 +4,8 @@ pub fn enable_interrupt(cpu: &mut CPU, enabled: bool) {
     if enabled {
         cpu.interrupt_enable_counter = 2;
     } else {
-        cpu.interrupt_disable_counter = 2;
+        // DI takes effect immediately, and cancels a pending EI
+        cpu.ime = false;
+        cpu.interrupt_enable_counter = 0;
     }
 }
@@ -33,7 +33,7 @@ pub fn add16(cpu: &mut CPU, target: &Target16) {
 }
 
 pub fn ld_add(cpu: &mut CPU, target: &Target16) {
-    let a = cpu.sp as u16;
+    let a = cpu.sp;
     let b = cpu.next_byte() as i8 as i16 as u16;
 
     cpu.registers.f.set_subtract(false);
@@ -1,6 +1,6 @@
 use std::fmt::Display;
 
-use crate::hardware::{cpu::CPU, Memory};
+use crate::hardware::{bus::Bus, cpu::CPU, Memory};
 
 #[derive(Debug, Clone, Copy)]
 pub enum Target {
@@ -71,7 +71,19 @@ impl Target {
         }
     }
 
-    pub fn debug_fmt(&self, cpu: &CPU) -> String {
+    // Machine cycles spent reading the operand, or writing to it
+    pub fn access_cycles(&self) -> u8 {
+        match self {
+            Target::MC | Target::MBC | Target::MDE | Target::MHL | Target::Immediate => 1,
+            Target::ZeroImmediate => 2,
+            Target::MImmediate => 3,
+            _ => 0,
+        }
+    }
+
+    // Formats the operand with its value, for an instruction whose operand
+    // bytes start at the given address
+    pub fn debug_fmt(&self, bus: &Bus, operand: u16) -> String {
         match self {
             Target::A => "A".to_owned(),
             Target::B => "B".to_owned(),
@@ -84,9 +96,32 @@ impl Target {
             Target::MBC => "(BC)".to_owned(),
             Target::MDE => "(DE)".to_owned(),
             Target::MHL => "(HL)".to_owned(),
-            Target::Immediate => format!("{:02X}", cpu.bus.read(cpu.pc)),
-            Target::MImmediate => format!("[{:04X}]", cpu.bus.read_word(cpu.pc)),
-            Target::ZeroImmediate => format!("[{:02X}]", cpu.bus.read(cpu.pc)),
+            Target::Immediate => format!("{:02X}", bus.peek(operand)),
+            Target::MImmediate => format!("[{:04X}]", bus.peek_word(operand)),
+            Target::ZeroImmediate => format!("[FF{:02X}]", bus.peek(operand)),
+        }
+    }
+
+    // Formats the operand in RGBDS syntax, with the bytes that follow the
+    // opcode
+    pub fn rgbds_fmt(&self, operands: &[u8]) -> String {
+        match self {
+            Target::A => "a".to_owned(),
+            Target::B => "b".to_owned(),
+            Target::C => "c".to_owned(),
+            Target::D => "d".to_owned(),
+            Target::E => "e".to_owned(),
+            Target::H => "h".to_owned(),
+            Target::L => "l".to_owned(),
+            Target::MC => "[c]".to_owned(),
+            Target::MBC => "[bc]".to_owned(),
+            Target::MDE => "[de]".to_owned(),
+            Target::MHL => "[hl]".to_owned(),
+            Target::Immediate => format!("${:02x}", operands[0]),
+            Target::MImmediate => {
+                format!("[${:04x}]", u16::from_le_bytes([operands[0], operands[1]]))
+            }
+            Target::ZeroImmediate => format!("[$ff{:02x}]", operands[0]),
         }
     }
 }
@@ -145,7 +180,7 @@ impl Target16 {
         match self {
             Target16::AF => cpu.registers.set_af(value),
             Target16::BC => cpu.registers.set_bc(value),
-            Target16::DE => cpu.registers.interrupt_disable_counter(value),
+            Target16::DE => cpu.registers.set_de(value),
             Target16::HL => cpu.registers.set_hl(value),
             Target16::SP => cpu.sp = value,
             Target16::MHL => unreachable!(),
@@ -157,7 +192,16 @@ impl Target16 {
         }
     }
 
-    pub fn debug_fmt(&self, cpu: &CPU) -> String {
+    // Machine cycles spent reading the operand, or writing to it
+    pub fn access_cycles(&self) -> u8 {
+        match self {
+            Target16::MHL | Target16::Immediate => 2,
+            Target16::MImmediate => 4,
+            _ => 0,
+        }
+    }
+
+    pub fn debug_fmt(&self, bus: &Bus, operand: u16) -> String {
         match self {
             Target16::AF => "AF".to_owned(),
             Target16::BC => "BC".to_owned(),
@@ -165,8 +209,23 @@ impl Target16 {
             Target16::HL => "HL".to_owned(),
             Target16::SP => "SP".to_owned(),
             Target16::MHL => "[HL]".to_owned(),
-            Target16::Immediate => format!("{:04X}", cpu.bus.read_word(cpu.pc)),
-            Target16::MImmediate => format!("Unknown"),
+            Target16::Immediate => format!("{:04X}", bus.peek_word(operand)),
+            Target16::MImmediate => format!("[{:04X}]", bus.peek_word(operand)),
+        }
+    }
+
+    pub fn rgbds_fmt(&self, operands: &[u8]) -> String {
+        let word = || u16::from_le_bytes([operands[0], operands[1]]);
+
+        match self {
+            Target16::AF => "af".to_owned(),
+            Target16::BC => "bc".to_owned(),
+            Target16::DE => "de".to_owned(),
+            Target16::HL => "hl".to_owned(),
+            Target16::SP => "sp".to_owned(),
+            Target16::MHL => "[hl]".to_owned(),
+            Target16::Immediate => format!("${:04x}", word()),
+            Target16::MImmediate => format!("[${:04x}]", word()),
         }
     }
 }
@@ -196,6 +255,17 @@ pub enum Condition {
 }
 
 impl Condition {
+    // Returns the condition in RGBDS syntax, followed by a comma if there is one
+    pub fn rgbds_fmt(&self) -> &'static str {
+        match self {
+            Condition::NotZero => "nz, ",
+            Condition::Zero => "z, ",
+            Condition::NotCarry => "nc, ",
+            Condition::Carry => "c, ",
+            Condition::None => "",
+        }
+    }
+
     pub fn test(&self, cpu: &CPU) -> bool {
         match self {
             Condition::NotZero => !cpu.registers.f.zero(),
@@ -0,0 +1,853 @@
+pub mod compatibility;
+pub mod palette;
+pub mod shades;
+
+use anyhow::Result;
+
+use super::{
+    bus::Interrupt,
+    model::Model,
+    state::{Snapshot, StateReader, StateWriter},
+    Memory, RAM,
+};
+use compatibility::CompatibilityPalettes;
+use palette::{bgr555_to_rgb, PaletteRAM};
+use shades::ShadePalette;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+// https://gbdev.io/pandocs/Rendering.html
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const DRAWING_DOTS: u16 = 172;
+const VBLANK_LINE: u8 = 144;
+const LINES_PER_FRAME: u8 = 154;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OAMScan = 2,
+    Drawing = 3,
+}
+
+// The Pixel Processing Unit draws a line at a time into the back buffer, which
+// becomes the framebuffer at VBlank. Each line takes a fixed 456 dots, 4 for
+// every machine cycle in normal speed.
+pub struct PPU {
+    // The CGB outputs BGR555 colors, the others a shade from 0 to 3
+    color: bool,
+
+    vram: RAM,
+    vram_bank: u8,
+    oam: RAM,
+
+    lcdc: u8,
+    // Only the interrupt selection bits, the rest is derived
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    dma: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+
+    // Object priority mode, by OAM index (0) or X coordinate (1)
+    opri: u8,
+
+    pub bg_palettes: PaletteRAM,
+    pub obj_palettes: PaletteRAM,
+
+    // Position within the current line
+    dots: u16,
+
+    // The window has its own line counter, which only advances on lines it
+    // was drawn on. It's only drawn once LY has matched WY this frame.
+    window_line: u8,
+    window_triggered: bool,
+
+    // The STAT interrupt fires on the rising edge of all its sources OR'd
+    stat_line: bool,
+
+    // Set when HBlank starts, for HBlank DMA
+    hblank_started: bool,
+
+    // The frame being drawn, and the last one completed
+    back_buffer: Vec<u16>,
+    framebuffer: Vec<u16>,
+    frames: u64,
+
+    /// Approximate the colors of the CGB LCD when converting to RGB
+    pub color_correction: bool,
+
+    /// The colors DMG shades are shown as when converting to RGB
+    pub shade_palette: ShadePalette,
+}
+
+impl PPU {
+    pub fn new(model: Model) -> PPU {
+        let color = model.is_cgb();
+        let banks = if color { 2 } else { 1 };
+
+        PPU {
+            color,
+            vram: RAM::new(0x2000 * banks),
+            vram_bank: 0,
+            oam: RAM::new(0xA0),
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            dma: 0xFF,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            opri: 0,
+            bg_palettes: PaletteRAM::new(),
+            obj_palettes: PaletteRAM::new(),
+            dots: 0,
+            window_line: 0,
+            window_triggered: false,
+            stat_line: false,
+            hblank_started: false,
+            back_buffer: vec![Self::white(color); SCREEN_WIDTH * SCREEN_HEIGHT],
+            framebuffer: vec![Self::white(color); SCREEN_WIDTH * SCREEN_HEIGHT],
+            frames: 0,
+            color_correction: false,
+            shade_palette: ShadePalette::default(),
+        }
+    }
+
+    // Sets the registers and palettes the boot ROM leaves behind
+    pub fn skip_boot_rom(&mut self, model: Model, cgb_mode: bool) {
+        self.lcdc = 0x91;
+        self.bgp = 0xFC;
+        self.dma = if model.is_cgb() { 0x00 } else { 0xFF };
+
+        if model.is_cgb() {
+            if cgb_mode {
+                for palette in 0..8 {
+                    for color in 0..4 {
+                        self.bg_palettes.set_color(palette, color, 0x7FFF);
+                    }
+                }
+            } else {
+                // DMG games prioritize objects by X coordinate
+                self.opri = 0x01;
+            }
+        }
+    }
+
+    /// Sets the palettes a DMG game is drawn with on the CGB, which uses the
+    /// first background palette and the first two object palettes
+    pub fn set_compatibility_palettes(&mut self, palettes: &CompatibilityPalettes) {
+        for color in 0..4 {
+            self.bg_palettes
+                .set_color(0, color, palettes.bg[color as usize]);
+            self.obj_palettes
+                .set_color(0, color, palettes.obj0[color as usize]);
+            self.obj_palettes
+                .set_color(1, color, palettes.obj1[color as usize]);
+        }
+    }
+
+    fn white(color: bool) -> u16 {
+        if color {
+            0x7FFF
+        } else {
+            0
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    pub fn mode(&self) -> Mode {
+        if !self.enabled() {
+            Mode::HBlank
+        } else if self.ly >= VBLANK_LINE {
+            Mode::VBlank
+        } else if self.dots < OAM_SCAN_DOTS {
+            Mode::OAMScan
+        } else if self.dots < OAM_SCAN_DOTS + DRAWING_DOTS {
+            Mode::Drawing
+        } else {
+            Mode::HBlank
+        }
+    }
+
+    /// Returns the number of frames completed, counted at the start of VBlank
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Returns the last completed frame, as BGR555 colors on the CGB and
+    /// shades from 0 (white) to 3 (black) otherwise
+    pub fn framebuffer(&self) -> &[u16] {
+        &self.framebuffer
+    }
+
+    /// Returns the last completed frame as shades from 0 (lightest) to 3, for
+    /// tools that map them to colors themselves. The CGB has no shades.
+    pub fn shades(&self) -> Option<Vec<u8>> {
+        (!self.color).then(|| self.framebuffer.iter().map(|&shade| shade as u8).collect())
+    }
+
+    /// Returns the last completed frame as 8-bit RGB triplets
+    pub fn frame_rgb(&self) -> Vec<u8> {
+        let shades = self.shade_palette.colors();
+
+        self.framebuffer
+            .iter()
+            .flat_map(|&pixel| {
+                if self.color {
+                    bgr555_to_rgb(pixel, self.color_correction)
+                } else {
+                    shades[pixel as usize]
+                }
+            })
+            .collect()
+    }
+
+    /// Advances the PPU by the given number of dots, returning the interrupt
+    /// flags to raise
+    pub fn tick(&mut self, dots: u8, cgb_mode: bool) -> u8 {
+        if !self.enabled() {
+            return 0;
+        }
+
+        let mut interrupts = 0;
+
+        for _ in 0..dots {
+            self.dots += 1;
+
+            // Drawing is done in one go, once the line's pixels are final
+            if self.dots == OAM_SCAN_DOTS + DRAWING_DOTS && self.ly < VBLANK_LINE {
+                self.render_line(cgb_mode);
+                self.hblank_started = true;
+            }
+
+            if self.dots == DOTS_PER_LINE {
+                self.dots = 0;
+                self.ly = (self.ly + 1) % LINES_PER_FRAME;
+
+                if self.ly == VBLANK_LINE {
+                    std::mem::swap(&mut self.framebuffer, &mut self.back_buffer);
+                    self.frames += 1;
+                    interrupts |= 1 << Interrupt::VBlank as u8;
+                }
+
+                if self.ly == 0 {
+                    self.window_line = 0;
+                    self.window_triggered = false;
+                }
+            }
+
+            if self.update_stat_line() {
+                interrupts |= 1 << Interrupt::LCD as u8;
+            }
+        }
+
+        interrupts
+    }
+
+    /// Returns true if HBlank has started since the last call
+    pub fn take_hblank(&mut self) -> bool {
+        std::mem::take(&mut self.hblank_started)
+    }
+
+    // Returns true on the rising edge of the STAT interrupt line
+    fn update_stat_line(&mut self) -> bool {
+        let coincidence = self.ly == self.lyc && self.stat & 0x40 != 0;
+        let mode = match self.mode() {
+            Mode::HBlank => self.stat & 0x08 != 0,
+            Mode::VBlank => self.stat & 0x10 != 0,
+            Mode::OAMScan => self.stat & 0x20 != 0,
+            Mode::Drawing => false,
+        };
+
+        let line = self.enabled() && (coincidence || mode);
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+
+        rising
+    }
+
+    // Returns the 2-bit color of a pixel in a tile row
+    fn tile_pixel(&self, row_address: u16, column: u8) -> u8 {
+        let low = self.vram.read(row_address);
+        let high = self.vram.read(row_address + 1);
+        let bit = 7 - column;
+
+        (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+    }
+
+    // Maps a color through a DMG palette register
+    fn shade(palette: u8, color: u8) -> u8 {
+        (palette >> (color * 2)) & 0x03
+    }
+
+    fn render_line(&mut self, cgb_mode: bool) {
+        let line = self.ly as usize * SCREEN_WIDTH;
+
+        // The background color and priority below each pixel, which decide
+        // whether objects are drawn over it
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+        let mut bg_priority = [false; SCREEN_WIDTH];
+
+        if self.ly == self.wy {
+            self.window_triggered = true;
+        }
+
+        // In CGB mode, LCDC bit 0 only takes away the background's priority
+        let bg_enabled = cgb_mode || self.lcdc & 0x01 != 0;
+        let window_enabled = bg_enabled && self.lcdc & 0x20 != 0 && self.window_triggered;
+        let mut window_drawn = false;
+
+        for x in 0..SCREEN_WIDTH {
+            if !bg_enabled {
+                self.back_buffer[line + x] = if self.color {
+                    self.bg_palettes.color(0, 0)
+                } else {
+                    0
+                };
+                continue;
+            }
+
+            let (map, map_x, map_y) = if window_enabled && x + 7 >= self.wx as usize {
+                window_drawn = true;
+
+                let map = if self.lcdc & 0x40 != 0 {
+                    0x1C00
+                } else {
+                    0x1800
+                };
+                (map, (x + 7 - self.wx as usize) as u8, self.window_line)
+            } else {
+                let map = if self.lcdc & 0x08 != 0 {
+                    0x1C00
+                } else {
+                    0x1800
+                };
+                (
+                    map,
+                    self.scx.wrapping_add(x as u8),
+                    self.scy.wrapping_add(self.ly),
+                )
+            };
+
+            let map_offset = map + (map_y as u16 / 8) * 32 + (map_x as u16 / 8);
+            let tile = self.vram.read(map_offset);
+
+            // The CGB keeps the attributes for each tile in the second bank
+            let attributes = if cgb_mode {
+                self.vram.read(0x2000 + map_offset)
+            } else {
+                0
+            };
+
+            let mut row = map_y % 8;
+            let mut column = map_x % 8;
+
+            if attributes & 0x40 != 0 {
+                row = 7 - row;
+            }
+
+            if attributes & 0x20 != 0 {
+                column = 7 - column;
+            }
+
+            let bank = if attributes & 0x08 != 0 { 0x2000 } else { 0 };
+            let tile_address = if self.lcdc & 0x10 != 0 {
+                tile as u16 * 16
+            } else {
+                (0x1000 + tile as i8 as i16 * 16) as u16
+            };
+
+            let color = self.tile_pixel(bank + tile_address + row as u16 * 2, column);
+
+            bg_colors[x] = color;
+            bg_priority[x] = attributes & 0x80 != 0;
+            self.back_buffer[line + x] = if cgb_mode {
+                self.bg_palettes.color(attributes & 0x07, color)
+            } else if self.color {
+                self.bg_palettes.color(0, Self::shade(self.bgp, color))
+            } else {
+                Self::shade(self.bgp, color) as u16
+            };
+        }
+
+        if window_drawn {
+            self.window_line += 1;
+        }
+
+        if self.lcdc & 0x02 != 0 {
+            self.render_objects(cgb_mode, line, &bg_colors, &bg_priority);
+        }
+    }
+
+    fn render_objects(
+        &mut self,
+        cgb_mode: bool,
+        line: usize,
+        bg_colors: &[u8; SCREEN_WIDTH],
+        bg_priority: &[bool; SCREEN_WIDTH],
+    ) {
+        let height = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
+        let ly = self.ly as i16;
+
+        // Up to 10 objects are selected per line, in OAM order
+        let mut objects: Vec<u16> = (0..40)
+            .map(|index| index * 4)
+            .filter(|&address| {
+                let y = self.oam.read(address) as i16 - 16;
+                ly >= y && ly < y + height
+            })
+            .take(10)
+            .collect();
+
+        // The DMG prioritizes objects by X coordinate, which the CGB only
+        // does for DMG games. The stable sort keeps OAM order for ties.
+        if !cgb_mode || self.opri & 0x01 != 0 {
+            objects.sort_by_key(|&address| self.oam.read(address + 1));
+        }
+
+        // Pixels are claimed by the highest priority opaque object, even if
+        // the background ends up hiding it
+        let mut claimed = [false; SCREEN_WIDTH];
+
+        for address in objects {
+            let y = self.oam.read(address) as i16 - 16;
+            let x = self.oam.read(address + 1) as i16 - 8;
+            let mut tile = self.oam.read(address + 2);
+            let attributes = self.oam.read(address + 3);
+
+            let mut row = (ly - y) as u16;
+
+            if attributes & 0x40 != 0 {
+                row = height as u16 - 1 - row;
+            }
+
+            // Tall objects ignore the lowest bit of the tile index
+            if height == 16 {
+                tile &= 0xFE;
+            }
+
+            let bank = if cgb_mode && attributes & 0x08 != 0 {
+                0x2000
+            } else {
+                0
+            };
+            let row_address = bank + tile as u16 * 16 + row * 2;
+
+            for column in 0..8 {
+                let screen_x = x + column as i16;
+
+                if !(0..SCREEN_WIDTH as i16).contains(&screen_x) {
+                    continue;
+                }
+
+                let screen_x = screen_x as usize;
+
+                if claimed[screen_x] {
+                    continue;
+                }
+
+                let pixel = if attributes & 0x20 != 0 {
+                    7 - column
+                } else {
+                    column
+                };
+                let color = self.tile_pixel(row_address, pixel);
+
+                // Color 0 is transparent
+                if color == 0 {
+                    continue;
+                }
+
+                claimed[screen_x] = true;
+
+                let behind_bg = bg_colors[screen_x] != 0
+                    && if cgb_mode {
+                        self.lcdc & 0x01 != 0 && (bg_priority[screen_x] || attributes & 0x80 != 0)
+                    } else {
+                        attributes & 0x80 != 0
+                    };
+
+                if behind_bg {
+                    continue;
+                }
+
+                let dmg_palette = if attributes & 0x10 != 0 {
+                    self.obp1
+                } else {
+                    self.obp0
+                };
+
+                self.back_buffer[line + screen_x] = if cgb_mode {
+                    self.obj_palettes.color(attributes & 0x07, color)
+                } else if self.color {
+                    self.obj_palettes
+                        .color((attributes >> 4) & 0x01, Self::shade(dmg_palette, color))
+                } else {
+                    Self::shade(dmg_palette, color) as u16
+                };
+            }
+        }
+    }
+
+    fn write_lcdc(&mut self, value: u8) {
+        let was_enabled = self.enabled();
+        self.lcdc = value;
+
+        if was_enabled && !self.enabled() {
+            // The screen goes blank, and the PPU restarts from the first line
+            // when it's turned back on
+            self.ly = 0;
+            self.dots = 0;
+            self.window_line = 0;
+            self.window_triggered = false;
+            self.stat_line = false;
+            self.back_buffer.fill(Self::white(self.color));
+            self.framebuffer.fill(Self::white(self.color));
+        }
+    }
+}
+
+impl Memory for PPU {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            0x8000..=0x9FFF => self
+                .vram
+                .read((self.vram_bank as u16 * 0x2000) + (address & 0x1FFF)),
+            0xFE00..=0xFE9F => self.oam.read(address - 0xFE00),
+            0xFF40 => self.lcdc,
+            0xFF41 => {
+                let coincidence = (self.ly == self.lyc) as u8;
+
+                0x80 | self.stat | (coincidence << 2) | self.mode() as u8
+            }
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF46 => self.dma,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF4F => 0xFE | self.vram_bank,
+            0xFF68 => self.bg_palettes.read_spec(),
+            0xFF69 => self.bg_palettes.read_data(),
+            0xFF6A => self.obj_palettes.read_spec(),
+            0xFF6B => self.obj_palettes.read_data(),
+            0xFF6C => 0xFE | self.opri,
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x9FFF => self
+                .vram
+                .write((self.vram_bank as u16 * 0x2000) + (address & 0x1FFF), value),
+            0xFE00..=0xFE9F => self.oam.write(address - 0xFE00, value),
+            0xFF40 => self.write_lcdc(value),
+            0xFF41 => self.stat = value & 0x78,
+            0xFF42 => self.scy = value,
+            0xFF43 => self.scx = value,
+            // LY is read only
+            0xFF44 => {}
+            0xFF45 => self.lyc = value,
+            0xFF46 => self.dma = value,
+            0xFF47 => self.bgp = value,
+            0xFF48 => self.obp0 = value,
+            0xFF49 => self.obp1 = value,
+            0xFF4A => self.wy = value,
+            0xFF4B => self.wx = value,
+            0xFF4F => self.vram_bank = value & 0x01,
+            0xFF68 => self.bg_palettes.write_spec(value),
+            0xFF69 => self.bg_palettes.write_data(value),
+            0xFF6A => self.obj_palettes.write_spec(value),
+            0xFF6B => self.obj_palettes.write_data(value),
+            0xFF6C => self.opri = value & 0x01,
+            _ => {}
+        }
+    }
+}
+
+// The color correction and shade palette are display settings, and stay as
+// they are
+impl Snapshot for PPU {
+    fn save(&self, state: &mut StateWriter) {
+        self.vram.save(state);
+        state.u8(self.vram_bank);
+        self.oam.save(state);
+
+        for register in [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.dma, self.bgp,
+            self.obp0, self.obp1, self.wy, self.wx, self.opri,
+        ] {
+            state.u8(register);
+        }
+
+        self.bg_palettes.save(state);
+        self.obj_palettes.save(state);
+        state.u16(self.dots);
+        state.u8(self.window_line);
+        state.bool(self.window_triggered);
+        state.bool(self.stat_line);
+        state.bool(self.hblank_started);
+        state.words(&self.back_buffer);
+        state.words(&self.framebuffer);
+        state.u64(self.frames);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<()> {
+        self.vram.load(state)?;
+        self.vram_bank = state.u8()?;
+        self.oam.load(state)?;
+
+        for register in [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.dma,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+            &mut self.opri,
+        ] {
+            *register = state.u8()?;
+        }
+
+        self.bg_palettes.load(state)?;
+        self.obj_palettes.load(state)?;
+        self.dots = state.u16()?;
+        self.window_line = state.u8()?;
+        self.window_triggered = state.bool()?;
+        self.stat_line = state.bool()?;
+        self.hblank_started = state.bool()?;
+        state.words_into(&mut self.back_buffer)?;
+        state.words_into(&mut self.framebuffer)?;
+        self.frames = state.u64()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cgb_ppu() -> PPU {
+        let mut ppu = PPU::new(Model::CGB);
+        ppu.skip_boot_rom(Model::CGB, true);
+        ppu
+    }
+
+    // Runs the PPU until a whole frame has been drawn
+    fn draw_frame(ppu: &mut PPU, cgb_mode: bool) {
+        let frames = ppu.frames();
+        while ppu.frames() == frames {
+            ppu.tick(4, cgb_mode);
+        }
+    }
+
+    fn write_palette(palettes: &mut PaletteRAM, palette: u8, colors: [u16; 4]) {
+        palettes.write_spec(0x80 | (palette * 8));
+
+        for color in colors {
+            let [low, high] = color.to_le_bytes();
+            palettes.write_data(low);
+            palettes.write_data(high);
+        }
+    }
+
+    #[test]
+    fn test_frame_timing() {
+        let mut ppu = PPU::new(Model::DMG);
+        ppu.skip_boot_rom(Model::DMG, false);
+        ppu.write(0xFF41, 0x10);
+
+        assert_eq!(ppu.mode(), Mode::OAMScan);
+        assert_eq!(ppu.tick(80, false), 0);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+
+        let mut interrupts = 0;
+        let mut dots = 80;
+
+        while ppu.read(0xFF44) != 144 {
+            interrupts |= ppu.tick(1, false);
+            dots += 1;
+        }
+
+        assert_eq!(dots, 456 * 144);
+        assert_eq!(ppu.mode(), Mode::VBlank);
+        assert_eq!(ppu.read(0xFF41) & 0x03, 0x01);
+        assert_eq!(ppu.frames(), 1);
+
+        // VBlank, and the STAT interrupt selected for mode 1
+        assert_eq!(interrupts, 0x03);
+    }
+
+    #[test]
+    fn test_lyc_interrupt() {
+        let mut ppu = PPU::new(Model::DMG);
+        ppu.skip_boot_rom(Model::DMG, false);
+        ppu.write(0xFF45, 2);
+        ppu.write(0xFF41, 0x40);
+
+        let mut lines = vec![];
+
+        for line in 0..4 {
+            if ppu.tick(228, false) | ppu.tick(228, false) != 0 {
+                lines.push(line);
+            }
+        }
+
+        // LY becomes 2 at the end of the second line
+        assert_eq!(lines, [1]);
+        assert_eq!(ppu.read(0xFF41) & 0x04, 0x00);
+    }
+
+    #[test]
+    fn test_attribute_map() {
+        let mut ppu = cgb_ppu();
+
+        // Tile 1 in bank 1 has a single pixel of color 3 on its first row
+        ppu.write(0xFF4F, 0x01);
+        ppu.write(0x8010, 0x80);
+        ppu.write(0x8011, 0x80);
+
+        // The first tile uses it with palette 2, flipped horizontally
+        ppu.write(0x9800, 0x08 | 0x20 | 0x02);
+        ppu.write(0xFF4F, 0x00);
+        ppu.write(0x9800, 0x01);
+
+        write_palette(&mut ppu.bg_palettes, 2, [0x0000, 0x0001, 0x0002, 0x001F]);
+        draw_frame(&mut ppu, true);
+
+        let framebuffer = ppu.framebuffer();
+        assert_eq!(framebuffer[7], 0x001F);
+        assert_eq!(&framebuffer[0..7], &[0x0000; 7]);
+
+        // Other tiles use attribute 0, so palette 0 which is left white
+        assert_eq!(framebuffer[8], 0x7FFF);
+    }
+
+    #[test]
+    fn test_object_priority() {
+        let mut ppu = cgb_ppu();
+        ppu.write(0xFF40, 0x93);
+
+        // Tile 1 is a solid block of color 1
+        for address in (0x8010..0x8020).step_by(2) {
+            ppu.write(address, 0xFF);
+        }
+
+        // Object 0 is further right, but earlier in OAM
+        for (address, value) in [16, 12, 1, 0x01, 16, 8, 1, 0x02].iter().enumerate() {
+            ppu.write(0xFE00 + address as u16, *value);
+        }
+
+        write_palette(&mut ppu.obj_palettes, 1, [0, 0x0001, 0, 0]);
+        write_palette(&mut ppu.obj_palettes, 2, [0, 0x0002, 0, 0]);
+        draw_frame(&mut ppu, true);
+
+        // The CGB goes by OAM index
+        assert_eq!(
+            &ppu.framebuffer()[0..6],
+            &[0x0002, 0x0002, 0x0002, 0x0002, 0x0001, 0x0001]
+        );
+
+        // Unless asked to behave like a DMG
+        ppu.write(0xFF40, 0x13);
+        ppu.write(0xFF6C, 0x01);
+        ppu.write(0xFF40, 0x93);
+        draw_frame(&mut ppu, true);
+        assert_eq!(&ppu.framebuffer()[0..6], &[0x0002; 6]);
+    }
+
+    #[test]
+    fn test_bg_to_oam_priority() {
+        let mut ppu = cgb_ppu();
+        ppu.write(0xFF40, 0x93);
+
+        // Tile 1 is a solid block of color 1, used by the background and an
+        // object at the top left
+        for address in (0x8010..0x8020).step_by(2) {
+            ppu.write(address, 0xFF);
+        }
+
+        ppu.write(0x9800, 0x01);
+        ppu.write(0x9801, 0x01);
+        ppu.write(0xFF4F, 0x01);
+        ppu.write(0x9800, 0x80);
+        ppu.write(0xFF4F, 0x00);
+
+        for (address, value) in [16, 8, 1, 0x01, 16, 16, 1, 0x01].iter().enumerate() {
+            ppu.write(0xFE00 + address as u16, *value);
+        }
+
+        write_palette(&mut ppu.bg_palettes, 0, [0, 0x0001, 0, 0]);
+        write_palette(&mut ppu.obj_palettes, 1, [0, 0x0002, 0, 0]);
+        draw_frame(&mut ppu, true);
+
+        // The first tile has priority over objects, the second doesn't
+        assert_eq!(ppu.framebuffer()[0], 0x0001);
+        assert_eq!(ppu.framebuffer()[8], 0x0002);
+
+        // Clearing LCDC bit 0 puts objects on top regardless
+        ppu.write(0xFF40, 0x12);
+        ppu.write(0xFF40, 0x92);
+        draw_frame(&mut ppu, true);
+        assert_eq!(ppu.framebuffer()[0], 0x0002);
+    }
+
+    #[test]
+    fn test_dmg_rendering() {
+        let mut ppu = PPU::new(Model::DMG);
+        ppu.skip_boot_rom(Model::DMG, false);
+
+        // With the boot ROM's palette, color 0 is white and the rest black
+        ppu.write(0x8000, 0x80);
+        draw_frame(&mut ppu, false);
+
+        assert_eq!(&ppu.framebuffer()[0..2], &[3, 0]);
+        assert_eq!(&ppu.shades().unwrap()[0..2], &[3, 0]);
+        assert_eq!(&ppu.frame_rgb()[0..6], &[0, 0, 0, 0xFF, 0xFF, 0xFF]);
+
+        ppu.shade_palette = ShadePalette::ClassicGreen;
+        assert_eq!(
+            &ppu.frame_rgb()[0..6],
+            &[0x0F, 0x38, 0x0F, 0x9B, 0xBC, 0x0F]
+        );
+
+        // Lines drawn mid-frame only show once the frame is complete
+        ppu.write(0x8000, 0x00);
+        while ppu.ly != 1 {
+            ppu.tick(4, false);
+        }
+
+        assert_eq!(&ppu.framebuffer()[0..2], &[3, 0]);
+        draw_frame(&mut ppu, false);
+        assert_eq!(&ppu.framebuffer()[0..2], &[0, 0]);
+    }
+}
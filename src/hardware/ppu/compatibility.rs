@@ -0,0 +1,354 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+
+use crate::hardware::{cartridge::Cartridge, io::joypad::Button};
+
+// The CGB boot ROM colorizes DMG games, picking the palettes from a table of
+// Nintendo published games, or from a button combo held during the logo.
+// https://gbdev.io/pandocs/Power_Up_Sequence.html#compatibility-palettes
+
+// The colors the combinations pick from, 4 at a time
+const PALETTES: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, // 0
+    0x639F, 0x4279, 0x15B0, 0x04CB, // 1
+    0x7FFF, 0x6E31, 0x454A, 0x0000, // 2
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, // 3
+    0x7FFF, 0x421F, 0x1CF2, 0x0000, // 4
+    0x7FFF, 0x5294, 0x294A, 0x0000, // 5
+    0x7FFF, 0x03FF, 0x012F, 0x0000, // 6
+    0x7FFF, 0x03EF, 0x01D6, 0x0000, // 7
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000, // 8
+    0x7E74, 0x03FF, 0x0180, 0x0000, // 9
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B, // 10
+    0x7ED6, 0x4BFF, 0x2175, 0x0000, // 11
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, // 12
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, // 13
+    0x03ED, 0x7FFF, 0x255F, 0x0000, // 14
+    0x036A, 0x021F, 0x03FF, 0x7FFF, // 15
+    0x7FFF, 0x01DF, 0x0112, 0x0000, // 16
+    0x231F, 0x035F, 0x00F2, 0x0009, // 17
+    0x7FFF, 0x03EA, 0x011F, 0x0000, // 18
+    0x299F, 0x001A, 0x000C, 0x0000, // 19
+    0x7FFF, 0x027F, 0x001F, 0x0000, // 20
+    0x7FFF, 0x03E0, 0x0206, 0x0120, // 21
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00, // 22
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F, // 23
+    0x7FFF, 0x03FF, 0x001F, 0x0000, // 24
+    0x03FF, 0x001F, 0x000C, 0x0000, // 25
+    0x7FFF, 0x033F, 0x0193, 0x0000, // 26
+    0x0000, 0x4200, 0x037F, 0x7FFF, // 27
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000, // 28
+    0x7FFF, 0x1BEF, 0x6180, 0x0000, // 29
+];
+
+// The first color of the OBJ0, OBJ1 and BG palettes of each combination. A
+// few start in the middle of a palette.
+const COMBINATIONS: [[u8; 3]; 51] = [
+    [4 * 4, 4 * 4, 29 * 4],
+    [18 * 4, 18 * 4, 18 * 4],
+    [20 * 4, 20 * 4, 20 * 4],
+    [24 * 4, 24 * 4, 24 * 4],
+    [9 * 4, 9 * 4, 9 * 4],
+    [0, 0, 0],
+    [27 * 4, 27 * 4, 27 * 4],
+    [5 * 4, 5 * 4, 5 * 4],
+    [12 * 4, 12 * 4, 12 * 4],
+    [26 * 4, 26 * 4, 26 * 4],
+    [16 * 4, 8 * 4, 8 * 4],
+    [4 * 4, 28 * 4, 28 * 4],
+    [4 * 4, 2 * 4, 2 * 4],
+    [3 * 4, 4 * 4, 4 * 4],
+    [4 * 4, 29 * 4, 29 * 4],
+    [28 * 4, 4 * 4, 28 * 4],
+    [2 * 4, 17 * 4, 2 * 4],
+    [16 * 4, 16 * 4, 8 * 4],
+    [4 * 4, 4 * 4, 7 * 4],
+    [4 * 4, 4 * 4, 18 * 4],
+    [4 * 4, 4 * 4, 20 * 4],
+    [19 * 4, 19 * 4, 9 * 4],
+    [4 * 4 - 1, 4 * 4 - 1, 11 * 4],
+    [17 * 4, 17 * 4, 2 * 4],
+    [4 * 4, 4 * 4, 2 * 4],
+    [4 * 4, 4 * 4, 3 * 4],
+    [28 * 4, 28 * 4, 0],
+    [3 * 4, 3 * 4, 0],
+    [0, 0, 4],
+    [18 * 4, 22 * 4, 18 * 4],
+    [20 * 4, 22 * 4, 20 * 4],
+    [24 * 4, 22 * 4, 24 * 4],
+    [16 * 4, 22 * 4, 8 * 4],
+    [17 * 4, 4 * 4, 13 * 4],
+    [28 * 4 - 1, 0, 14 * 4],
+    [28 * 4 - 1, 4 * 4, 15 * 4],
+    [19 * 4, 22 * 4, 9 * 4],
+    [16 * 4, 28 * 4, 10 * 4],
+    [4 * 4, 23 * 4, 28 * 4],
+    [17 * 4, 22 * 4, 2 * 4],
+    [4 * 4, 0, 2 * 4],
+    [4 * 4, 28 * 4, 3 * 4],
+    [28 * 4, 3 * 4, 0],
+    [3 * 4, 28 * 4, 4 * 4],
+    [21 * 4, 28 * 4, 4 * 4],
+    [3 * 4, 28 * 4, 0],
+    [25 * 4, 3 * 4, 28 * 4],
+    [0, 28 * 4, 8 * 4],
+    [4 * 4, 3 * 4, 28 * 4],
+    [28 * 4, 3 * 4, 6 * 4],
+    [4 * 4, 28 * 4, 29 * 4],
+];
+
+// Title checksums of the games with their own palettes. The last few are
+// shared by several games, which the 4th letter of the title tells apart.
+const TITLE_CHECKSUMS: [u8; 79] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+];
+
+const FIRST_SHARED_CHECKSUM: usize = 65;
+const SHARED_CHECKSUMS: usize = TITLE_CHECKSUMS.len() - FIRST_SHARED_CHECKSUM;
+
+// The 4th letter of each game with a shared checksum, going through the
+// shared checksums in order, and wrapping around
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// The combination used by each game, first by checksum, then by 4th letter
+const GAME_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29,
+    5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6,
+    5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39,
+    24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+/// The palettes that can be picked by holding a direction, optionally with A
+/// or B, while the CGB boot ROM shows the logo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManualPalette {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl ManualPalette {
+    pub const ALL: [ManualPalette; 12] = [
+        ManualPalette::Up,
+        ManualPalette::UpA,
+        ManualPalette::UpB,
+        ManualPalette::Left,
+        ManualPalette::LeftA,
+        ManualPalette::LeftB,
+        ManualPalette::Down,
+        ManualPalette::DownA,
+        ManualPalette::DownB,
+        ManualPalette::Right,
+        ManualPalette::RightA,
+        ManualPalette::RightB,
+    ];
+
+    /// Returns the palette a button combo picks. A takes precedence over B.
+    pub fn from_buttons(buttons: &[Button]) -> Option<ManualPalette> {
+        let a = buttons.contains(&Button::A);
+        let b = buttons.contains(&Button::B);
+        let direction = buttons.iter().find(|button| {
+            matches!(
+                button,
+                Button::Up | Button::Down | Button::Left | Button::Right
+            )
+        })?;
+
+        let palette = match (direction, a, b) {
+            (Button::Up, true, _) => ManualPalette::UpA,
+            (Button::Up, _, true) => ManualPalette::UpB,
+            (Button::Up, _, _) => ManualPalette::Up,
+            (Button::Left, true, _) => ManualPalette::LeftA,
+            (Button::Left, _, true) => ManualPalette::LeftB,
+            (Button::Left, _, _) => ManualPalette::Left,
+            (Button::Down, true, _) => ManualPalette::DownA,
+            (Button::Down, _, true) => ManualPalette::DownB,
+            (Button::Down, _, _) => ManualPalette::Down,
+            (_, true, _) => ManualPalette::RightA,
+            (_, _, true) => ManualPalette::RightB,
+            _ => ManualPalette::Right,
+        };
+
+        Some(palette)
+    }
+
+    fn combination(&self) -> usize {
+        match self {
+            ManualPalette::Up => 5,
+            ManualPalette::UpA => 43,
+            ManualPalette::UpB => 28,
+            ManualPalette::Left => 48,
+            ManualPalette::LeftA => 40,
+            ManualPalette::LeftB => 7,
+            ManualPalette::Down => 8,
+            ManualPalette::DownA => 3,
+            ManualPalette::DownB => 49,
+            ManualPalette::Right => 1,
+            ManualPalette::RightA => 0,
+            ManualPalette::RightB => 6,
+        }
+    }
+}
+
+impl FromStr for ManualPalette {
+    type Err = Error;
+
+    /// Parses combos like "up", "left+a" or "down+b"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let buttons = s
+            .split('+')
+            .map(|button| match button.trim().to_ascii_lowercase().as_str() {
+                "up" => Ok(Button::Up),
+                "down" => Ok(Button::Down),
+                "left" => Ok(Button::Left),
+                "right" => Ok(Button::Right),
+                "a" => Ok(Button::A),
+                "b" => Ok(Button::B),
+                _ => Err(anyhow!("Unknown button in palette combo: {}", s)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        ManualPalette::from_buttons(&buttons)
+            .ok_or_else(|| anyhow!("Palette combos need a direction: {}", s))
+    }
+}
+
+/// The palettes a DMG game is drawn with on the CGB, as BGR555
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompatibilityPalettes {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+impl CompatibilityPalettes {
+    /// Picks the palettes the boot ROM would for this cartridge
+    pub fn for_cartridge(cartridge: &Cartridge) -> CompatibilityPalettes {
+        // Only Nintendo's own games are looked up
+        let combination = if cartridge.is_licensed_by_nintendo() {
+            game_combination(cartridge.title_checksum(), cartridge.rom[0x0137])
+        } else {
+            0
+        };
+
+        CompatibilityPalettes::from_combination(combination)
+    }
+
+    pub fn manual(palette: ManualPalette) -> CompatibilityPalettes {
+        CompatibilityPalettes::from_combination(palette.combination())
+    }
+
+    fn from_combination(combination: usize) -> CompatibilityPalettes {
+        let [obj0, obj1, bg] = COMBINATIONS[combination];
+        let colors = |start: u8| -> [u16; 4] {
+            let start = start as usize;
+            PALETTES[start..start + 4].try_into().unwrap()
+        };
+
+        CompatibilityPalettes {
+            bg: colors(bg),
+            obj0: colors(obj0),
+            obj1: colors(obj1),
+        }
+    }
+}
+
+// Returns the combination for a title checksum, or the default one for
+// games that aren't in the table
+fn game_combination(checksum: u8, fourth_letter: u8) -> usize {
+    let Some(index) = TITLE_CHECKSUMS.iter().position(|&c| c == checksum) else {
+        return 0;
+    };
+
+    if index < FIRST_SHARED_CHECKSUM {
+        return GAME_COMBINATIONS[index] as usize;
+    }
+
+    (index - FIRST_SHARED_CHECKSUM..FOURTH_LETTERS.len())
+        .step_by(SHARED_CHECKSUMS)
+        .find(|&i| FOURTH_LETTERS[i] == fourth_letter)
+        .map_or(0, |i| GAME_COMBINATIONS[FIRST_SHARED_CHECKSUM + i] as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cartridge(title: &str) -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title.as_bytes());
+        rom[0x014B] = 0x01;
+
+        Cartridge::new(rom)
+    }
+
+    #[test]
+    fn test_game_lookup() {
+        // Tetris is drawn in orange
+        let palettes = CompatibilityPalettes::for_cartridge(&cartridge("TETRIS"));
+        assert_eq!(
+            palettes,
+            CompatibilityPalettes::manual(ManualPalette::DownA)
+        );
+
+        // Pokemon Red and Blue share a checksum with other games
+        let palettes = CompatibilityPalettes::for_cartridge(&cartridge("POKEMON BLUE"));
+        assert_eq!(palettes.bg, [0x7FFF, 0x7E8C, 0x7C00, 0x0000]);
+        assert_eq!(palettes.obj0, [0x7FFF, 0x421F, 0x1CF2, 0x0000]);
+    }
+
+    #[test]
+    fn test_default_palette() {
+        let default = CompatibilityPalettes::manual(ManualPalette::RightA);
+
+        assert_eq!(
+            CompatibilityPalettes::for_cartridge(&cartridge("NOT A REAL GAME")),
+            default
+        );
+
+        // Only Nintendo's games are looked up
+        let mut unlicensed = cartridge("TETRIS");
+        unlicensed.rom[0x014B] = 0x00;
+        assert_eq!(CompatibilityPalettes::for_cartridge(&unlicensed), default);
+    }
+
+    #[test]
+    fn test_manual_palettes() {
+        // Left + B is grayscale
+        let palettes = CompatibilityPalettes::manual(ManualPalette::LeftB);
+        assert_eq!(palettes.bg, [0x7FFF, 0x5294, 0x294A, 0x0000]);
+        assert_eq!(palettes.obj0, palettes.bg);
+        assert_eq!(palettes.obj1, palettes.bg);
+
+        // Down + B has blue and green objects on yellow
+        let palettes = CompatibilityPalettes::manual(ManualPalette::DownB);
+        assert_eq!(palettes.bg, [0x7FFF, 0x03FF, 0x012F, 0x0000]);
+        assert_eq!(palettes.obj0, [0x7FFF, 0x7E8C, 0x7C00, 0x0000]);
+        assert_eq!(palettes.obj1, [0x7FFF, 0x1BEF, 0x0200, 0x0000]);
+    }
+
+    #[test]
+    fn test_button_combos() {
+        assert_eq!(
+            ManualPalette::from_buttons(&[Button::A, Button::Left]),
+            Some(ManualPalette::LeftA)
+        );
+        assert_eq!(ManualPalette::from_buttons(&[Button::B]), None);
+        assert_eq!("up+b".parse::<ManualPalette>().unwrap(), ManualPalette::UpB);
+        assert!("a+b".parse::<ManualPalette>().is_err());
+    }
+}
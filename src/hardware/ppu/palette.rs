@@ -0,0 +1,142 @@
+use anyhow::Result;
+
+use crate::hardware::state::{Snapshot, StateReader, StateWriter};
+
+// CGB palette memory, holding 8 palettes of 4 colors each. Colors are 15-bit
+// BGR555, stored little endian.
+// https://gbdev.io/pandocs/Palettes.html#lcd-color-palettes-cgb-only
+pub struct PaletteRAM {
+    data: [u8; 64],
+
+    // BCPS/OCPS: the address of the next access, and whether it increments
+    // after each write
+    index: u8,
+    auto_increment: bool,
+}
+
+impl PaletteRAM {
+    pub fn new() -> PaletteRAM {
+        PaletteRAM {
+            data: [0xFF; 64],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    pub fn read_spec(&self) -> u8 {
+        0x40 | ((self.auto_increment as u8) << 7) | self.index
+    }
+
+    pub fn write_spec(&mut self, value: u8) {
+        self.index = value & 0x3F;
+        self.auto_increment = value & 0x80 != 0;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    /// Returns a color as 15-bit BGR555
+    pub fn color(&self, palette: u8, color: u8) -> u16 {
+        let offset = (palette as usize * 8) + (color as usize * 2);
+
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+
+    pub fn set_color(&mut self, palette: u8, color: u8, value: u16) {
+        let offset = (palette as usize * 8) + (color as usize * 2);
+
+        self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+impl Default for PaletteRAM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Converts a BGR555 color to 8-bit RGB. The CGB LCD is darker and less
+/// saturated than a modern display, which color correction approximates.
+pub fn bgr555_to_rgb(color: u16, color_correction: bool) -> [u8; 3] {
+    let r = (color & 0x1F) as u32;
+    let g = ((color >> 5) & 0x1F) as u32;
+    let b = ((color >> 10) & 0x1F) as u32;
+
+    if color_correction {
+        // Mixes the channels, the same curve Gambatte uses
+        [
+            ((r * 13 + g * 2 + b) >> 1) as u8,
+            ((g * 3 + b) << 1) as u8,
+            ((r * 3 + g * 2 + b * 11) >> 1) as u8,
+        ]
+    } else {
+        // Scale to 8 bits, so 0x1F maps to 0xFF
+        [
+            ((r << 3) | (r >> 2)) as u8,
+            ((g << 3) | (g >> 2)) as u8,
+            ((b << 3) | (b >> 2)) as u8,
+        ]
+    }
+}
+
+impl Snapshot for PaletteRAM {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.data);
+        state.u8(self.index);
+        state.bool(self.auto_increment);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<()> {
+        state.bytes_into(&mut self.data)?;
+        self.index = state.u8()?;
+        self.auto_increment = state.bool()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_increment() {
+        let mut palettes = PaletteRAM::new();
+        palettes.write_spec(0x80 | 0x3E);
+        assert_eq!(palettes.read_spec(), 0xFE);
+
+        palettes.write_data(0x1F);
+        palettes.write_data(0x00);
+        assert_eq!(palettes.color(7, 3), 0x001F);
+
+        // The index wraps around
+        assert_eq!(palettes.read_spec(), 0xC0);
+
+        // Without auto increment, writes keep going to the same address
+        palettes.write_spec(0x02);
+        palettes.write_data(0xE0);
+        palettes.write_data(0x03);
+        assert_eq!(palettes.read_data(), 0x03);
+        assert_eq!(palettes.read_spec(), 0x42);
+    }
+
+    #[test]
+    fn test_bgr555_to_rgb() {
+        assert_eq!(bgr555_to_rgb(0x7FFF, false), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(bgr555_to_rgb(0x001F, false), [0xFF, 0x00, 0x00]);
+        assert_eq!(bgr555_to_rgb(0x7C00, false), [0x00, 0x00, 0xFF]);
+
+        // Corrected red bleeds into blue
+        let [r, g, b] = bgr555_to_rgb(0x001F, true);
+        assert!(r > b && b > g);
+    }
+}
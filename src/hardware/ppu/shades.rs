@@ -0,0 +1,137 @@
+use std::{fs, str::FromStr};
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+/// The colors the 4 DMG shades are shown as, lightest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShadePalette {
+    #[default]
+    Grayscale,
+
+    /// The green tint of the original Game Boy screen
+    ClassicGreen,
+
+    /// The Game Boy Pocket's screen
+    Pocket,
+
+    /// The Game Boy Light with its backlight on
+    Light,
+
+    Custom([[u8; 3]; 4]),
+}
+
+impl ShadePalette {
+    pub fn colors(&self) -> [[u8; 3]; 4] {
+        match self {
+            ShadePalette::Grayscale => [
+                [0xFF, 0xFF, 0xFF],
+                [0xAA, 0xAA, 0xAA],
+                [0x55, 0x55, 0x55],
+                [0x00, 0x00, 0x00],
+            ],
+            ShadePalette::ClassicGreen => [
+                [0x9B, 0xBC, 0x0F],
+                [0x8B, 0xAC, 0x0F],
+                [0x30, 0x62, 0x30],
+                [0x0F, 0x38, 0x0F],
+            ],
+            ShadePalette::Pocket => [
+                [0xC4, 0xCF, 0xA1],
+                [0x8B, 0x95, 0x6D],
+                [0x4D, 0x53, 0x3C],
+                [0x1F, 0x1F, 0x1F],
+            ],
+            ShadePalette::Light => [
+                [0x00, 0xB5, 0x81],
+                [0x00, 0x9A, 0x71],
+                [0x00, 0x69, 0x4A],
+                [0x00, 0x4F, 0x3B],
+            ],
+            ShadePalette::Custom(colors) => *colors,
+        }
+    }
+
+    /// Reads a custom palette from a file of 4 colors, lightest first, written
+    /// as RRGGBB hex and separated by whitespace or commas. Lines starting
+    /// with ';' are comments.
+    pub fn from_path(path: &str) -> Result<ShadePalette> {
+        let config = fs::read_to_string(path).with_context(|| format!("Can't read {}", path))?;
+
+        ShadePalette::from_config(&config).with_context(|| format!("Invalid palette in {}", path))
+    }
+
+    pub fn from_config(config: &str) -> Result<ShadePalette> {
+        let colors = config
+            .lines()
+            .filter(|line| !line.trim_start().starts_with(';'))
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|color| !color.is_empty())
+            .map(|color| {
+                let hex = color.trim_start_matches('#');
+                let value = u32::from_str_radix(hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 6)
+                    .ok_or_else(|| anyhow!("Not an RRGGBB color: {}", color))?;
+
+                let [_, r, g, b] = value.to_be_bytes();
+                Ok([r, g, b])
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        match colors.try_into() {
+            Ok(colors) => Ok(ShadePalette::Custom(colors)),
+            Err(colors) => bail!("Expected 4 colors, found {}", colors.len()),
+        }
+    }
+}
+
+impl FromStr for ShadePalette {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "grayscale" | "gray" => Ok(ShadePalette::Grayscale),
+            "green" | "dmg" => Ok(ShadePalette::ClassicGreen),
+            "pocket" | "mgb" => Ok(ShadePalette::Pocket),
+            "light" => Ok(ShadePalette::Light),
+            _ => Err(anyhow!(
+                "Unknown shade palette: {}, try grayscale, green, pocket or light",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_config() {
+        let palette =
+            ShadePalette::from_config("; Lightest first\n#FFFFFF, C0C0C0\n808080 000000\n")
+                .unwrap();
+
+        assert_eq!(
+            palette.colors(),
+            [
+                [0xFF, 0xFF, 0xFF],
+                [0xC0, 0xC0, 0xC0],
+                [0x80, 0x80, 0x80],
+                [0x00, 0x00, 0x00]
+            ]
+        );
+
+        assert!(ShadePalette::from_config("FFFFFF C0C0C0 808080").is_err());
+        assert!(ShadePalette::from_config("FFFFFF C0C0C0 808080 00000G").is_err());
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(
+            "Pocket".parse::<ShadePalette>().unwrap(),
+            ShadePalette::Pocket
+        );
+        assert!("sepia".parse::<ShadePalette>().is_err());
+    }
+}
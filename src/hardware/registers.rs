@@ -58,6 +58,20 @@ impl Registers {
         }
     }
 
+    // Registers at power on, before the boot ROM has run
+    pub fn zeroed() -> Registers {
+        Registers {
+            a: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            f: Flags::new(),
+            h: 0,
+            l: 0,
+        }
+    }
+
     pub fn af(&self) -> u16 {
         let f: u8 = self.f.into();
         ((self.a as u16) << 8) | (f as u16)
@@ -85,7 +99,7 @@ impl Registers {
         self.c = (value & 0x00FF) as u8;
     }
 
-    pub fn interrupt_disable_counter(&mut self, value: u16) {
+    pub fn set_de(&mut self, value: u16) {
         self.d = ((value & 0xFF00) >> 8) as u8;
         self.e = (value & 0x00FF) as u8;
     }
@@ -96,6 +110,12 @@ impl Registers {
     }
 }
 
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Display for Registers {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -119,10 +139,10 @@ mod tests {
     #[test]
     fn test_flags() {
         let mut flags = Flags::new();
-        assert_eq!(flags.carry(), false);
-        assert_eq!(flags.half_carry(), false);
-        assert_eq!(flags.subtract(), false);
-        assert_eq!(flags.zero(), false);
+        assert!(!flags.carry());
+        assert!(!flags.half_carry());
+        assert!(!flags.subtract());
+        assert!(!flags.zero());
 
         assert_eq!(0b0000_0000u8, flags.into());
 
@@ -130,10 +150,10 @@ mod tests {
         flags.set_half_carry(true);
         flags.set_subtract(true);
         flags.set_zero(true);
-        assert_eq!(flags.carry(), true);
-        assert_eq!(flags.half_carry(), true);
-        assert_eq!(flags.subtract(), true);
-        assert_eq!(flags.zero(), true);
+        assert!(flags.carry());
+        assert!(flags.half_carry());
+        assert!(flags.subtract());
+        assert!(flags.zero());
         assert_eq!(0b1111_0000u8, flags.into());
 
         flags.set_carry(false);
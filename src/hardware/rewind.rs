@@ -0,0 +1,439 @@
+use std::collections::VecDeque;
+
+use anyhow::{bail, Result};
+
+use super::{io::joypad::Button, machine::Machine, movie::Input};
+
+// Each keyframe is followed by this many captures stored as deltas against it
+const DELTAS_PER_KEYFRAME: usize = 30;
+
+// Captures every 5 frames, within 32 MiB, which is several minutes of most games
+const DEFAULT_INTERVAL: u64 = 5;
+const DEFAULT_BUDGET: usize = 32 * 1024 * 1024;
+
+// A save state, compressed. Deltas are XORed against the keyframe before
+// them, which leaves long runs of zeros wherever the state didn't change.
+enum Capture {
+    Keyframe {
+        frame: u64,
+        cycle: u64,
+        data: Vec<u8>,
+    },
+    Delta {
+        frame: u64,
+        cycle: u64,
+        data: Vec<u8>,
+    },
+}
+
+impl Capture {
+    fn frame(&self) -> u64 {
+        match self {
+            Capture::Keyframe { frame, .. } | Capture::Delta { frame, .. } => *frame,
+        }
+    }
+
+    fn cycle(&self) -> u64 {
+        match self {
+            Capture::Keyframe { cycle, .. } | Capture::Delta { cycle, .. } => *cycle,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Capture::Keyframe { data, .. } | Capture::Delta { data, .. } => data.len(),
+        }
+    }
+}
+
+/// Keeps save states from the recent past, so the machine can be sent back
+/// in time. Old history is dropped to stay within a memory budget.
+pub struct Rewind {
+    // Frames between captures
+    interval: u64,
+
+    // Bytes of compressed states to keep at most
+    budget: usize,
+    used: usize,
+
+    captures: VecDeque<Capture>,
+
+    // The uncompressed state the latest deltas are against
+    keyframe: Vec<u8>,
+    deltas: usize,
+
+    // Joypad changes since the oldest capture, replayed when frames after a
+    // capture are run again
+    inputs: VecDeque<Input>,
+}
+
+impl Rewind {
+    pub fn new(interval: u64, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            used: 0,
+            captures: VecDeque::new(),
+            keyframe: Vec::new(),
+            deltas: 0,
+            inputs: VecDeque::new(),
+        }
+    }
+
+    /// Returns the number of states kept
+    pub fn len(&self) -> usize {
+        self.captures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.captures.is_empty()
+    }
+
+    /// Returns the bytes used by the compressed states
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    /// Returns the earliest frame that can be rewound to
+    pub fn oldest_frame(&self) -> Option<u64> {
+        self.captures.front().map(Capture::frame)
+    }
+
+    /// Presses or releases a button, keeping it to replay after a rewind.
+    /// Joypad changes have to go through here for rewinding to repeat them.
+    pub fn set_button(&mut self, machine: &mut Machine, button: Button, pressed: bool) {
+        machine.cpu.bus.set_button(button, pressed);
+
+        self.inputs.push_back(Input {
+            frame: machine.frames(),
+            cycle: machine.cycles(),
+            button,
+            pressed,
+        });
+    }
+
+    /// Captures the machine's state if it's due. Call this after every frame.
+    pub fn capture(&mut self, machine: &Machine) {
+        let frame = machine.frames();
+
+        if !frame.is_multiple_of(self.interval)
+            || self.captures.back().map(Capture::frame) == Some(frame)
+        {
+            return;
+        }
+
+        let state = machine.save_state();
+        let cycle = machine.cycles();
+
+        let capture = if self.keyframe.is_empty() || self.deltas == DELTAS_PER_KEYFRAME {
+            let data = compress(&state);
+            self.keyframe = state;
+            self.deltas = 0;
+
+            Capture::Keyframe { frame, cycle, data }
+        } else {
+            self.deltas += 1;
+
+            Capture::Delta {
+                frame,
+                cycle,
+                data: compress(&xor(&self.keyframe, &state)),
+            }
+        };
+
+        self.used += capture.size();
+        self.captures.push_back(capture);
+        self.trim();
+    }
+
+    /// Sends the machine back the given number of frames. The nearest state
+    /// captured at or before then is restored, and the frames after it are
+    /// run again with the same joypad changes. History after that point is
+    /// dropped.
+    pub fn rewind(&mut self, machine: &mut Machine, frames: u64) -> Result<()> {
+        let target = machine.frames().saturating_sub(frames);
+
+        let Some(index) = self.captures.iter().rposition(|c| c.frame() <= target) else {
+            bail!(
+                "Can't rewind to frame {}, the history doesn't go back that far",
+                target
+            );
+        };
+
+        let keyframe_index = (0..=index)
+            .rev()
+            .find(|&i| matches!(self.captures[i], Capture::Keyframe { .. }))
+            .expect("History should start with a keyframe");
+
+        let Capture::Keyframe { data, .. } = &self.captures[keyframe_index] else {
+            unreachable!();
+        };
+        let keyframe = decompress(data)?;
+
+        let state = match &self.captures[index] {
+            Capture::Keyframe { .. } => keyframe.clone(),
+            Capture::Delta { data, .. } => xor(&keyframe, &decompress(data)?),
+        };
+
+        machine.load_state(&state)?;
+
+        // Changes from before the capture are already part of its state
+        let start = self.captures[index].cycle();
+        let replayed: Vec<Input> = self
+            .inputs
+            .iter()
+            .filter(|input| input.cycle >= start)
+            .copied()
+            .collect();
+        let mut inputs = replayed.iter().peekable();
+
+        // Later captures are against a future that's being replaced
+        while self.captures.len() > index + 1 {
+            let capture = self.captures.pop_back().unwrap();
+            self.used -= capture.size();
+        }
+        self.keyframe = keyframe;
+        self.deltas = index - keyframe_index;
+
+        while machine.frames() < target {
+            machine.run_frame_with(|machine| {
+                while let Some(input) = inputs.next_if(|i| i.cycle <= machine.cycles()) {
+                    machine.cpu.bus.set_button(input.button, input.pressed);
+                }
+            })?;
+        }
+
+        let now = machine.cycles();
+        self.inputs.retain(|input| input.cycle < now);
+
+        Ok(())
+    }
+
+    // Drops the oldest keyframes, along with their deltas, until the history
+    // fits the budget. The latest keyframe is always kept.
+    fn trim(&mut self) {
+        while self.used > self.budget {
+            let Some(next) = self
+                .captures
+                .iter()
+                .skip(1)
+                .position(|c| matches!(c, Capture::Keyframe { .. }))
+            else {
+                return;
+            };
+
+            for capture in self.captures.drain(..=next) {
+                self.used -= capture.size();
+            }
+
+            let oldest = self.captures.front().map_or(0, Capture::cycle);
+            while self
+                .inputs
+                .front()
+                .is_some_and(|input| input.cycle < oldest)
+            {
+                self.inputs.pop_front();
+            }
+        }
+    }
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Self::new(DEFAULT_INTERVAL, DEFAULT_BUDGET)
+    }
+}
+
+// XORs two states together. States can differ in length, the shorter one is
+// treated as padded with zeros and the length of the second is kept.
+fn xor(keyframe: &[u8], state: &[u8]) -> Vec<u8> {
+    state
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ keyframe.get(i).unwrap_or(&0))
+        .collect()
+}
+
+// Run length encodes zeros. The output is a series of zero run lengths, each
+// followed by the number of literal bytes after it and the bytes themselves.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    let mut position = 0;
+
+    while position < data.len() {
+        let zeros = data[position..].iter().take_while(|&&b| b == 0).count();
+        position += zeros;
+
+        // Literals run until two zeros in a row, which are worth a new run
+        let start = position;
+        while position < data.len() && !data[position..].starts_with(&[0, 0]) {
+            position += 1;
+        }
+
+        write_length(&mut compressed, zeros);
+        write_length(&mut compressed, position - start);
+        compressed.extend_from_slice(&data[start..position]);
+    }
+
+    compressed
+}
+
+fn decompress(compressed: &[u8]) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut position = 0;
+
+    while position < compressed.len() {
+        let zeros = read_length(compressed, &mut position)?;
+        let literals = read_length(compressed, &mut position)?;
+
+        let Some(bytes) = compressed.get(position..position + literals) else {
+            bail!("Compressed state is truncated");
+        };
+
+        data.resize(data.len() + zeros, 0);
+        data.extend_from_slice(bytes);
+        position += literals;
+    }
+
+    Ok(data)
+}
+
+// Lengths are stored 7 bits at a time, with the top bit set on all but the last
+fn write_length(compressed: &mut Vec<u8>, mut length: usize) {
+    while length >= 0x80 {
+        compressed.push(0x80 | (length & 0x7F) as u8);
+        length >>= 7;
+    }
+
+    compressed.push(length as u8);
+}
+
+fn read_length(compressed: &[u8], position: &mut usize) -> Result<usize> {
+    let mut length = 0;
+    let mut shift = 0;
+
+    loop {
+        let Some(&byte) = compressed.get(*position) else {
+            bail!("Compressed state is truncated");
+        };
+        *position += 1;
+
+        length |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(length);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::{bus::Bus, cartridge::Cartridge, model::Model};
+
+    // Keeps changing VRAM and the scroll registers, so every frame differs
+    fn machine() -> Machine {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x0150..0x015B].copy_from_slice(&[
+            0x21, 0x00, 0x80, 0x3C, 0x22, 0xE0, 0x43, 0xCB, 0xAC, 0x18, 0xF8,
+        ]);
+
+        Machine::new(Bus::new(Cartridge::new(rom), Model::DMG))
+    }
+
+    #[test]
+    fn test_compress() {
+        for data in [
+            vec![],
+            vec![0; 1000],
+            vec![1, 2, 3],
+            [vec![0; 300], vec![5, 0, 6], vec![0; 200], vec![7; 200]].concat(),
+        ] {
+            let compressed = compress(&data);
+            assert_eq!(decompress(&compressed).unwrap(), data);
+        }
+
+        assert!(compress(&[0; 4096]).len() < 8);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut machine = machine();
+        let mut rewind = Rewind::new(2, DEFAULT_BUDGET);
+        let mut states = Vec::new();
+
+        for _ in 0..40 {
+            machine.run_frame().unwrap();
+            rewind.capture(&machine);
+            states.push(machine.save_state());
+        }
+
+        // Even with VRAM and the picture changing all the time, the deltas
+        // take up far less than the states themselves
+        assert_eq!(rewind.len(), 20);
+        assert!(rewind.memory_used() < states[0].len() * 20 / 2);
+
+        // Frame 33 isn't captured, so it's run again from frame 32
+        rewind.rewind(&mut machine, 7).unwrap();
+        assert_eq!(machine.frames(), 33);
+        assert_eq!(machine.save_state(), states[32]);
+        assert_eq!(rewind.len(), 16);
+
+        // History carries on from there
+        machine.run_frame().unwrap();
+        rewind.capture(&machine);
+        rewind.rewind(&mut machine, 30).unwrap();
+        assert_eq!(machine.save_state(), states[3]);
+
+        assert!(rewind.rewind(&mut machine, 10).is_err());
+    }
+
+    #[test]
+    fn test_rewind_inputs() {
+        let mut machine = machine();
+        let mut rewind = Rewind::new(5, DEFAULT_BUDGET);
+        let mut states = vec![machine.save_state()];
+
+        // Start goes down partway into frame 7 and comes back up in frame 8,
+        // between the captures at frames 5 and 10
+        for frame in 0..12 {
+            if frame == 7 || frame == 8 {
+                for _ in 0..100 {
+                    machine.step().unwrap();
+                }
+                rewind.set_button(&mut machine, Button::Start, frame == 7);
+            }
+
+            machine.run_frame().unwrap();
+            rewind.capture(&machine);
+            states.push(machine.save_state());
+        }
+
+        rewind.rewind(&mut machine, 3).unwrap();
+        assert_eq!(machine.frames(), 9);
+        assert_eq!(machine.save_state(), states[9]);
+
+        rewind.rewind(&mut machine, 1).unwrap();
+        assert_eq!(machine.save_state(), states[8]);
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut machine = machine();
+        let mut rewind = Rewind::new(1, 0);
+
+        for _ in 0..(DELTAS_PER_KEYFRAME + 1) * 3 {
+            machine.run_frame().unwrap();
+            rewind.capture(&machine);
+        }
+
+        // Only the latest keyframe and its deltas are left
+        assert_eq!(rewind.len(), DELTAS_PER_KEYFRAME + 1);
+        assert_eq!(
+            rewind.oldest_frame(),
+            Some((DELTAS_PER_KEYFRAME as u64 + 1) * 2 + 1)
+        );
+    }
+}
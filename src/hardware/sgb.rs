@@ -0,0 +1,791 @@
+use anyhow::{bail, Result};
+
+use super::ppu::{palette::bgr555_to_rgb, SCREEN_HEIGHT, SCREEN_WIDTH};
+use super::state::{Snapshot, StateReader, StateWriter};
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+
+// Where the Game Boy screen sits within the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+// The screen is colored in 8x8 cells
+const CELLS_X: usize = SCREEN_WIDTH / 8;
+const CELLS_Y: usize = SCREEN_HEIGHT / 8;
+
+// Bytes in an attribute file, which holds a palette for each cell
+const ATTRIBUTE_FILE_SIZE: usize = CELLS_X * CELLS_Y / 4;
+
+// The palette the SGB starts with, shades of tan and brown
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+// Commands that transfer a block of data by displaying it on screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    Palettes,
+    Tiles(usize),
+    Border,
+    Attributes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mask {
+    None,
+    Freeze,
+    Black,
+    Color0,
+}
+
+// The Super Game Boy receives commands from the game as packets of 16 bytes,
+// sent a bit at a time through the joypad select lines. It colors the screen
+// with 4 palettes, and draws a border around it.
+// https://gbdev.io/pandocs/SGB_Functions.html
+pub struct SGB {
+    // Games need to declare SGB support in their header
+    enabled: bool,
+
+    // The packet being received, and the bits received of it so far
+    packet: [u8; 16],
+    bits: usize,
+    receiving: bool,
+
+    // The previous write to P1, bit transfers start on a change from both
+    // lines high
+    last_select: u8,
+
+    // Commands can span several packets
+    command: Vec<u8>,
+    packets_left: usize,
+
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,
+    attributes: [u8; CELLS_X * CELLS_Y],
+    attribute_files: Vec<u8>,
+
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    border_palettes: [[u16; 16]; 4],
+
+    mask: Mask,
+    frozen: Vec<u16>,
+    transfer: Option<Transfer>,
+
+    // Multiplayer, with the joypad currently being read
+    players: u8,
+    player: u8,
+}
+
+impl SGB {
+    pub fn new(enabled: bool) -> SGB {
+        SGB {
+            enabled,
+            packet: [0; 16],
+            bits: 0,
+            receiving: false,
+            last_select: 0x30,
+            command: Vec::new(),
+            packets_left: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; 512 * 4],
+            attributes: [0; CELLS_X * CELLS_Y],
+            attribute_files: vec![0; 45 * ATTRIBUTE_FILE_SIZE],
+            border_tiles: vec![0; 256 * 32],
+            border_map: vec![0; 32 * 32],
+            border_palettes: [[0; 16]; 4],
+            mask: Mask::None,
+            frozen: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            transfer: None,
+            players: 1,
+            player: 0,
+        }
+    }
+
+    /// Watches writes to P1 for packet bits and multiplayer polling
+    pub fn write_joypad(&mut self, value: u8) {
+        let select = value & 0x30;
+        let last = std::mem::replace(&mut self.last_select, select);
+
+        // The next joypad is selected when P15 goes high again
+        if self.players > 1 && last & 0x20 == 0 && select & 0x20 != 0 {
+            self.player = (self.player + 1) % self.players;
+        }
+
+        if !self.enabled || select == last {
+            return;
+        }
+
+        match select {
+            // Both lines low resets the transfer
+            0x00 => {
+                self.receiving = true;
+                self.bits = 0;
+                self.packet = [0; 16];
+            }
+
+            // P14 low sends a 0, P15 low sends a 1
+            0x10 | 0x20 if self.receiving && last == 0x30 => {
+                let bit = (select == 0x10) as u8;
+
+                if self.bits == 128 {
+                    // The stop bit, which should be a 0
+                    self.receiving = false;
+
+                    if bit == 0 {
+                        self.receive_packet();
+                    }
+                } else {
+                    self.packet[self.bits / 8] |= bit << (self.bits % 8);
+                    self.bits += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns what reading P1 gives, replacing the ID of the selected joypad
+    /// when more than one is connected
+    pub fn read_joypad(&self, value: u8) -> u8 {
+        if self.players == 1 {
+            return value;
+        }
+
+        if value & 0x30 == 0x30 {
+            (value & 0xF0) | (0x0F - self.player)
+        } else if self.player != 0 {
+            // Only the first joypad has anything pressed
+            value | 0x0F
+        } else {
+            value
+        }
+    }
+
+    fn receive_packet(&mut self) {
+        if self.packets_left == 0 {
+            self.command.clear();
+            self.packets_left = (self.packet[0] & 0x07).max(1) as usize;
+        }
+
+        self.command.extend_from_slice(&self.packet);
+        self.packets_left -= 1;
+
+        if self.packets_left == 0 {
+            let command = std::mem::take(&mut self.command);
+            self.run_command(&command);
+        }
+    }
+
+    fn run_command(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            // PAL01, PAL23, PAL03, PAL12
+            0x00 => self.set_palettes(0, 1, data),
+            0x01 => self.set_palettes(2, 3, data),
+            0x02 => self.set_palettes(0, 3, data),
+            0x03 => self.set_palettes(1, 2, data),
+
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            0x0A => self.pal_set(data),
+            0x0B => self.transfer = Some(Transfer::Palettes),
+
+            // MLT_REQ
+            0x11 => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+
+            0x13 => self.transfer = Some(Transfer::Tiles((data[1] & 0x01) as usize * 128)),
+            0x14 => self.transfer = Some(Transfer::Border),
+            0x15 => self.transfer = Some(Transfer::Attributes),
+
+            // ATTR_SET
+            0x16 => {
+                self.apply_attribute_file((data[1] & 0x3F) as usize);
+
+                if data[1] & 0x40 != 0 {
+                    self.mask = Mask::None;
+                }
+            }
+
+            // MASK_EN
+            0x17 => {
+                self.mask = match data[1] & 0x03 {
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    3 => Mask::Color0,
+                    _ => Mask::None,
+                }
+            }
+
+            // Sound, SNES code, and other commands that don't affect the
+            // picture are ignored
+            _ => {}
+        }
+    }
+
+    fn color(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([data[offset], data[offset + 1]]) & 0x7FFF
+    }
+
+    // Color 0 is shared by all palettes, so setting it for one sets it for all
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color0 = Self::color(data, 1);
+
+        for palette in &mut self.palettes {
+            palette[0] = color0;
+        }
+
+        for color in 1..4 {
+            self.palettes[first][color] = Self::color(data, 1 + color * 2);
+            self.palettes[second][color] = Self::color(data, 7 + color * 2);
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = (data[1] as usize).min(18);
+
+        for set in data[2..].chunks_exact(6).take(count) {
+            let control = set[0];
+            let inside = set[1] & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+
+            // With only one of inside or outside selected, the border goes
+            // along with it
+            let border = match control & 0x07 {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                _ if control & 0x02 != 0 => Some((set[1] >> 2) & 0x03),
+                _ => None,
+            };
+
+            let (x1, y1, x2, y2) = (
+                set[2] as usize,
+                set[3] as usize,
+                set[4] as usize,
+                set[5] as usize,
+            );
+
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let within = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let on_border = within && (x == x1 || x == x2 || y == y1 || y == y2);
+
+                    let palette = if on_border {
+                        border
+                    } else if within && control & 0x01 != 0 {
+                        Some(inside)
+                    } else if !within && control & 0x04 != 0 {
+                        Some(outside)
+                    } else {
+                        None
+                    };
+
+                    if let Some(palette) = palette {
+                        self.attributes[y * CELLS_X + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+
+        for &line in data[2..].iter().take(count) {
+            let index = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+
+            if line & 0x80 != 0 {
+                if index < CELLS_Y {
+                    self.attributes[index * CELLS_X..(index + 1) * CELLS_X].fill(palette);
+                }
+            } else if index < CELLS_X {
+                for y in 0..CELLS_Y {
+                    self.attributes[y * CELLS_X + index] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let division = data[2] as usize;
+
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if horizontal { y } else { x };
+
+                self.attributes[y * CELLS_X + x] = match position.cmp(&division) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = data[1] as usize;
+        let mut y = data[2] as usize;
+        let count = u16::from_le_bytes([data[3], data[4]]) as usize;
+        let vertical = data[5] & 0x01 != 0;
+
+        for index in 0..count.min(CELLS_X * CELLS_Y) {
+            let Some(&byte) = data.get(6 + index / 4) else {
+                break;
+            };
+
+            if x >= CELLS_X || y >= CELLS_Y {
+                break;
+            }
+
+            self.attributes[y * CELLS_X + x] = (byte >> (6 - (index % 4) * 2)) & 0x03;
+
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn pal_set(&mut self, data: &[u8]) {
+        for palette in 0..4 {
+            let number = (u16::from_le_bytes([data[1 + palette * 2], data[2 + palette * 2]])
+                & 0x1FF) as usize;
+
+            self.palettes[palette]
+                .copy_from_slice(&self.system_palettes[number * 4..number * 4 + 4]);
+        }
+
+        if data[9] & 0x80 != 0 {
+            self.apply_attribute_file((data[9] & 0x3F) as usize);
+        }
+
+        if data[9] & 0x40 != 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    fn apply_attribute_file(&mut self, file: usize) {
+        if file >= 45 {
+            return;
+        }
+
+        let offset = file * ATTRIBUTE_FILE_SIZE;
+
+        for (cell, attribute) in self.attributes.iter_mut().enumerate() {
+            let byte = self.attribute_files[offset + cell / 4];
+            *attribute = (byte >> (6 - (cell % 4) * 2)) & 0x03;
+        }
+    }
+
+    /// Called with each completed frame, which is what VRAM transfers read
+    pub fn frame_completed(&mut self, screen: &[u16]) {
+        if self.mask != Mask::Freeze {
+            self.frozen.copy_from_slice(screen);
+        }
+
+        let Some(transfer) = self.transfer.take() else {
+            return;
+        };
+
+        let data = Self::screen_data(screen);
+
+        match transfer {
+            Transfer::Palettes => {
+                for (index, color) in self.system_palettes.iter_mut().enumerate() {
+                    *color = Self::color(&data, index * 2);
+                }
+            }
+            Transfer::Tiles(first) => {
+                self.border_tiles[first * 32..(first + 128) * 32].copy_from_slice(&data);
+            }
+            Transfer::Border => {
+                for (index, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+                }
+
+                for (palette, colors) in self.border_palettes.iter_mut().enumerate() {
+                    for (color, value) in colors.iter_mut().enumerate() {
+                        *value = Self::color(&data, 0x800 + palette * 32 + color * 2);
+                    }
+                }
+            }
+            Transfer::Attributes => {
+                let size = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&data[..size]);
+            }
+        }
+    }
+
+    // Turns the screen back into the 4KB of tile data the game displayed.
+    // The tiles are read left to right, 20 to a row.
+    fn screen_data(screen: &[u16]) -> Vec<u8> {
+        let mut data = vec![0; 0x1000];
+
+        for (tile, bytes) in data.chunks_exact_mut(16).enumerate() {
+            let tile_x = (tile % CELLS_X) * 8;
+            let tile_y = (tile / CELLS_X) * 8;
+
+            for row in 0..8 {
+                for column in 0..8 {
+                    let shade = screen[(tile_y + row) * SCREEN_WIDTH + tile_x + column] as u8;
+                    let bit = 7 - column;
+
+                    bytes[row * 2] |= (shade & 0x01) << bit;
+                    bytes[row * 2 + 1] |= ((shade >> 1) & 0x01) << bit;
+                }
+            }
+        }
+
+        data
+    }
+
+    // Returns the color of a border pixel, or None where it's transparent
+    fn border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = self.border_map[(y / 8) * 32 + (x / 8)];
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0x07) as usize;
+
+        let mut row = y % 8;
+        let mut column = x % 8;
+
+        if entry & 0x4000 != 0 {
+            column = 7 - column;
+        }
+
+        if entry & 0x8000 != 0 {
+            row = 7 - row;
+        }
+
+        // SNES tiles have 4 bit planes, stored in pairs
+        let tile = &self.border_tiles[tile * 32..tile * 32 + 32];
+        let bit = 7 - column;
+        let color = ((tile[row * 2] >> bit) & 1)
+            | (((tile[row * 2 + 1] >> bit) & 1) << 1)
+            | (((tile[16 + row * 2] >> bit) & 1) << 2)
+            | (((tile[16 + row * 2 + 1] >> bit) & 1) << 3);
+
+        // Only palettes 4 to 7 can be used for the border
+        match (color, palette) {
+            (0, _) | (_, 0..=3) => None,
+            _ => Some(self.border_palettes[palette - 4][color as usize]),
+        }
+    }
+
+    /// Returns the screen, colored and surrounded by the border, as 8-bit
+    /// RGB triplets. The screen is given as DMG shades.
+    pub fn frame_rgb(&self, screen: &[u16]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(SGB_WIDTH * SGB_HEIGHT * 3);
+        let screen = match self.mask {
+            Mask::Freeze => &self.frozen,
+            _ => screen,
+        };
+
+        for y in 0..SGB_HEIGHT {
+            for x in 0..SGB_WIDTH {
+                let in_screen = (SCREEN_X..SCREEN_X + SCREEN_WIDTH).contains(&x)
+                    && (SCREEN_Y..SCREEN_Y + SCREEN_HEIGHT).contains(&y);
+
+                let color = match self.border_pixel(x, y) {
+                    Some(color) => color,
+                    None if in_screen => {
+                        let (x, y) = (x - SCREEN_X, y - SCREEN_Y);
+                        let palette = self.attributes[(y / 8) * CELLS_X + x / 8] as usize;
+
+                        match self.mask {
+                            Mask::Black => 0x0000,
+                            Mask::Color0 => self.palettes[0][0],
+                            _ => self.palettes[palette][screen[y * SCREEN_WIDTH + x] as usize],
+                        }
+                    }
+                    None => self.palettes[0][0],
+                };
+
+                frame.extend_from_slice(&bgr555_to_rgb(color, false));
+            }
+        }
+
+        frame
+    }
+}
+
+// Whether the game enabled SGB features comes from the header, which the
+// state was checked against
+impl Snapshot for SGB {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.packet);
+        state.u8(self.bits as u8);
+        state.bool(self.receiving);
+        state.u8(self.last_select);
+        state.bytes(&self.command);
+        state.u8(self.packets_left as u8);
+
+        state.words(self.palettes.as_flattened());
+        state.words(&self.system_palettes);
+        state.bytes(&self.attributes);
+        state.bytes(&self.attribute_files);
+
+        state.bytes(&self.border_tiles);
+        state.words(&self.border_map);
+        state.words(self.border_palettes.as_flattened());
+
+        state.u8(match self.mask {
+            Mask::None => 0,
+            Mask::Freeze => 1,
+            Mask::Black => 2,
+            Mask::Color0 => 3,
+        });
+        state.words(&self.frozen);
+
+        match self.transfer {
+            None => state.u8(0),
+            Some(Transfer::Palettes) => state.u8(1),
+            Some(Transfer::Tiles(first)) => {
+                state.u8(2);
+                state.u8((first / 128) as u8);
+            }
+            Some(Transfer::Border) => state.u8(3),
+            Some(Transfer::Attributes) => state.u8(4),
+        }
+
+        state.u8(self.players);
+        state.u8(self.player);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<()> {
+        state.bytes_into(&mut self.packet)?;
+        self.bits = state.u8()? as usize;
+        self.receiving = state.bool()?;
+        self.last_select = state.u8()?;
+        self.command = state.bytes()?;
+        self.packets_left = state.u8()? as usize;
+
+        state.words_into(self.palettes.as_flattened_mut())?;
+        state.words_into(&mut self.system_palettes)?;
+        state.bytes_into(&mut self.attributes)?;
+        state.bytes_into(&mut self.attribute_files)?;
+
+        state.bytes_into(&mut self.border_tiles)?;
+        state.words_into(&mut self.border_map)?;
+        state.words_into(self.border_palettes.as_flattened_mut())?;
+
+        self.mask = match state.u8()? {
+            0 => Mask::None,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            value => bail!("Save state has an invalid SGB mask: {}", value),
+        };
+        state.words_into(&mut self.frozen)?;
+
+        self.transfer = match state.u8()? {
+            0 => None,
+            1 => Some(Transfer::Palettes),
+            2 => Some(Transfer::Tiles(state.u8()? as usize * 128)),
+            3 => Some(Transfer::Border),
+            4 => Some(Transfer::Attributes),
+            value => bail!("Save state has an invalid SGB transfer: {}", value),
+        };
+
+        self.players = state.u8()?;
+        self.player = state.u8()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sends packets the way games do, a bit per pulse on P14 or P15
+    fn send(sgb: &mut SGB, data: &[u8]) {
+        for packet in data.chunks(16) {
+            sgb.write_joypad(0x00);
+            sgb.write_joypad(0x30);
+
+            for index in 0..128 {
+                let bit = packet
+                    .get(index / 8)
+                    .map_or(0, |byte| (byte >> (index % 8)) & 1);
+
+                sgb.write_joypad(if bit == 1 { 0x10 } else { 0x20 });
+                sgb.write_joypad(0x30);
+            }
+
+            sgb.write_joypad(0x20);
+            sgb.write_joypad(0x30);
+        }
+    }
+
+    fn pixel(frame: &[u8], x: usize, y: usize) -> [u8; 3] {
+        let offset = (y * SGB_WIDTH + x) * 3;
+        frame[offset..offset + 3].try_into().unwrap()
+    }
+
+    #[test]
+    fn test_palettes() {
+        let mut sgb = SGB::new(true);
+
+        // PAL01, with red as color 0, green for palette 0 color 3 and blue
+        // for palette 1 color 3
+        send(
+            &mut sgb,
+            &[
+                0x01, 0x1F, 0x00, 0, 0, 0, 0, 0xE0, 0x03, 0, 0, 0, 0, 0x00, 0x7C,
+            ],
+        );
+
+        assert_eq!(sgb.palettes[0], [0x001F, 0, 0, 0x03E0]);
+        assert_eq!(sgb.palettes[1], [0x001F, 0, 0, 0x7C00]);
+        assert_eq!(sgb.palettes[3][0], 0x001F);
+
+        // ATTR_DIV, with palette 1 on the right half of the screen
+        send(&mut sgb, &[0x31, 0x01, 10]);
+
+        let mut screen = vec![3; SCREEN_WIDTH * SCREEN_HEIGHT];
+        screen[0] = 0;
+        let frame = sgb.frame_rgb(&screen);
+
+        assert_eq!(pixel(&frame, SCREEN_X, SCREEN_Y), [0xFF, 0, 0]);
+        assert_eq!(pixel(&frame, SCREEN_X + 1, SCREEN_Y), [0, 0xFF, 0]);
+        assert_eq!(pixel(&frame, SCREEN_X + 88, SCREEN_Y), [0, 0, 0xFF]);
+
+        // Outside the screen, without a border, color 0 shows
+        assert_eq!(pixel(&frame, 0, 0), [0xFF, 0, 0]);
+    }
+
+    #[test]
+    fn test_disabled_by_header() {
+        let mut sgb = SGB::new(false);
+        send(&mut sgb, &[0x01, 0x1F, 0x00]);
+
+        assert_eq!(sgb.palettes[0], DEFAULT_PALETTE);
+    }
+
+    #[test]
+    fn test_attr_blk() {
+        let mut sgb = SGB::new(true);
+
+        // Palette 1 inside and 2 on the border of (1, 1)-(3, 3), and 3
+        // outside it
+        send(&mut sgb, &[0x21, 0x01, 0x07, 0x39, 1, 1, 3, 3]);
+
+        assert_eq!(sgb.attributes[2 * CELLS_X + 2], 1);
+        assert_eq!(sgb.attributes[CELLS_X + 1], 2);
+        assert_eq!(sgb.attributes[3 * CELLS_X + 2], 2);
+        assert_eq!(sgb.attributes[0], 3);
+    }
+
+    #[test]
+    fn test_attr_chr() {
+        let mut sgb = SGB::new(true);
+
+        // Top to bottom, from (19, 16)
+        send(&mut sgb, &[0x39, 19, 16, 3, 0, 1, 0b01_10_11_00]);
+
+        assert_eq!(sgb.attributes[16 * CELLS_X + 19], 1);
+        assert_eq!(sgb.attributes[17 * CELLS_X + 19], 2);
+
+        // Wrapping around to the next column, which is off the screen
+        assert_eq!(sgb.attributes[0], 0);
+    }
+
+    #[test]
+    fn test_pal_trn() {
+        let mut sgb = SGB::new(true);
+
+        // Tile 0 of the transfer holds the first 4 colors: 0x7FFF, 0, 0, 0
+        let mut screen = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        screen[0..8].fill(3);
+        screen[SCREEN_WIDTH..SCREEN_WIDTH + 8].copy_from_slice(&[0, 3, 3, 3, 3, 3, 3, 3]);
+
+        send(&mut sgb, &[0x59]);
+        sgb.frame_completed(&screen);
+        assert_eq!(&sgb.system_palettes[0..4], &[0x7FFF, 0x7F7F, 0, 0]);
+
+        // PAL_SET, using system palette 0 for all 4 palettes
+        send(&mut sgb, &[0x51]);
+        assert_eq!(sgb.palettes[2], [0x7FFF, 0x7F7F, 0, 0]);
+    }
+
+    #[test]
+    fn test_border() {
+        let mut sgb = SGB::new(true);
+        let mut screen = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+
+        // CHR_TRN, where the first border tile is all color 1
+        for y in 0..8 {
+            screen[y * SCREEN_WIDTH..y * SCREEN_WIDTH + 8].fill(1);
+        }
+
+        send(&mut sgb, &[0x99, 0x00]);
+        sgb.frame_completed(&screen);
+
+        // PCT_TRN, using that tile with palette 4 at the top left, and red
+        // as its color 1
+        screen.fill(0);
+        screen[3] = 2;
+        screen[49 * SCREEN_WIDTH + 67..49 * SCREEN_WIDTH + 72].fill(1);
+
+        send(&mut sgb, &[0xA1]);
+        sgb.frame_completed(&screen);
+
+        let frame = sgb.frame_rgb(&screen);
+        assert_eq!(pixel(&frame, 0, 0), [0xFF, 0, 0]);
+        assert_eq!(pixel(&frame, 7, 7), [0xFF, 0, 0]);
+
+        // The rest of the border uses palette 0, which is transparent
+        assert_eq!(
+            pixel(&frame, 8, 0),
+            bgr555_to_rgb(DEFAULT_PALETTE[0], false)
+        );
+    }
+
+    #[test]
+    fn test_multiplayer() {
+        let mut sgb = SGB::new(true);
+        assert_eq!(sgb.read_joypad(0xFF), 0xFF);
+
+        // MLT_REQ for 2 players
+        send(&mut sgb, &[0x89, 0x01]);
+        assert_eq!(sgb.read_joypad(0xFF), 0xFF);
+
+        sgb.write_joypad(0x10);
+        sgb.write_joypad(0x30);
+        assert_eq!(sgb.read_joypad(0xFF), 0xFE);
+
+        // The second joypad has nothing pressed
+        assert_eq!(sgb.read_joypad(0xE0), 0xEF);
+    }
+
+    #[test]
+    fn test_mask() {
+        let mut sgb = SGB::new(true);
+
+        // MASK_EN black
+        send(&mut sgb, &[0xB9, 0x02]);
+
+        let frame = sgb.frame_rgb(&vec![0; SCREEN_WIDTH * SCREEN_HEIGHT]);
+        assert_eq!(pixel(&frame, SCREEN_X, SCREEN_Y), [0, 0, 0]);
+    }
+}
@@ -0,0 +1,306 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use super::{cartridge::Cartridge, model::Model};
+
+/// Files built from components' state start with a magic number and a
+/// version, followed by the model and the MD5 of the ROM they're for
+pub struct Format {
+    pub magic: &'static [u8; 4],
+
+    // Bump the version whenever what's stored changes
+    pub version: u16,
+
+    // What the file is called in errors
+    pub name: &'static str,
+}
+
+pub const SAVE_STATE: Format = Format {
+    magic: b"GBHS",
+    version: 4,
+    name: "Save state",
+};
+
+// Slots are numbered, each one a file next to the ROM
+pub const SLOTS: u8 = 10;
+
+/// Implemented by every component with state that needs to survive a save
+/// and a load. Components read back exactly what they wrote, in order.
+pub trait Snapshot {
+    fn save(&self, state: &mut StateWriter);
+    fn load(&mut self, state: &mut StateReader) -> Result<()>;
+}
+
+/// Returns the file a save state slot is kept in, next to the ROM
+pub fn slot_path(rom_path: &str, slot: u8) -> Result<String> {
+    if slot >= SLOTS {
+        bail!("Save state slots go from 0 to {}", SLOTS - 1);
+    }
+
+    let path = Path::new(rom_path).with_extension(format!("ss{}", slot));
+
+    Ok(path.to_string_lossy().into_owned())
+}
+
+// Everything is stored little endian, with variable sized data prefixed by
+// its length
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(model: Model, cartridge: &Cartridge) -> StateWriter {
+        StateWriter::with_format(&SAVE_STATE, model, cartridge)
+    }
+
+    pub fn with_format(format: &Format, model: Model, cartridge: &Cartridge) -> StateWriter {
+        let mut state = StateWriter { data: Vec::new() };
+
+        state.data.extend_from_slice(format.magic);
+        state.u16(format.version);
+        state.u8(model as u8);
+        state
+            .data
+            .extend_from_slice(&md5::compute(&cartridge.rom).0);
+
+        state
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn words(&mut self, words: &[u16]) {
+        self.u32(words.len() as u32);
+
+        for word in words {
+            self.u16(*word);
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    name: &'static str,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8], model: Model, cartridge: &Cartridge) -> Result<StateReader<'a>> {
+        StateReader::with_format(data, &SAVE_STATE, model, cartridge)
+    }
+
+    /// Checks that the data is in this version of the format, and is from
+    /// the same model and ROM
+    pub fn with_format(
+        data: &'a [u8],
+        format: &Format,
+        model: Model,
+        cartridge: &Cartridge,
+    ) -> Result<StateReader<'a>> {
+        let name = format.name;
+        let mut state = StateReader {
+            data,
+            position: 0,
+            name,
+        };
+
+        if state.take(format.magic.len())? != format.magic {
+            bail!("Not a {}", name.to_lowercase());
+        }
+
+        let version = state.u16()?;
+        if version != format.version {
+            bail!(
+                "{} version {} isn't supported, expected {}",
+                name,
+                version,
+                format.version
+            );
+        }
+
+        if state.u8()? != model as u8 {
+            bail!("{} is from a different model than {:?}", name, model);
+        }
+
+        if state.take(16)? != md5::compute(&cartridge.rom).0 {
+            bail!("{} is from a different ROM", name);
+        }
+
+        Ok(state)
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        let Some(bytes) = self.data.get(self.position..self.position + length) else {
+            bail!("{} is truncated", self.name);
+        };
+
+        self.position += length;
+
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => bail!("{} has an invalid flag: {}", self.name, value),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    /// Reads bytes of any length
+    pub fn bytes(&mut self) -> Result<Vec<u8>> {
+        let length = self.u32()? as usize;
+
+        Ok(self.take(length)?.to_vec())
+    }
+
+    /// Reads bytes into a buffer, which they need to fill exactly
+    pub fn bytes_into(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let bytes = self.bytes()?;
+
+        if bytes.len() != buffer.len() {
+            bail!(
+                "{} has {} bytes where {} were expected",
+                self.name,
+                bytes.len(),
+                buffer.len()
+            );
+        }
+
+        buffer.copy_from_slice(&bytes);
+
+        Ok(())
+    }
+
+    pub fn words(&mut self) -> Result<Vec<u16>> {
+        let length = self.u32()? as usize;
+
+        (0..length).map(|_| self.u16()).collect()
+    }
+
+    /// Reads words into a buffer, which they need to fill exactly
+    pub fn words_into(&mut self, buffer: &mut [u16]) -> Result<()> {
+        let words = self.words()?;
+
+        if words.len() != buffer.len() {
+            bail!(
+                "{} has {} words where {} were expected",
+                self.name,
+                words.len(),
+                buffer.len()
+            );
+        }
+
+        buffer.copy_from_slice(&words);
+
+        Ok(())
+    }
+
+    /// Checks that nothing was left unread
+    pub fn finish(self) -> Result<()> {
+        if self.position != self.data.len() {
+            bail!(
+                "{} has {} bytes left over",
+                self.name,
+                self.data.len() - self.position
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cartridge() -> Cartridge {
+        Cartridge::new(vec![0; 0x8000])
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new(Model::DMG, &cartridge());
+        writer.u8(0x12);
+        writer.bool(true);
+        writer.u16(0x3456);
+        writer.u64(u64::MAX);
+        writer.bytes(&[1, 2, 3]);
+        writer.words(&[0x7FFF]);
+        let data = writer.finish();
+
+        let mut reader = StateReader::new(&data, Model::DMG, &cartridge()).unwrap();
+        assert_eq!(reader.u8().unwrap(), 0x12);
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.u16().unwrap(), 0x3456);
+        assert_eq!(reader.u64().unwrap(), u64::MAX);
+        assert!(reader.bytes_into(&mut [0; 2]).is_err());
+
+        let mut words = [0; 1];
+        reader.words_into(&mut words).unwrap();
+        assert_eq!(words, [0x7FFF]);
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn test_header() {
+        let data = StateWriter::new(Model::DMG, &cartridge()).finish();
+
+        assert!(StateReader::new(&data, Model::CGB, &cartridge()).is_err());
+        assert!(StateReader::new(&data, Model::DMG, &Cartridge::new(vec![1; 0x8000])).is_err());
+        assert!(StateReader::new(&data[..8], Model::DMG, &cartridge()).is_err());
+
+        let mut data = data;
+        data[4] = SAVE_STATE.version as u8 + 1;
+        assert!(StateReader::new(&data, Model::DMG, &cartridge()).is_err());
+    }
+
+    #[test]
+    fn test_slot_path() {
+        assert_eq!(slot_path("roms/tetris.gb", 3).unwrap(), "roms/tetris.ss3");
+        assert!(slot_path("roms/tetris.gb", SLOTS).is_err());
+    }
+}
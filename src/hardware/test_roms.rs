@@ -0,0 +1,274 @@
+// Runs test ROMs without a display and compares what they draw with reference
+// screenshots. The ROMs and references aren't checked in, they go in priv/.
+
+use std::{fs, fs::File, path::Path};
+
+use super::{
+    bus::Bus,
+    cartridge::Cartridge,
+    machine::{write_png, Machine},
+    model::Model,
+};
+
+// Where diff images of failed comparisons are written
+const DIFF_DIR: &str = "target/screenshot-diffs";
+
+// LD B,B, which test ROMs execute when they're done
+const BREAKPOINT_OPCODE: u8 = 0x40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Until {
+    Frames(u64),
+
+    /// Stops at an LD B,B, giving up after this many frames
+    Breakpoint(u64),
+}
+
+pub struct Screenshot {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Screenshot {
+    pub fn of(machine: &Machine) -> Screenshot {
+        let (width, height) = machine.screen_size();
+
+        Screenshot {
+            width,
+            height,
+            pixels: machine.frame_rgb(),
+        }
+    }
+
+    /// Reads a PNG, of any color type, as 8-bit RGB
+    pub fn from_path(path: &str) -> Result<Screenshot, String> {
+        let file = File::open(path).map_err(|e| format!("Can't open {}: {}", path, e))?;
+
+        let mut decoder = png::Decoder::new(file);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+
+        let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+        let samples = &buffer[..info.buffer_size()];
+
+        let pixels = match info.color_type {
+            png::ColorType::Rgb => samples.to_vec(),
+            png::ColorType::Rgba => samples
+                .chunks_exact(4)
+                .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+                .collect(),
+            png::ColorType::Grayscale => samples.iter().flat_map(|&gray| [gray; 3]).collect(),
+            png::ColorType::GrayscaleAlpha => samples
+                .chunks_exact(2)
+                .flat_map(|pixel| [pixel[0]; 3])
+                .collect(),
+            png::ColorType::Indexed => unreachable!("EXPAND turns palettes into RGB"),
+        };
+
+        Ok(Screenshot {
+            width: info.width as usize,
+            height: info.height as usize,
+            pixels,
+        })
+    }
+
+    /// Returns an image marking the pixels that differ in red, over a faded
+    /// copy of the expected image, or None if they're the same
+    pub fn diff(&self, expected: &Screenshot) -> Option<Screenshot> {
+        if self.width != expected.width || self.height != expected.height {
+            return Some(Screenshot {
+                pixels: [0xFF, 0x00, 0x00].repeat(self.width * self.height),
+                ..*self
+            });
+        }
+
+        if self.pixels == expected.pixels {
+            return None;
+        }
+
+        let pixels = self
+            .pixels
+            .chunks_exact(3)
+            .zip(expected.pixels.chunks_exact(3))
+            .flat_map(|(actual, expected)| {
+                if actual == expected {
+                    let gray = (actual.iter().map(|&c| c as u16).sum::<u16>() / 3) as u8;
+                    [0x80 + gray / 2; 3]
+                } else {
+                    [0xFF, 0x00, 0x00]
+                }
+            })
+            .collect();
+
+        Some(Screenshot {
+            width: self.width,
+            height: self.height,
+            pixels,
+        })
+    }
+}
+
+/// Runs a machine until the condition is met
+pub fn run(machine: &mut Machine, until: Until) {
+    match until {
+        Until::Frames(frames) => {
+            while machine.frames() < frames {
+                machine.run_frame().unwrap();
+            }
+        }
+        Until::Breakpoint(frames) => {
+            let limit = machine.cpu.bus.ppu().frames() + frames;
+
+            while machine.cpu.peek_byte() != BREAKPOINT_OPCODE {
+                assert!(
+                    machine.cpu.bus.ppu().frames() < limit,
+                    "No LD B,B within {} frames",
+                    frames
+                );
+
+                machine.step().unwrap();
+            }
+        }
+    }
+}
+
+/// Runs a ROM and compares the last frame with the reference PNG, writing
+/// the actual picture and a diff image to target/screenshot-diffs on failure
+pub fn check_screenshot(
+    rom: &str,
+    model: Model,
+    until: Until,
+    reference: &str,
+) -> Result<(), String> {
+    let cartridge = Cartridge::from_path(rom).map_err(|e| format!("Can't load {}: {}", rom, e))?;
+    let mut machine = Machine::new(Bus::new(cartridge, model));
+
+    run(&mut machine, until);
+
+    let actual = Screenshot::of(&machine);
+    let expected = Screenshot::from_path(reference)?;
+
+    let Some(diff) = actual.diff(&expected) else {
+        return Ok(());
+    };
+
+    let name = Path::new(rom).file_stem().unwrap().to_string_lossy();
+    fs::create_dir_all(DIFF_DIR).map_err(|e| e.to_string())?;
+
+    let actual_path = format!("{}/{}.png", DIFF_DIR, name);
+    let diff_path = format!("{}/{}-diff.png", DIFF_DIR, name);
+    write_png(&actual_path, actual.width, actual.height, &actual.pixels)
+        .map_err(|e| e.to_string())?;
+    write_png(&diff_path, diff.width, diff.height, &diff.pixels).map_err(|e| e.to_string())?;
+
+    Err(format!(
+        "{} doesn't match {}, see {} and {}",
+        rom, reference, actual_path, diff_path
+    ))
+}
+
+#[test]
+fn test_diff() {
+    let expected = Screenshot {
+        width: 2,
+        height: 1,
+        pixels: vec![0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00],
+    };
+    let same = Screenshot {
+        pixels: expected.pixels.clone(),
+        ..expected
+    };
+    assert!(same.diff(&expected).is_none());
+
+    let different = Screenshot {
+        pixels: vec![0xFF, 0xFF, 0xFF, 0x55, 0x55, 0x55],
+        ..expected
+    };
+    let diff = different.diff(&expected).unwrap();
+    assert_eq!(diff.pixels, [0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00]);
+}
+
+#[test]
+fn test_run() {
+    // A few NOPs, then LD B,B in a loop
+    let mut rom = vec![0; 0x8000];
+    rom[0x0104..0x0107].copy_from_slice(&[0x40, 0x18, 0xFD]);
+    let mut machine = Machine::new(Bus::new(Cartridge::new(rom), Model::DMG));
+
+    run(&mut machine, Until::Breakpoint(1));
+    assert_eq!(machine.cpu.pc, 0x0104);
+
+    run(&mut machine, Until::Frames(2));
+    assert_eq!(machine.frames(), 2);
+}
+
+#[test]
+fn test_png_round_trip() {
+    let path = std::env::temp_dir().join("gb-hinder-round-trip.png");
+    let path = path.to_str().unwrap();
+    let pixels = vec![0x12, 0x34, 0x56, 0xAB, 0xCD, 0xEF];
+
+    write_png(path, 1, 2, &pixels).unwrap();
+    let screenshot = Screenshot::from_path(path).unwrap();
+    fs::remove_file(path).unwrap();
+
+    assert_eq!((screenshot.width, screenshot.height), (1, 2));
+    assert_eq!(screenshot.pixels, pixels);
+}
+
+#[test]
+#[ignore = "needs dmg-acid2 in priv/"]
+fn test_dmg_acid2() {
+    check_screenshot(
+        "priv/dmg-acid2.gb",
+        Model::DMG,
+        Until::Breakpoint(60),
+        "priv/dmg-acid2.png",
+    )
+    .unwrap();
+}
+
+#[test]
+#[ignore = "needs cgb-acid2 in priv/"]
+fn test_cgb_acid2() {
+    check_screenshot(
+        "priv/cgb-acid2.gbc",
+        Model::CGB,
+        Until::Breakpoint(60),
+        "priv/cgb-acid2.png",
+    )
+    .unwrap();
+}
+
+// Each ROM in priv/mealybug/ is checked against the PNG with the same name,
+// and a missing PNG counts as a failure
+#[test]
+#[ignore = "needs the mealybug-tearoom tests in priv/mealybug/"]
+fn test_mealybug_tearoom() {
+    let mut roms: Vec<_> = fs::read_dir("priv/mealybug")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "gb"))
+        .collect();
+    roms.sort();
+    assert!(!roms.is_empty(), "No ROMs in priv/mealybug");
+
+    let failures: Vec<String> = roms
+        .iter()
+        .filter_map(|rom| {
+            let reference = rom.with_extension("png");
+
+            check_screenshot(
+                rom.to_str().unwrap(),
+                Model::DMG,
+                Until::Breakpoint(60),
+                reference.to_str().unwrap(),
+            )
+            .err()
+        })
+        .collect();
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
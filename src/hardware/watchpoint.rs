@@ -0,0 +1,127 @@
+use std::fmt::Display;
+
+use anyhow::{bail, Result};
+
+/// The kinds of access a watchpoint can fire on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+/// Fires when an address in a range is accessed, and optionally only when
+/// the byte read or written has a given value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub access: Access,
+    pub start: u16,
+    pub end: u16,
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    pub fn new(access: Access, address: u16) -> Watchpoint {
+        Watchpoint {
+            access,
+            start: address,
+            end: address,
+            value: None,
+        }
+    }
+
+    /// Watches every address from `start` to `end`, inclusive
+    pub fn range(access: Access, start: u16, end: u16) -> Result<Watchpoint> {
+        if end < start {
+            bail!("Watchpoint range {:04X}-{:04X} is backwards", start, end);
+        }
+
+        Ok(Watchpoint {
+            access,
+            start,
+            end,
+            value: None,
+        })
+    }
+
+    pub fn with_value(self, value: u8) -> Watchpoint {
+        Watchpoint {
+            value: Some(value),
+            ..self
+        }
+    }
+
+    pub fn matches(&self, access: Access, address: u16, value: u8) -> bool {
+        self.access == access
+            && (self.start..=self.end).contains(&address)
+            && self.value.is_none_or(|v| v == value)
+    }
+}
+
+impl Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {:04X}", self.access, self.start)?;
+
+        if self.end != self.start {
+            write!(f, "-{:04X}", self.end)?;
+        }
+
+        match self.value {
+            Some(value) => write!(f, " = {:02X}", value),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An access that set off a watchpoint, along with the instruction that made
+/// it. Accesses made while dispatching an interrupt are put down to the
+/// instruction it interrupted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watchpoint: usize,
+    pub access: Access,
+    pub address: u16,
+    pub value: u8,
+    pub pc: u16,
+    pub bank: usize,
+}
+
+impl Display for WatchHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Watchpoint {}: {} {:04X} = {:02X} at {:02X}:{:04X}",
+            self.watchpoint, self.access, self.address, self.value, self.bank, self.pc
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let watchpoint = Watchpoint::range(Access::Write, 0xC000, 0xC0FF)
+            .unwrap()
+            .with_value(0x42);
+
+        assert!(watchpoint.matches(Access::Write, 0xC0FF, 0x42));
+        assert!(!watchpoint.matches(Access::Write, 0xC100, 0x42));
+        assert!(!watchpoint.matches(Access::Write, 0xC000, 0x43));
+        assert!(!watchpoint.matches(Access::Read, 0xC000, 0x42));
+        assert_eq!(watchpoint.to_string(), "write C000-C0FF = 42");
+
+        assert!(Watchpoint::range(Access::Read, 0xC001, 0xC000).is_err());
+    }
+}
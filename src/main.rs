@@ -1,14 +1,333 @@
+// Register and instruction names follow the hardware documentation
+#![allow(clippy::upper_case_acronyms)]
+
+mod debugger;
+mod disassembler;
 pub mod hardware;
+mod symbols;
+
+use std::{fs, path::Path, rc::Rc};
+
+use anyhow::{bail, Context, Result};
+use debugger::{gdb, Debugger};
+use disassembler::Disassembler;
+use hardware::{
+    boot_rom::BootROM,
+    cartridge::Cartridge,
+    cdl::CodeDataLog,
+    error::EmulationMode,
+    movie::{replay, Movie, Recorder},
+    ppu::shades::ShadePalette,
+    rewind::Rewind,
+    state::slot_path,
+};
+use symbols::Symbols;
+
+use crate::hardware::{bus::Bus, machine::Machine, model::Model};
+
+// Movies recorded here hash the state once a second
+const MOVIE_HASH_INTERVAL: u64 = 60;
+
+fn main() -> Result<()> {
+    let mut model = Model::DMG;
+    let mut mode = EmulationMode::default();
+    let mut path = String::from("priv/02-interrupts.gb");
+    let mut boot_rom_path = None;
+    let mut palette = None;
+    let mut shades = None;
+    let mut screenshot = None;
+    let mut scale = 1;
+    let mut load_slot = None;
+    let mut save_slot = None;
+    let mut record_movie = None;
+    let mut play_movie = None;
+    let mut debug = false;
+    let mut debug_on_error = false;
+    let mut trace = false;
+    let mut gdb_port = None;
+    let mut cdl_path = None;
+    let mut symbols_path = None;
+
+    // Disassembly is a command of its own, which doesn't run anything
+    if std::env::args().nth(1).as_deref() == Some("disasm") {
+        return disasm(std::env::args().skip(2));
+    }
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--model" => model = args.next().context("--model needs a value")?.parse()?,
+            "--mode" => mode = args.next().context("--mode needs a value")?.parse()?,
+            "--boot-rom" => boot_rom_path = Some(args.next().context("--boot-rom needs a path")?),
+            "--palette" => palette = Some(args.next().context("--palette needs a combo")?.parse()?),
+            "--shades" => {
+                let shades_arg = args.next().context("--shades needs a preset or a file")?;
+
+                // Anything that looks like a path is a palette file, so a
+                // misspelled preset isn't reported as a missing file
+                let shades_path = Path::new(&shades_arg);
+                let is_file = shades_path.exists()
+                    || shades_path.extension().is_some()
+                    || shades_arg.contains(std::path::is_separator);
+
+                shades = Some(match is_file {
+                    true => ShadePalette::from_path(&shades_arg)?,
+                    false => shades_arg.parse()?,
+                });
+            }
+            "--screenshot-at-frame" => {
+                let frame: u64 = args
+                    .next()
+                    .context("--screenshot-at-frame needs a frame number")?
+                    .parse()?;
+                let file = args.next().context("--screenshot-at-frame needs a file")?;
+
+                screenshot = Some((frame, file));
+            }
+            "--load-state" => {
+                load_slot = Some(args.next().context("--load-state needs a slot")?.parse()?)
+            }
+            "--save-state-at-frame" => {
+                let frame: u64 = args
+                    .next()
+                    .context("--save-state-at-frame needs a frame number")?
+                    .parse()?;
+                let slot: u8 = args
+                    .next()
+                    .context("--save-state-at-frame needs a slot")?
+                    .parse()?;
+
+                save_slot = Some((frame, slot));
+            }
+            "--record-movie" => {
+                record_movie = Some(args.next().context("--record-movie needs a file")?)
+            }
+            "--play-movie" => play_movie = Some(args.next().context("--play-movie needs a file")?),
+            "--debug" => debug = true,
+            "--debug-on-error" => debug_on_error = true,
+            "--trace" => trace = true,
+            "--cdl" => cdl_path = Some(args.next().context("--cdl needs a file")?),
+            "--symbols" => symbols_path = Some(args.next().context("--symbols needs a file")?),
+            "--gdb" => gdb_port = Some(args.next().context("--gdb needs a port")?.parse()?),
+            "--scale" => scale = args.next().context("--scale needs a factor")?.parse()?,
+            _ => path = arg,
+        }
+    }
+
+    // Buttons are pressed from the debugger, there's nothing else to record
+    if record_movie.is_some() && !debug {
+        bail!("--record-movie needs --debug");
+    }
+
+    // Each of these takes over running the machine, so only one can be given
+    let runners: Vec<&str> = [
+        (debug, "--debug"),
+        (debug_on_error, "--debug-on-error"),
+        (gdb_port.is_some(), "--gdb"),
+        (play_movie.is_some(), "--play-movie"),
+        (screenshot.is_some(), "--screenshot-at-frame"),
+    ]
+    .into_iter()
+    .filter_map(|(given, flag)| given.then_some(flag))
+    .collect();
+
+    if runners.len() > 1 {
+        bail!("{} can't be used together", runners.join(" and "));
+    }
+
+    if save_slot.is_some() && (debug || gdb_port.is_some() || play_movie.is_some()) {
+        bail!("--save-state-at-frame can't be used with {}", runners[0]);
+    }
+
+    let cartridge = Cartridge::from_path(&path)?;
+    let mut bus = match boot_rom_path {
+        Some(boot_rom_path) => {
+            Bus::with_boot_rom(cartridge, model, BootROM::from_path(&boot_rom_path, model)?)
+        }
+        None => Bus::new(cartridge, model),
+    };
+
+    if let Some(palette) = palette {
+        bus.set_compatibility_palette(palette);
+    }
+
+    if let Some(shades) = shades {
+        bus.ppu_mut().shade_palette = shades;
+    }
+
+    // Logging carries on from an earlier run's log
+    if let Some(file) = &cdl_path {
+        let rom_size = bus.cartridge().rom.len();
+        let log = match fs::exists(file)? {
+            true => Some(CodeDataLog::from_path(file, rom_size)?),
+            false => None,
+        };
+
+        bus.log_code_and_data(log);
+    }
+
+    let symbols = Rc::new(load_symbols(&path, symbols_path)?);
+
+    let mut machine = Machine::new(bus);
+    machine.cpu.mode = mode;
+    machine.cpu.set_trace(trace);
+    machine.cpu.set_symbols(Rc::clone(&symbols));
+
+    if let Some(slot) = load_slot {
+        let slot_path = slot_path(&path, slot)?;
+        let state = fs::read(&slot_path).with_context(|| format!("Can't read {}", slot_path))?;
+
+        machine
+            .load_state(&state)
+            .with_context(|| format!("Can't load {}", slot_path))?;
+    }
 
-use hardware::cartridge::Cartridge;
+    // The code/data log is written out however the run ends
+    let result = (|| -> Result<()> {
+        if debug {
+            let mut debugger = Debugger::new(&path).with_symbols(symbols);
+            if record_movie.is_some() {
+                debugger = debugger.with_recorder(Recorder::start(&machine, MOVIE_HASH_INTERVAL));
+            }
 
-use crate::hardware::{bus::Bus, cpu::CPU};
+            let result = debugger.run(&mut machine);
 
-fn main() {
-    let bus = Bus::new(Cartridge::from_path("priv/02-interrupts.gb").unwrap());
-    let mut cpu = CPU::new(bus);
+            // The movie is kept even if the debugger stopped with an error
+            if let (Some(file), Some(movie)) = (record_movie, debugger.finish_recording()) {
+                movie.save(&file, machine.cpu.bus.cartridge())?;
+            }
 
-    loop {
-        cpu.execute_next_instruction();
+            return result;
+        }
+
+        if let Some(port) = gdb_port {
+            let listener = gdb::listen(port)?;
+            println!("Waiting for GDB on port {}", listener.local_addr()?.port());
+
+            return gdb::serve(listener, &mut machine);
+        }
+
+        if let Some(file) = play_movie {
+            let movie = Movie::from_path(&file, model, machine.cpu.bus.cartridge())?;
+
+            return match replay(movie, &mut machine)? {
+                Some(frame) => bail!("Replay diverged from {} at frame {}", file, frame),
+                None => Ok(()),
+            };
+        }
+
+        // Frames count on from the loaded state
+        let mut save_slot = match save_slot {
+            Some((frame, slot)) => Some((frame, slot_path(&path, slot)?)),
+            None => None,
+        };
+
+        // Without a display, a screenshot is the only output besides serial
+        if let Some((frame, file)) = screenshot {
+            while machine.frames() < frame {
+                machine.run_frame()?;
+                save_state_if_due(&machine, &mut save_slot)?;
+            }
+
+            return machine.save_screenshot_scaled(&file, scale);
+        }
+
+        // History is only kept for the debugger to go back through
+        let mut rewind = debug_on_error.then(Rewind::default);
+
+        loop {
+            if let Err(error) = machine.run_frame() {
+                let Some(rewind) = rewind else {
+                    return Err(error.into());
+                };
+
+                println!("Stopped: {}", error);
+                return Debugger::new(&path)
+                    .with_symbols(symbols)
+                    .with_rewind(rewind)
+                    .run(&mut machine);
+            }
+
+            if let Some(rewind) = &mut rewind {
+                rewind.capture(&machine);
+            }
+
+            save_state_if_due(&machine, &mut save_slot)?;
+        }
+    })();
+
+    if let (Some(file), Some(log)) = (cdl_path, machine.cpu.bus.code_data_log()) {
+        log.save(&file)?;
+    }
+
+    result
+}
+
+// Prints a ROM as RGBDS assembly. With --recursive only the bytes reached by
+// following the code are disassembled, and --map writes out which are which.
+// A code/data log from --cdl helps the trace along.
+fn disasm(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut path = None;
+    let mut recursive = false;
+    let mut map_path = None;
+    let mut cdl_path = None;
+    let mut symbols_path = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--recursive" => recursive = true,
+            "--map" => map_path = Some(args.next().context("--map needs a file")?),
+            "--cdl" => cdl_path = Some(args.next().context("--cdl needs a file")?),
+            "--symbols" => symbols_path = Some(args.next().context("--symbols needs a file")?),
+            _ => path = Some(arg),
+        }
     }
+
+    let path = path.context("disasm needs a ROM")?;
+    let cartridge = Cartridge::from_path(&path)?;
+    let symbols = load_symbols(&path, symbols_path)?;
+    let log = match cdl_path {
+        Some(file) => Some(CodeDataLog::from_path(&file, cartridge.rom.len())?),
+        None => None,
+    };
+
+    let disassembler = match (recursive, &log) {
+        (true, log) => Disassembler::traced(&cartridge, log.as_ref()),
+        (false, None) => Disassembler::new(&cartridge),
+        (false, Some(_)) => bail!("--cdl needs --recursive"),
+    }
+    .with_symbols(&symbols);
+
+    if let Some(map_path) = map_path {
+        let map = disassembler.code_map().context("--map needs --recursive")?;
+        fs::write(map_path, map.to_string())?;
+    }
+
+    print!("{}", disassembler.rgbds());
+
+    Ok(())
+}
+
+// Symbols come from --symbols, or the .sym file RGBDS writes next to the ROM.
+// Without either there are none.
+fn load_symbols(rom_path: &str, symbols_path: Option<String>) -> Result<Symbols> {
+    if let Some(symbols_path) = symbols_path {
+        return Symbols::from_path(&symbols_path);
+    }
+
+    let beside = Path::new(rom_path).with_extension("sym");
+    match beside.to_str() {
+        Some(beside) if fs::exists(beside)? => Symbols::from_path(beside),
+        _ => Ok(Symbols::default()),
+    }
+}
+
+// Writes the save state slot once its frame is reached
+fn save_state_if_due(machine: &Machine, save_slot: &mut Option<(u64, String)>) -> Result<()> {
+    if let Some((_, slot_path)) = save_slot.take_if(|(frame, _)| machine.frames() >= *frame) {
+        fs::write(&slot_path, machine.save_state())
+            .with_context(|| format!("Can't write {}", slot_path))?;
+    }
+
+    Ok(())
 }
@@ -0,0 +1,105 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// Labels from an RGBDS symbol file, which has a `BANK:ADDRESS NAME` line for
+/// each label. Comments start with a semicolon.
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    // By address then bank. Only the first label at each place is kept for
+    // showing, but every name can be looked up.
+    labels: BTreeMap<(u16, usize), String>,
+    addresses: HashMap<String, (usize, u16)>,
+}
+
+impl Symbols {
+    pub fn parse(text: &str) -> Result<Symbols> {
+        let mut symbols = Symbols::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parsed = line
+                .split_once(char::is_whitespace)
+                .and_then(|(place, name)| Some((place.split_once(':')?, name.trim())));
+
+            let Some(((bank, address), name)) = parsed else {
+                bail!("Line {} isn't BANK:ADDRESS NAME", number + 1);
+            };
+
+            let bank = usize::from_str_radix(bank, 16)
+                .map_err(|_| anyhow!("Line {} has a bad bank: {}", number + 1, bank))?;
+            let address = u16::from_str_radix(address, 16)
+                .map_err(|_| anyhow!("Line {} has a bad address: {}", number + 1, address))?;
+
+            symbols
+                .labels
+                .entry((address, bank))
+                .or_insert_with(|| name.to_owned());
+            symbols.addresses.insert(name.to_owned(), (bank, address));
+        }
+
+        Ok(symbols)
+    }
+
+    pub fn from_path(path: &str) -> Result<Symbols> {
+        let text = fs::read_to_string(path).with_context(|| format!("Can't read {}", path))?;
+
+        Symbols::parse(&text).with_context(|| format!("Can't load {}", path))
+    }
+
+    /// Returns the label at an address, given the bank mapped there. Banks
+    /// of RAM aren't tracked, so a label in any of them will do.
+    pub fn label(&self, bank: usize, address: u16) -> Option<&str> {
+        let label = match address {
+            0x0000..=0x7FFF => self.labels.get(&(address, bank)),
+            _ => self
+                .labels
+                .range((address, 0)..=(address, usize::MAX))
+                .map(|(_, label)| label)
+                .next(),
+        };
+
+        label.map(String::as_str)
+    }
+
+    /// Returns the bank and address of a label
+    pub fn address(&self, name: &str) -> Option<(usize, u16)> {
+        self.addresses.get(name).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let symbols = Symbols::parse(
+            "; File generated by rgblink\n\
+             00:0150 Main\n\
+             00:0150 EntryPoint\n\
+             00:0158 Main.loop\n\
+             02:4000 Far ; in bank 2\n\
+             01:d000 wBuffer\n",
+        )
+        .unwrap();
+
+        assert_eq!(symbols.label(0, 0x0150), Some("Main"));
+        assert_eq!(symbols.address("EntryPoint"), Some((0, 0x0150)));
+        assert_eq!(symbols.address("Main.loop"), Some((0, 0x0158)));
+        assert_eq!(symbols.label(2, 0x4000), Some("Far"));
+        assert_eq!(symbols.label(1, 0x4000), None);
+        assert_eq!(symbols.label(0, 0xD000), Some("wBuffer"));
+        assert_eq!(symbols.address("Nowhere"), None);
+
+        assert!(Symbols::parse("0150 Main").is_err());
+        assert!(Symbols::parse("00:01G0 Main").is_err());
+    }
+}